@@ -0,0 +1,143 @@
+use std::sync::Arc;
+
+use crate::di::*;
+use async_trait::async_trait;
+use indradb::EdgeKey;
+use log::debug;
+use uuid::Uuid;
+
+use crate::behaviour::components::mqtt_ha_exposed::MqttHaExposed;
+use crate::behaviour::components::MqttHaExposedProperties;
+use crate::behaviour::entity::entity_behaviour_provider::MqttEntityBehaviourProvider;
+use crate::behaviour::entity::entity_behaviour_provider::MqttEntityBehaviourProviderImpl;
+use crate::model::ReactiveEntityInstance;
+use crate::model::ReactiveRelationInstance;
+use crate::plugins::ComponentBehaviourProvider;
+
+const MQTT_HA_EXPOSED: &'static str = "mqtt_ha_exposed";
+
+#[wrapper]
+pub struct MqttHaExposedStorage(
+    std::sync::RwLock<std::collections::HashMap<Uuid, std::sync::Arc<MqttHaExposed>>>,
+);
+
+#[provides]
+fn create_mqtt_ha_exposed_storage() -> MqttHaExposedStorage {
+    MqttHaExposedStorage(std::sync::RwLock::new(std::collections::HashMap::new()))
+}
+
+#[async_trait]
+pub trait MqttComponentBehaviourProvider: ComponentBehaviourProvider + Send + Sync {
+    fn create_ha_exposed(&self, entity_instance: Arc<ReactiveEntityInstance>);
+
+    fn remove_ha_exposed(&self, entity_instance: Arc<ReactiveEntityInstance>);
+
+    fn remove_by_id(&self, id: Uuid);
+}
+
+pub struct MqttComponentBehaviourProviderImpl {
+    mqtt_ha_exposed: MqttHaExposedStorage,
+
+    entity_behaviour_provider: Wrc<MqttEntityBehaviourProviderImpl>,
+}
+
+interfaces!(MqttComponentBehaviourProviderImpl: dyn ComponentBehaviourProvider);
+
+#[component]
+impl MqttComponentBehaviourProviderImpl {
+    #[provides]
+    fn new(entity_behaviour_provider: Wrc<MqttEntityBehaviourProviderImpl>) -> Self {
+        Self {
+            mqtt_ha_exposed: create_mqtt_ha_exposed_storage(),
+            entity_behaviour_provider,
+        }
+    }
+}
+
+#[async_trait]
+#[provides]
+impl MqttComponentBehaviourProvider for MqttComponentBehaviourProviderImpl {
+    fn create_ha_exposed(&self, entity_instance: Arc<ReactiveEntityInstance>) {
+        let id = entity_instance.id;
+        let broker = match self.entity_behaviour_provider.get_linked_broker(
+            entity_instance.clone(),
+            MqttHaExposedProperties::HA_BROKER.as_ref(),
+            MQTT_HA_EXPOSED,
+        ) {
+            Some(broker) => broker,
+            None => return,
+        };
+        // The previous behaviour removes its observers, which use the same handle, first
+        self.mqtt_ha_exposed.0.write().unwrap().remove(&id);
+        let ha_exposed = MqttHaExposed::new(entity_instance.clone(), broker);
+        if ha_exposed.is_ok() {
+            let ha_exposed = Arc::new(ha_exposed.unwrap());
+            self.mqtt_ha_exposed
+                .0
+                .write()
+                .unwrap()
+                .insert(id, ha_exposed);
+            entity_instance.add_behaviour(MQTT_HA_EXPOSED);
+            debug!(
+                "Added behaviour {} to entity instance {}",
+                MQTT_HA_EXPOSED, id
+            );
+        }
+    }
+
+    fn remove_ha_exposed(&self, entity_instance: Arc<ReactiveEntityInstance>) {
+        self.entity_behaviour_provider
+            .unlink_broker(entity_instance.id, MQTT_HA_EXPOSED);
+        let ha_exposed = self
+            .mqtt_ha_exposed
+            .0
+            .write()
+            .unwrap()
+            .remove(&entity_instance.id);
+        // Only removing the component removes the entity from Home Assistant
+        if let Some(ha_exposed) = ha_exposed {
+            ha_exposed.clear_config();
+        }
+        entity_instance.remove_behaviour(MQTT_HA_EXPOSED);
+        debug!(
+            "Removed behaviour {} from entity instance {}",
+            MQTT_HA_EXPOSED, entity_instance.id
+        );
+    }
+
+    fn remove_by_id(&self, id: Uuid) {
+        self.entity_behaviour_provider
+            .unlink_broker(id, MQTT_HA_EXPOSED);
+        if self.mqtt_ha_exposed.0.write().unwrap().contains_key(&id) {
+            self.mqtt_ha_exposed.0.write().unwrap().remove(&id);
+            debug!(
+                "Removed behaviour {} from entity instance {}",
+                MQTT_HA_EXPOSED, id
+            );
+        }
+    }
+}
+
+impl ComponentBehaviourProvider for MqttComponentBehaviourProviderImpl {
+    fn add_behaviours_to_entity(&self, entity_instance: Arc<ReactiveEntityInstance>) {
+        if entity_instance.is_a(MQTT_HA_EXPOSED) {
+            self.create_ha_exposed(entity_instance);
+        }
+    }
+
+    fn add_behaviours_to_relation(&self, _relation_instance: Arc<ReactiveRelationInstance>) {}
+
+    fn remove_behaviours_from_entity(&self, entity_instance: Arc<ReactiveEntityInstance>) {
+        if entity_instance.is_a(MQTT_HA_EXPOSED) {
+            self.remove_ha_exposed(entity_instance);
+        }
+    }
+
+    fn remove_behaviours_from_relation(&self, _relation_instance: Arc<ReactiveRelationInstance>) {}
+
+    fn remove_behaviours_by_id(&self, id: Uuid) {
+        self.remove_by_id(id);
+    }
+
+    fn remove_behaviours_by_key(&self, _edge_key: EdgeKey) {}
+}
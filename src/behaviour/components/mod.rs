@@ -1,3 +1,9 @@
 pub use properties::*;
+pub use topic::*;
+pub use topic_filter::*;
 
+pub mod component_behaviour_provider;
+pub mod mqtt_ha_exposed;
 pub mod properties;
+pub mod topic;
+pub mod topic_filter;
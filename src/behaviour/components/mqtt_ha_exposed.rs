@@ -0,0 +1,326 @@
+use std::convert::AsRef;
+use std::sync::Arc;
+use std::sync::Weak;
+
+use log::debug;
+use log::error;
+use rumqttc::QoS;
+use serde_json::json;
+use serde_json::Map;
+use serde_json::Value;
+
+use crate::behaviour::components::get_received_payload;
+use crate::behaviour::components::MqttEndpointProperties;
+use crate::behaviour::components::MqttHaExposedProperties;
+use crate::behaviour::components::MqttPayloadMode;
+use crate::behaviour::components::MqttTopicProperties;
+use crate::behaviour::entity::importer::LABEL;
+use crate::behaviour::entity::mqtt_broker::MqttBroker;
+use crate::behaviour::entity::mqtt_ha_discovery::HA_ORIGIN;
+use crate::behaviour::entity::MqttBrokerProperties;
+use crate::model::PropertyInstanceGetter;
+use crate::model::ReactiveEntityInstance;
+use crate::reactive::entity::Disconnectable;
+use crate::reactive::BehaviourCreationError;
+
+/// The payloads of the availability topic, which are the defaults of Home Assistant.
+const AVAILABLE: &str = "online";
+const NOT_AVAILABLE: &str = "offline";
+
+/// The topics of an exposed entity below `discovery_prefix/component/object_id`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HaExposedTopics {
+    pub config_topic: String,
+
+    pub state_topic: String,
+
+    /// Only settable properties accept commands
+    pub command_topic: Option<String>,
+
+    /// The entity is available while the behaviour exists
+    pub availability_topic: String,
+}
+
+/// Announces an entity to Home Assistant via MQTT discovery.
+///
+/// The retained discovery config describes the chosen property of the entity, which is mirrored
+/// into the state topic. Commands received on the command topic are written into the property.
+/// The configuration is read once, changes take effect when the behaviour is recreated.
+///
+/// The discovery config is only cleared if the component has been removed. If the behaviour is
+/// dropped otherwise, for example on shutdown, the entity becomes unavailable in Home Assistant.
+pub struct MqttHaExposed {
+    pub entity: Arc<ReactiveEntityInstance>,
+
+    pub handle_id: u128,
+
+    /// The exposed property
+    property: String,
+
+    topics: HaExposedTopics,
+
+    broker: Weak<MqttBroker>,
+
+    /// The command observer outlives a removed behaviour of the broker
+    broker_entity: Weak<ReactiveEntityInstance>,
+}
+
+impl MqttHaExposed {
+    pub fn new<'a>(
+        e: Arc<ReactiveEntityInstance>,
+        broker: Arc<MqttBroker>,
+    ) -> Result<MqttHaExposed, BehaviourCreationError> {
+        let property_name = e
+            .as_string(MqttHaExposedProperties::HA_PROPERTY.as_ref())
+            .unwrap_or_else(|| {
+                MqttHaExposedProperties::HA_PROPERTY
+                    .default_value()
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string()
+            });
+        let property = e.properties.get(property_name.as_str());
+        if property.is_none() {
+            error!(
+                "Can't expose property {} of entity instance {}: No such property",
+                property_name, e.id
+            );
+            return Err(BehaviourCreationError.into());
+        }
+        let property = property.unwrap();
+        let handle_id = property.id.as_u128();
+
+        let object_id = e
+            .as_string(MqttHaExposedProperties::HA_OBJECT_ID.as_ref())
+            .filter(|object_id| !object_id.is_empty())
+            .unwrap_or(e.id.simple().to_string());
+        let name = e
+            .as_string(MqttHaExposedProperties::HA_NAME.as_ref())
+            .filter(|name| !name.is_empty())
+            .or_else(|| e.as_string(LABEL).filter(|label| !label.is_empty()))
+            .unwrap_or(object_id.clone());
+        let settable = e
+            .as_bool(MqttHaExposedProperties::HA_SETTABLE.as_ref())
+            .unwrap_or(false);
+        let discovery_prefix = e
+            .as_string(MqttHaExposedProperties::HA_DISCOVERY_PREFIX.as_ref())
+            .unwrap_or_else(|| {
+                MqttHaExposedProperties::HA_DISCOVERY_PREFIX
+                    .default_value()
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string()
+            });
+        let component = e
+            .as_string(MqttHaExposedProperties::HA_COMPONENT.as_ref())
+            .unwrap_or_else(|| {
+                MqttHaExposedProperties::HA_COMPONENT
+                    .default_value()
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string()
+            });
+        let base_topic = format!("{}/{}/{}", discovery_prefix, component, object_id);
+        let topics = HaExposedTopics {
+            config_topic: format!("{}/config", base_topic),
+            state_topic: format!("{}/state", base_topic),
+            command_topic: match settable {
+                true => Some(format!("{}/set", base_topic)),
+                false => None,
+            },
+            availability_topic: format!("{}/availability", base_topic),
+        };
+
+        // Home Assistant compares the state with the payloads of boolean components
+        let mut config = Map::new();
+        config.insert(String::from("name"), json!(name));
+        config.insert(
+            String::from("unique_id"),
+            json!(format!("{}_{}", e.id.simple(), property_name)),
+        );
+        config.insert(String::from("object_id"), json!(object_id));
+        config.insert(String::from("state_topic"), json!(topics.state_topic));
+        if let Some(command_topic) = &topics.command_topic {
+            config.insert(String::from("command_topic"), json!(command_topic));
+        }
+        if property.get().is_boolean() {
+            config.insert(String::from("payload_on"), json!("true"));
+            config.insert(String::from("payload_off"), json!("false"));
+        }
+        config.insert(
+            String::from("availability"),
+            availability(&broker.entity, &topics.availability_topic),
+        );
+        config.insert(String::from("availability_mode"), json!("all"));
+        // The mqtt_ha_discovery doesn't import the entities of the graph again
+        config.insert(String::from("origin"), json!({ "name": HA_ORIGIN }));
+        debug!(
+            "Exposing property {} of entity instance {} as {}",
+            property_name, e.id, topics.config_topic
+        );
+        send_package(&broker.entity, &topics.config_topic, Value::Object(config));
+        // A retained empty message would clear the state
+        let state = property.get();
+        if !state.is_null() {
+            send_package(&broker.entity, &topics.state_topic, state);
+        }
+        send_package(&broker.entity, &topics.availability_topic, json!(AVAILABLE));
+
+        // Mirror the property into the state topic
+        let state_broker = broker.entity.clone();
+        let state_topic = topics.state_topic.clone();
+        property.stream.read().unwrap().observe_with_handle(
+            move |v| {
+                if !v.is_null() {
+                    send_package(&state_broker, &state_topic, v.clone());
+                }
+            },
+            handle_id,
+        );
+
+        if let Some(command_topic) = topics.command_topic.clone() {
+            let entity = e.clone();
+            let command_property = property_name.clone();
+            broker
+                .entity
+                .properties
+                .get(MqttBrokerProperties::RECEIVED_PACKAGE.as_ref())
+                .unwrap()
+                .stream
+                .read()
+                .unwrap()
+                .observe_with_handle(
+                    move |v| {
+                        let received_topic = v
+                            .get(MqttTopicProperties::TOPIC.as_ref())
+                            .and_then(Value::as_str);
+                        if received_topic != Some(command_topic.as_str()) {
+                            return;
+                        }
+                        let property = match entity.properties.get(command_property.as_str()) {
+                            Some(property) => property,
+                            None => return,
+                        };
+                        let payload = get_received_payload(v).unwrap_or_default();
+                        // String properties take the command as it is
+                        let mode = match property.get() {
+                            Value::String(_) => MqttPayloadMode::Raw,
+                            _ => MqttPayloadMode::Json,
+                        };
+                        if let Ok(value) = mode.decode(payload.as_slice()) {
+                            debug!(
+                                "Received command for property {} of entity instance {}",
+                                command_property, entity.id
+                            );
+                            property.set(value);
+                        }
+                    },
+                    handle_id,
+                );
+            broker.subscribe(command_topic.as_str(), QoS::AtLeastOnce);
+        }
+
+        Ok(MqttHaExposed {
+            entity: e.clone(),
+            handle_id,
+            property: property_name,
+            topics,
+            broker: Arc::downgrade(&broker),
+            broker_entity: Arc::downgrade(&broker.entity),
+        })
+    }
+
+    /// Removes the entity from Home Assistant by clearing the retained discovery config.
+    pub fn clear_config(&self) {
+        if let Some(broker) = self.broker.upgrade() {
+            debug!("Clearing {}", self.topics.config_topic);
+            send_package(&broker.entity, &self.topics.config_topic, Value::Null);
+        }
+    }
+
+    pub fn type_name(&self) -> String {
+        self.entity.type_name.clone()
+    }
+}
+
+/// The entity is available while the behaviour exists. If the broker publishes its last will on
+/// the topic of its birth message, the entity is also unavailable while the broker is offline.
+fn availability(broker: &ReactiveEntityInstance, availability_topic: &str) -> Value {
+    let mut availability = vec![json!({
+        "topic": availability_topic,
+        "payload_available": AVAILABLE,
+        "payload_not_available": NOT_AVAILABLE
+    })];
+    let will_topic = broker
+        .as_string(MqttBrokerProperties::WILL_TOPIC.as_ref())
+        .unwrap_or_default();
+    let birth_topic = broker
+        .as_string(MqttBrokerProperties::BIRTH_TOPIC.as_ref())
+        .unwrap_or_default();
+    if !will_topic.is_empty() && will_topic == birth_topic {
+        availability.push(json!({
+            "topic": will_topic,
+            "payload_available": broker
+                .as_string(MqttBrokerProperties::BIRTH_PAYLOAD.as_ref())
+                .unwrap_or_default(),
+            "payload_not_available": broker
+                .as_string(MqttBrokerProperties::WILL_PAYLOAD.as_ref())
+                .unwrap_or_default()
+        }));
+    }
+    Value::Array(availability)
+}
+
+/// Publishes the retained payload via the broker. Strings are published as they are.
+fn send_package(broker: &ReactiveEntityInstance, topic: &str, payload: Value) {
+    let mode = match payload {
+        Value::String(_) => MqttPayloadMode::Raw,
+        _ => MqttPayloadMode::Json,
+    };
+    if let Some(property) = broker
+        .properties
+        .get(MqttBrokerProperties::SEND_PACKAGE.as_ref())
+    {
+        property.set(json!({
+            MqttTopicProperties::TOPIC.as_ref(): topic,
+            MqttTopicProperties::MODE.as_ref(): mode.as_ref(),
+            MqttTopicProperties::QOS.as_ref(): QoS::AtLeastOnce as u8,
+            MqttTopicProperties::RETAIN.as_ref(): true,
+            MqttEndpointProperties::PAYLOAD.as_ref(): payload
+        }));
+    }
+}
+
+impl Disconnectable for MqttHaExposed {
+    fn disconnect(&self) {
+        debug!("Disconnecting mqtt_ha_exposed {}", self.handle_id);
+        if let Some(property) = self.entity.properties.get(self.property.as_str()) {
+            property.stream.read().unwrap().remove(self.handle_id);
+        }
+        if let Some(broker_entity) = self.broker_entity.upgrade() {
+            if let Some(property) = broker_entity
+                .properties
+                .get(MqttBrokerProperties::RECEIVED_PACKAGE.as_ref())
+            {
+                property.stream.read().unwrap().remove(self.handle_id);
+            }
+        }
+        if let Some(broker) = self.broker.upgrade() {
+            if let Some(command_topic) = &self.topics.command_topic {
+                broker.unsubscribe(command_topic.as_str(), QoS::AtLeastOnce);
+            }
+            send_package(
+                &broker.entity,
+                &self.topics.availability_topic,
+                json!(NOT_AVAILABLE),
+            );
+        }
+    }
+}
+
+/// Automatically disconnect streams on destruction
+impl Drop for MqttHaExposed {
+    fn drop(&mut self) {
+        self.disconnect();
+    }
+}
@@ -1,13 +1,45 @@
+use std::convert::TryFrom;
+
 use indradb::{Identifier, NamedProperty};
-use serde_json::{json, Value};
+use rumqttc::QoS;
+use serde_json::{json, Map, Value};
 use strum_macros::{AsRefStr, Display, IntoStaticStr};
 
 use crate::reactive::property::NamedProperties;
 
-#[derive(Copy, Clone, AsRefStr, IntoStaticStr, Display)]
+#[derive(Copy, Clone, Debug, PartialEq, AsRefStr, IntoStaticStr, Display)]
 pub enum MqttPayloadMode {
+    /// Parses JSON and falls back to a string
+    #[strum(serialize = "json")]
     Json,
+    /// Uses the payload as string
+    #[strum(serialize = "raw")]
     Raw,
+    /// Parses JSON and fails if the payload isn't valid JSON
+    #[strum(serialize = "strict_json")]
+    StrictJson,
+    /// Binary payload as base64 encoded string
+    #[strum(serialize = "base64")]
+    Base64,
+    /// Binary payload as array of bytes
+    #[strum(serialize = "bytes")]
+    Bytes,
+}
+
+impl MqttPayloadMode {
+    /// Decodes a received payload.
+    pub fn decode(&self, payload: &[u8]) -> Result<Value, serde_json::Error> {
+        match self {
+            MqttPayloadMode::Json => {
+                let payload = String::from_utf8_lossy(payload);
+                Ok(serde_json::from_str(payload.as_ref()).unwrap_or(json!(payload)))
+            }
+            MqttPayloadMode::Raw => Ok(json!(String::from_utf8_lossy(payload))),
+            MqttPayloadMode::StrictJson => serde_json::from_slice(payload),
+            MqttPayloadMode::Base64 => Ok(json!(base64::encode(payload))),
+            MqttPayloadMode::Bytes => Ok(json!(payload)),
+        }
+    }
 }
 
 impl From<&str> for MqttPayloadMode {
@@ -15,6 +47,9 @@ impl From<&str> for MqttPayloadMode {
         match mode {
             "json" => MqttPayloadMode::Json,
             "raw" => MqttPayloadMode::Raw,
+            "strict_json" => MqttPayloadMode::StrictJson,
+            "base64" => MqttPayloadMode::Base64,
+            "bytes" => MqttPayloadMode::Bytes,
             _ => MqttPayloadMode::Raw,
         }
     }
@@ -23,6 +58,32 @@ impl From<&str> for MqttPayloadMode {
 pub enum MqttPayload {
     Json(Value),
     Raw(Value),
+    Base64(Value),
+    Bytes(Value),
+}
+
+impl MqttPayload {
+    pub fn new(mode: MqttPayloadMode, value: Value) -> Self {
+        match mode {
+            MqttPayloadMode::Json | MqttPayloadMode::StrictJson => MqttPayload::Json(value),
+            MqttPayloadMode::Raw => MqttPayload::Raw(value),
+            MqttPayloadMode::Base64 => MqttPayload::Base64(value),
+            MqttPayloadMode::Bytes => MqttPayload::Bytes(value),
+        }
+    }
+
+    /// Encodes the payload to be published. Returns none if the value doesn't fit the mode.
+    pub fn to_bytes(&self) -> Option<Vec<u8>> {
+        match self {
+            Self::Json(_) | Self::Raw(_) => Some(self.to_string().into_bytes()),
+            Self::Base64(value) => base64::decode(value.as_str()?).ok(),
+            Self::Bytes(value) => value
+                .as_array()?
+                .iter()
+                .map(|byte| byte.as_u64().and_then(|byte| u8::try_from(byte).ok()))
+                .collect(),
+        }
+    }
 }
 
 impl ToString for MqttPayload {
@@ -30,10 +91,147 @@ impl ToString for MqttPayload {
         return match self {
             Self::Json(value) => value.clone().to_string(),
             Self::Raw(value) => value.clone().as_str().unwrap_or("").to_string(),
+            Self::Base64(value) => value.clone().as_str().unwrap_or("").to_string(),
+            Self::Bytes(value) => value.clone().to_string(),
+        };
+    }
+}
+
+/// The received packages contain this key if the payload is encoded.
+pub const PACKAGE_ENCODING: &str = "encoding";
+
+/// Payloads which are not valid UTF-8 are transported base64 encoded.
+pub const PACKAGE_ENCODING_BASE64: &str = "base64";
+
+/// The user properties of a MQTT 5 message as object.
+pub const PACKAGE_USER_PROPERTIES: &str = "user_properties";
+
+/// The content type of the payload of a MQTT 5 message.
+pub const PACKAGE_CONTENT_TYPE: &str = "content_type";
+
+/// The lifetime of a MQTT 5 message in seconds.
+pub const PACKAGE_MESSAGE_EXPIRY: &str = "message_expiry";
+
+/// The properties of a MQTT 5 message. MQTT 3.1.1 connections ignore them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MqttMessageProperties {
+    pub user_properties: Vec<(String, String)>,
+
+    pub content_type: Option<String>,
+
+    pub message_expiry_interval: Option<u32>,
+}
+
+impl MqttMessageProperties {
+    /// Reads the properties of a package. Values of user properties which are not strings are
+    /// converted to JSON.
+    pub fn from_package(package: &Value) -> Self {
+        let user_properties = package
+            .get(PACKAGE_USER_PROPERTIES)
+            .and_then(|user_properties| user_properties.as_object())
+            .map(|user_properties| {
+                user_properties
+                    .iter()
+                    .map(|(name, value)| match value {
+                        Value::String(value) => (name.clone(), value.clone()),
+                        value => (name.clone(), value.to_string()),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let content_type = package
+            .get(PACKAGE_CONTENT_TYPE)
+            .and_then(|content_type| content_type.as_str())
+            .filter(|content_type| !content_type.is_empty())
+            .map(String::from);
+        let message_expiry_interval = package
+            .get(PACKAGE_MESSAGE_EXPIRY)
+            .and_then(|message_expiry| message_expiry.as_u64())
+            .filter(|message_expiry| *message_expiry > 0)
+            .map(|message_expiry| u32::try_from(message_expiry).unwrap_or(u32::MAX));
+        MqttMessageProperties {
+            user_properties,
+            content_type,
+            message_expiry_interval,
+        }
+    }
+
+    /// The user properties as object. If a name occurs multiple times, the last value wins.
+    pub fn user_properties_object(&self) -> Value {
+        let user_properties: Map<String, Value> = self
+            .user_properties
+            .iter()
+            .map(|(name, value)| (name.clone(), json!(value)))
+            .collect();
+        Value::Object(user_properties)
+    }
+
+    /// Adds the properties which are set to the package.
+    pub fn add_to_package(&self, package: &mut Value) {
+        let package = match package.as_object_mut() {
+            Some(package) => package,
+            None => return,
         };
+        if !self.user_properties.is_empty() {
+            package.insert(
+                PACKAGE_USER_PROPERTIES.to_string(),
+                self.user_properties_object(),
+            );
+        }
+        if let Some(content_type) = &self.content_type {
+            package.insert(PACKAGE_CONTENT_TYPE.to_string(), json!(content_type));
+        }
+        if let Some(message_expiry_interval) = self.message_expiry_interval {
+            package.insert(
+                PACKAGE_MESSAGE_EXPIRY.to_string(),
+                json!(message_expiry_interval),
+            );
+        }
+    }
+}
+
+/// Creates the package of a received message.
+pub fn create_received_package(
+    topic: &str,
+    payload: &[u8],
+    properties: &MqttMessageProperties,
+) -> Value {
+    let mut package = match std::str::from_utf8(payload) {
+        Ok(payload) => json!({
+            MqttTopicProperties::TOPIC.as_ref(): topic,
+            MqttEndpointProperties::PAYLOAD.as_ref(): payload
+        }),
+        Err(_) => json!({
+            MqttTopicProperties::TOPIC.as_ref(): topic,
+            MqttEndpointProperties::PAYLOAD.as_ref(): base64::encode(payload),
+            PACKAGE_ENCODING: PACKAGE_ENCODING_BASE64
+        }),
+    };
+    properties.add_to_package(&mut package);
+    package
+}
+
+/// Returns the payload of a received package.
+pub fn get_received_payload(package: &Value) -> Option<Vec<u8>> {
+    let payload = package
+        .get(MqttEndpointProperties::PAYLOAD.as_ref())?
+        .as_str()?;
+    match package
+        .get(PACKAGE_ENCODING)
+        .and_then(|encoding| encoding.as_str())
+    {
+        Some(PACKAGE_ENCODING_BASE64) => base64::decode(payload).ok(),
+        _ => Some(payload.as_bytes().to_vec()),
     }
 }
 
+/// Returns the QoS of the given level (0, 1 or 2).
+pub fn mqtt_qos(level: u64) -> Option<QoS> {
+    u8::try_from(level)
+        .ok()
+        .and_then(|level| rumqttc::qos(level).ok())
+}
+
 #[allow(non_camel_case_types)]
 #[derive(AsRefStr, IntoStaticStr, Display)]
 pub enum MqttTopicProperties {
@@ -41,19 +239,28 @@ pub enum MqttTopicProperties {
     TOPIC,
     #[strum(serialize = "mode")]
     MODE,
+    #[strum(serialize = "qos")]
+    QOS,
+    #[strum(serialize = "retain")]
+    RETAIN,
 }
 
 impl MqttTopicProperties {
-    pub fn default_value(&self) -> String {
+    pub fn default_value(&self) -> Value {
         match self {
-            MqttTopicProperties::TOPIC => String::from(""),
-            MqttTopicProperties::MODE => String::from("json"),
+            MqttTopicProperties::TOPIC => json!(""),
+            MqttTopicProperties::MODE => json!("json"),
+            // Publishes and subscribes at least once, unless the relation sets the QoS
+            MqttTopicProperties::QOS => json!(1),
+            MqttTopicProperties::RETAIN => json!(false),
         }
     }
     pub fn properties() -> NamedProperties {
         vec![
             NamedProperty::from(MqttTopicProperties::TOPIC),
             NamedProperty::from(MqttTopicProperties::MODE),
+            NamedProperty::from(MqttTopicProperties::QOS),
+            NamedProperty::from(MqttTopicProperties::RETAIN),
         ]
     }
 }
@@ -62,7 +269,7 @@ impl From<MqttTopicProperties> for NamedProperty {
     fn from(p: MqttTopicProperties) -> Self {
         NamedProperty {
             name: Identifier::new(p.to_string()).unwrap(),
-            value: json!(p.default_value()),
+            value: p.default_value(),
         }
     }
 }
@@ -75,9 +282,9 @@ pub enum MqttEndpointProperties {
 }
 
 impl MqttEndpointProperties {
-    pub fn default_value(&self) -> String {
+    pub fn default_value(&self) -> Value {
         match self {
-            MqttEndpointProperties::PAYLOAD => String::from(""),
+            MqttEndpointProperties::PAYLOAD => json!(""),
         }
     }
     pub fn properties() -> NamedProperties {
@@ -89,7 +296,60 @@ impl From<MqttEndpointProperties> for NamedProperty {
     fn from(p: MqttEndpointProperties) -> Self {
         NamedProperty {
             name: Identifier::new(p.to_string()).unwrap(),
-            value: json!(p.default_value()),
+            value: p.default_value(),
+        }
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(AsRefStr, IntoStaticStr, Display)]
+pub enum MqttHaExposedProperties {
+    #[strum(serialize = "ha_broker")]
+    HA_BROKER,
+    #[strum(serialize = "ha_discovery_prefix")]
+    HA_DISCOVERY_PREFIX,
+    #[strum(serialize = "ha_component")]
+    HA_COMPONENT,
+    #[strum(serialize = "ha_object_id")]
+    HA_OBJECT_ID,
+    #[strum(serialize = "ha_name")]
+    HA_NAME,
+    #[strum(serialize = "ha_property")]
+    HA_PROPERTY,
+    #[strum(serialize = "ha_settable")]
+    HA_SETTABLE,
+}
+
+impl MqttHaExposedProperties {
+    pub fn default_value(&self) -> Value {
+        match self {
+            MqttHaExposedProperties::HA_BROKER => json!(""),
+            MqttHaExposedProperties::HA_DISCOVERY_PREFIX => json!("homeassistant"),
+            MqttHaExposedProperties::HA_COMPONENT => json!("sensor"),
+            MqttHaExposedProperties::HA_OBJECT_ID => json!(""),
+            MqttHaExposedProperties::HA_NAME => json!(""),
+            MqttHaExposedProperties::HA_PROPERTY => json!("value"),
+            MqttHaExposedProperties::HA_SETTABLE => json!(false),
+        }
+    }
+    pub fn properties() -> NamedProperties {
+        vec![
+            NamedProperty::from(MqttHaExposedProperties::HA_BROKER),
+            NamedProperty::from(MqttHaExposedProperties::HA_DISCOVERY_PREFIX),
+            NamedProperty::from(MqttHaExposedProperties::HA_COMPONENT),
+            NamedProperty::from(MqttHaExposedProperties::HA_OBJECT_ID),
+            NamedProperty::from(MqttHaExposedProperties::HA_NAME),
+            NamedProperty::from(MqttHaExposedProperties::HA_PROPERTY),
+            NamedProperty::from(MqttHaExposedProperties::HA_SETTABLE),
+        ]
+    }
+}
+
+impl From<MqttHaExposedProperties> for NamedProperty {
+    fn from(p: MqttHaExposedProperties) -> Self {
+        NamedProperty {
+            name: Identifier::new(p.to_string()).unwrap(),
+            value: p.default_value(),
         }
     }
 }
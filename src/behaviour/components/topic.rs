@@ -0,0 +1,287 @@
+use std::convert::AsRef;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use log::error;
+use rumqttc::QoS;
+use serde_json::json;
+use serde_json::Value;
+
+use crate::behaviour::components::mqtt_qos;
+use crate::behaviour::components::MqttPayloadMode;
+use crate::behaviour::components::MqttTopicProperties;
+use crate::behaviour::entity::MqttSubscriberProperties;
+use crate::model::PropertyInstanceGetter;
+use crate::model::ReactiveEntityInstance;
+use crate::model::ReactiveRelationInstance;
+
+/// The current configuration of the mqtt_topic component of a relation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MqttTopic {
+    /// The topic with resolved placeholders. Empty if a placeholder can't be resolved. The reason
+    /// is reported in the error property of a subscriber.
+    pub topic: String,
+
+    pub mode: MqttPayloadMode,
+
+    pub qos: QoS,
+
+    pub retain: bool,
+}
+
+impl MqttTopic {
+    /// The placeholders of the topic are resolved against the properties of the given
+    /// publisher or subscriber entity.
+    pub fn new(r: &ReactiveRelationInstance, e: &ReactiveEntityInstance) -> Self {
+        let template = r
+            .as_string(MqttTopicProperties::TOPIC.as_ref())
+            .unwrap_or_default();
+        let topic = match resolve_topic(template.as_str(), e) {
+            Ok(topic) => topic,
+            Err(err) => {
+                error!(
+                    "Failed to resolve topic {} of entity {}: {}",
+                    template, e.id, err
+                );
+                if let Some(property) = e.properties.get(MqttSubscriberProperties::ERROR.as_ref()) {
+                    property.set(json!(err));
+                }
+                String::new()
+            }
+        };
+        let mode = r
+            .as_string(MqttTopicProperties::MODE.as_ref())
+            .map(|mode| MqttPayloadMode::from(mode.as_str()))
+            .unwrap_or(MqttPayloadMode::Json);
+        let qos = r
+            .as_u64(MqttTopicProperties::QOS.as_ref())
+            .and_then(mqtt_qos)
+            .unwrap_or(QoS::AtLeastOnce);
+        let retain = r
+            .as_bool(MqttTopicProperties::RETAIN.as_ref())
+            .unwrap_or(false);
+        MqttTopic {
+            topic,
+            mode,
+            qos,
+            retain,
+        }
+    }
+}
+
+/// Replaces the placeholders (for example `shellies/{device}/relay/0/command`) with the values of
+/// the properties of the entity. Returns an error if the entity doesn't have a property or if a
+/// value contains a topic level separator or a wildcard, which would change the subscribed topics.
+pub fn resolve_topic(template: &str, e: &ReactiveEntityInstance) -> Result<String, String> {
+    let mut topic = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let name = &rest[start + 1..end];
+        let value = match e.properties.get(name) {
+            Some(property) => match property.get() {
+                Value::String(value) => value,
+                value => value.to_string(),
+            },
+            None => return Err(format!("No property {}", name)),
+        };
+        if value.contains(&['/', '+', '#'][..]) {
+            return Err(format!(
+                "The value {} of property {} isn't a single topic level",
+                value, name
+            ));
+        }
+        topic.push_str(&rest[..start]);
+        topic.push_str(value.as_str());
+        rest = &rest[end + 1..];
+    }
+    topic.push_str(rest);
+    Ok(topic)
+}
+
+/// Returns the names of the properties which are used as placeholders by the topic template.
+pub fn topic_placeholders(template: &str) -> Vec<&str> {
+    let mut placeholders = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        placeholders.push(&rest[start + 1..end]);
+        rest = &rest[end + 1..];
+    }
+    placeholders
+}
+
+/// Calls the observer whenever a property of the mqtt_topic component of the relation or a
+/// property of the entity which is a placeholder of the topic template changes.
+///
+/// The observed placeholders follow changes of the topic template. The observers only hold weak
+/// references, because the relation and the entity own the streams of their properties.
+pub fn observe_topic<F>(
+    r: &Arc<ReactiveRelationInstance>,
+    e: &Arc<ReactiveEntityInstance>,
+    ignored_properties: Vec<String>,
+    handle_id: u128,
+    observer: F,
+) where
+    F: Fn(&ReactiveRelationInstance) + Send + Sync + 'static,
+{
+    let observer = Arc::new(observer);
+    let relation = Arc::downgrade(r);
+    let notify = move || {
+        if let Some(relation) = relation.upgrade() {
+            observer(&relation);
+        }
+    };
+
+    let template = r
+        .as_string(MqttTopicProperties::TOPIC.as_ref())
+        .unwrap_or_default();
+    observe_placeholders(
+        e,
+        template.as_str(),
+        &ignored_properties,
+        handle_id,
+        &notify,
+    );
+
+    if let Some(property) = r.properties.get(MqttTopicProperties::TOPIC.as_ref()) {
+        let entity = Arc::downgrade(e);
+        let observed_template = Mutex::new(template);
+        let notify = notify.clone();
+        property.stream.read().unwrap().observe_with_handle(
+            move |v| {
+                if let Some(entity) = entity.upgrade() {
+                    let template = v.as_str().unwrap_or_default().to_string();
+                    let mut observed_template = observed_template.lock().unwrap();
+                    if *observed_template != template {
+                        unobserve_placeholders(
+                            &entity,
+                            observed_template.as_str(),
+                            &ignored_properties,
+                            handle_id,
+                        );
+                        observe_placeholders(
+                            &entity,
+                            template.as_str(),
+                            &ignored_properties,
+                            handle_id,
+                            &notify,
+                        );
+                        *observed_template = template;
+                    }
+                }
+                notify();
+            },
+            handle_id,
+        );
+    }
+    for property in [
+        MqttTopicProperties::MODE,
+        MqttTopicProperties::QOS,
+        MqttTopicProperties::RETAIN,
+    ] {
+        if let Some(property) = r.properties.get(property.as_ref()) {
+            let notify = notify.clone();
+            property
+                .stream
+                .read()
+                .unwrap()
+                .observe_with_handle(move |_| notify(), handle_id);
+        }
+    }
+}
+
+pub fn unobserve_topic(
+    r: &ReactiveRelationInstance,
+    e: &ReactiveEntityInstance,
+    ignored_properties: &[String],
+    handle_id: u128,
+) {
+    for property in MqttTopicProperties::properties() {
+        if let Some(property) = r.properties.get(property.name.as_str()) {
+            property.stream.read().unwrap().remove(handle_id);
+        }
+    }
+    // The placeholders of the current template are observed
+    let template = r
+        .as_string(MqttTopicProperties::TOPIC.as_ref())
+        .unwrap_or_default();
+    unobserve_placeholders(e, template.as_str(), ignored_properties, handle_id);
+}
+
+fn observe_placeholders<F>(
+    e: &ReactiveEntityInstance,
+    template: &str,
+    ignored_properties: &[String],
+    handle_id: u128,
+    notify: &F,
+) where
+    F: Fn() + Clone + Send + Sync + 'static,
+{
+    for name in topic_placeholders(template) {
+        if ignored_properties.iter().any(|ignored| ignored == name) {
+            continue;
+        }
+        if let Some(property) = e.properties.get(name) {
+            let notify = notify.clone();
+            property
+                .stream
+                .read()
+                .unwrap()
+                .observe_with_handle(move |_| notify(), handle_id);
+        }
+    }
+}
+
+fn unobserve_placeholders(
+    e: &ReactiveEntityInstance,
+    template: &str,
+    ignored_properties: &[String],
+    handle_id: u128,
+) {
+    for name in topic_placeholders(template) {
+        if ignored_properties.iter().any(|ignored| ignored == name) {
+            continue;
+        }
+        if let Some(property) = e.properties.get(name) {
+            property.stream.read().unwrap().remove(handle_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::builder::ReactiveEntityInstanceBuilder;
+
+    use super::*;
+
+    #[test]
+    fn placeholders_are_replaced_by_values() {
+        let mut builder = ReactiveEntityInstanceBuilder::new("mqtt_subscriber");
+        builder
+            .property("device", json!("shelly-rgbw2"))
+            .property("channel", json!(0));
+        let e = builder.get();
+        assert_eq!(
+            Ok(String::from("shellies/shelly-rgbw2/color/0")),
+            resolve_topic("shellies/{device}/color/{channel}", &e)
+        );
+        assert!(resolve_topic("shellies/{missing}/color", &e).is_err());
+    }
+
+    #[test]
+    fn values_with_separators_or_wildcards_are_rejected() {
+        for value in ["a/b", "+", "#"] {
+            let mut builder = ReactiveEntityInstanceBuilder::new("mqtt_subscriber");
+            builder.property("device", json!(value));
+            let e = builder.get();
+            assert!(resolve_topic("shellies/{device}/color/0", &e).is_err());
+        }
+    }
+}
@@ -0,0 +1,110 @@
+/// Matches a topic name against a topic filter (MQTT 3.1.1, chapter 4.7).
+///
+/// * `+` matches exactly one topic level
+/// * `#` matches the parent level and any number of child levels (only allowed as last level)
+/// * Topics starting with `$` are not matched by filters starting with a wildcard
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    topic_captures(filter, topic).is_some()
+}
+
+/// Returns true if the topic filter is valid.
+pub fn is_valid_topic_filter(filter: &str) -> bool {
+    if filter.is_empty() {
+        return false;
+    }
+    let levels: Vec<&str> = filter.split('/').collect();
+    let last = levels.len() - 1;
+    levels.iter().enumerate().all(|(i, level)| match *level {
+        "#" => i == last,
+        "+" => true,
+        level => !level.contains('+') && !level.contains('#'),
+    })
+}
+
+/// Matches a topic name against a topic filter and returns the topic levels matched by `+`.
+pub fn topic_captures(filter: &str, topic: &str) -> Option<Vec<String>> {
+    if !is_valid_topic_filter(filter) || topic.is_empty() {
+        return None;
+    }
+    // Topic names must not contain wildcards
+    if topic.contains('+') || topic.contains('#') {
+        return None;
+    }
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return None;
+    }
+    let mut captures = Vec::new();
+    let mut topic_levels = topic.split('/');
+    for filter_level in filter.split('/') {
+        match filter_level {
+            // Also matches the parent level: "sport/#" matches "sport"
+            "#" => return Some(captures),
+            "+" => captures.push(topic_levels.next()?.to_string()),
+            filter_level => {
+                if topic_levels.next()? != filter_level {
+                    return None;
+                }
+            }
+        }
+    }
+    match topic_levels.next() {
+        Some(_) => None,
+        None => Some(captures),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multi_level_wildcard_matches_parent_level() {
+        assert!(topic_matches("sport/#", "sport"));
+        assert!(topic_matches("sport/#", "sport/tennis/player1"));
+        assert!(!topic_matches("sport/#", "sports"));
+    }
+
+    #[test]
+    fn wildcards_do_not_match_system_topics() {
+        assert!(!topic_matches("#", "$SYS/broker/uptime"));
+        assert!(!topic_matches("+/broker/uptime", "$SYS/broker/uptime"));
+        assert!(topic_matches("$SYS/#", "$SYS/broker/uptime"));
+    }
+
+    #[test]
+    fn single_level_wildcard_matches_empty_level() {
+        assert!(topic_matches("+/finance", "/finance"));
+        assert!(topic_matches("/+", "/finance"));
+        assert!(!topic_matches("+", "/finance"));
+    }
+
+    #[test]
+    fn invalid_filters_are_rejected() {
+        assert!(!is_valid_topic_filter("sport/tennis#"));
+        assert!(!is_valid_topic_filter("sport/#/x"));
+        assert!(!is_valid_topic_filter("sport+"));
+        assert!(!is_valid_topic_filter(""));
+        assert!(!topic_matches("sport/tennis#", "sport/tennis#"));
+        assert!(!topic_matches("sport/#/x", "sport/tennis/x"));
+    }
+
+    #[test]
+    fn topics_with_wildcards_are_rejected() {
+        assert!(!topic_matches("#", "sport/+"));
+        assert!(!topic_matches("sport/#", "sport/#"));
+    }
+
+    #[test]
+    fn captures_are_returned_in_order() {
+        assert_eq!(
+            Some(vec![String::from("tennis"), String::from("player1")]),
+            topic_captures("sport/+/+/ranking", "sport/tennis/player1/ranking")
+        );
+        assert_eq!(
+            Some(vec![String::from("")]),
+            topic_captures("+/finance", "/finance")
+        );
+        assert_eq!(Some(Vec::new()), topic_captures("sport/#", "sport/tennis"));
+        assert_eq!(None, topic_captures("sport/+", "sport/tennis/player1"));
+    }
+}
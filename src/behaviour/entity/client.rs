@@ -0,0 +1,630 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rumqttc::v5;
+use rumqttc::v5::mqttbytes::v5::ConnectProperties;
+use rumqttc::v5::mqttbytes::v5::Filter;
+use rumqttc::v5::mqttbytes::v5::Packet as V5Packet;
+use rumqttc::v5::mqttbytes::v5::PubAckReason;
+use rumqttc::v5::mqttbytes::v5::PubRecReason;
+use rumqttc::v5::mqttbytes::v5::PublishProperties;
+use rumqttc::v5::mqttbytes::v5::SubscribeReasonCode;
+use rumqttc::v5::mqttbytes::v5::UnsubAckReason;
+use rumqttc::v5::Request as V5Request;
+use rumqttc::AsyncClient;
+use rumqttc::ClientError;
+use rumqttc::ConnectionError;
+use rumqttc::Event;
+use rumqttc::EventLoop;
+use rumqttc::LastWill;
+use rumqttc::MqttOptions;
+use rumqttc::Packet;
+use rumqttc::QoS;
+use rumqttc::SubscribeFilter;
+use rumqttc::Transport;
+
+use crate::behaviour::components::MqttMessageProperties;
+use crate::behaviour::entity::offline_queue::MqttPublishRequest;
+
+/// The version of the MQTT protocol as specified by the protocol level.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MqttProtocolVersion {
+    /// MQTT 3.1.1
+    V4,
+    /// MQTT 5
+    V5,
+}
+
+impl MqttProtocolVersion {
+    pub fn from_level(level: u64) -> Option<Self> {
+        match level {
+            4 => Some(MqttProtocolVersion::V4),
+            5 => Some(MqttProtocolVersion::V5),
+            _ => None,
+        }
+    }
+}
+
+/// The options of a client connection which are common to all protocol versions.
+pub struct MqttClientOptions {
+    pub protocol_version: MqttProtocolVersion,
+
+    pub client_id: String,
+
+    /// The hostname or the URL of a websocket endpoint
+    pub broker_address: String,
+
+    pub port: u16,
+
+    pub keep_alive: Duration,
+
+    pub clean_session: bool,
+
+    pub max_inflight: u16,
+
+    pub max_packet_size: usize,
+
+    pub request_channel_capacity: usize,
+
+    /// The maximum number of topic aliases (MQTT 5 only)
+    pub topic_alias_max: u16,
+
+    pub credentials: Option<(String, String)>,
+
+    pub last_will: Option<MqttPublishRequest>,
+
+    pub transport: Transport,
+}
+
+impl MqttClientOptions {
+    /// Creates the client and the event loop for the protocol version.
+    pub fn create_client(self) -> (MqttClient, MqttClientEventLoop) {
+        match self.protocol_version {
+            MqttProtocolVersion::V4 => {
+                let mut mqtt_options =
+                    MqttOptions::new(self.client_id, self.broker_address, self.port);
+                mqtt_options.set_keep_alive(self.keep_alive);
+                mqtt_options.set_clean_session(self.clean_session);
+                mqtt_options.set_inflight(self.max_inflight);
+                mqtt_options.set_max_packet_size(self.max_packet_size, self.max_packet_size);
+                mqtt_options.set_request_channel_capacity(self.request_channel_capacity);
+                if let Some((username, password)) = self.credentials {
+                    mqtt_options.set_credentials(username, password);
+                }
+                if let Some(last_will) = self.last_will {
+                    mqtt_options.set_last_will(LastWill::new(
+                        last_will.topic,
+                        last_will.payload,
+                        last_will.qos,
+                        last_will.retain,
+                    ));
+                }
+                mqtt_options.set_transport(self.transport);
+                let (client, event_loop) =
+                    AsyncClient::new(mqtt_options, self.request_channel_capacity);
+                (MqttClient::V4(client), MqttClientEventLoop::V4(event_loop))
+            }
+            MqttProtocolVersion::V5 => {
+                let mut mqtt_options =
+                    v5::MqttOptions::new(self.client_id, self.broker_address, self.port);
+                mqtt_options.set_keep_alive(self.keep_alive);
+                mqtt_options.set_clean_start(self.clean_session);
+                let mut connect_properties = ConnectProperties::new();
+                // Without a session expiry interval the broker discards the session on disconnect
+                if !self.clean_session {
+                    connect_properties.session_expiry_interval = Some(u32::MAX);
+                }
+                connect_properties.receive_maximum = Some(self.max_inflight);
+                connect_properties.max_packet_size =
+                    Some(u32::try_from(self.max_packet_size).unwrap_or(u32::MAX));
+                connect_properties.topic_alias_max = Some(self.topic_alias_max);
+                mqtt_options.set_connect_properties(connect_properties);
+                mqtt_options.set_request_channel_capacity(self.request_channel_capacity);
+                if let Some((username, password)) = self.credentials {
+                    mqtt_options.set_credentials(username, password);
+                }
+                if let Some(last_will) = self.last_will {
+                    mqtt_options.set_last_will(v5::mqttbytes::v5::LastWill::new(
+                        last_will.topic,
+                        last_will.payload,
+                        v5_qos(last_will.qos),
+                        last_will.retain,
+                        None,
+                    ));
+                }
+                mqtt_options.set_transport(self.transport);
+                let (client, event_loop) =
+                    v5::AsyncClient::new(mqtt_options, self.request_channel_capacity);
+                let topic_aliases =
+                    Arc::new(Mutex::new(MqttTopicAliases::new(self.topic_alias_max)));
+                (
+                    MqttClient::V5(client, topic_aliases.clone()),
+                    MqttClientEventLoop::V5(event_loop, topic_aliases),
+                )
+            }
+        }
+    }
+}
+
+/// The topic aliases which have been assigned to outgoing messages of the current connection.
+pub struct MqttTopicAliases {
+    /// The maximum number of topic aliases configured by the client
+    client_maximum: u16,
+
+    /// The maximum number of topic aliases of the current connection
+    maximum: u16,
+
+    aliases: HashMap<String, u16>,
+}
+
+impl MqttTopicAliases {
+    fn new(client_maximum: u16) -> Self {
+        MqttTopicAliases {
+            client_maximum,
+            maximum: 0,
+            aliases: HashMap::new(),
+        }
+    }
+
+    /// Topic aliases are only valid for a single connection. The broker announces how many
+    /// topic aliases it accepts in the CONNACK. Returns the topics of the aliases of the
+    /// previous connection.
+    fn reset(&mut self, broker_maximum: u16) -> HashMap<u16, String> {
+        self.maximum = self.client_maximum.min(broker_maximum);
+        self.aliases
+            .drain()
+            .map(|(topic, alias)| (alias, topic))
+            .collect()
+    }
+
+    /// Messages which are published until the next CONNACK don't use topic aliases.
+    fn disconnected(&mut self) {
+        self.maximum = 0;
+    }
+
+    /// Returns the topic to send and the alias of the topic. The topic is empty if the broker
+    /// already knows the alias.
+    fn alias(&mut self, topic: String) -> (String, Option<u16>) {
+        if self.maximum == 0 {
+            return (topic, None);
+        }
+        if let Some(alias) = self.aliases.get(&topic) {
+            return (String::new(), Some(*alias));
+        }
+        if self.aliases.len() >= self.maximum as usize {
+            return (topic, None);
+        }
+        let alias = self.aliases.len() as u16 + 1;
+        self.aliases.insert(topic.clone(), alias);
+        (topic, Some(alias))
+    }
+
+    /// Releases the alias if the message which should have introduced it couldn't be sent. Only
+    /// the most recent alias is released, so the aliases stay consecutive.
+    fn release(&mut self, alias: u16) {
+        if alias as usize == self.aliases.len() {
+            self.aliases.retain(|_, assigned| *assigned != alias);
+        }
+    }
+}
+
+/// A client of either protocol version. The QoS of MQTT 3.1.1 is used for both versions.
+#[derive(Clone)]
+pub enum MqttClient {
+    V4(AsyncClient),
+    V5(v5::AsyncClient, Arc<Mutex<MqttTopicAliases>>),
+}
+
+impl MqttClient {
+    pub fn try_subscribe(&self, topic: &str, qos: QoS) -> Result<(), MqttClientError> {
+        match self {
+            MqttClient::V4(client) => client
+                .try_subscribe(topic, qos)
+                .map_err(MqttClientError::V4),
+            MqttClient::V5(client, _) => client
+                .try_subscribe(topic, v5_qos(qos))
+                .map_err(MqttClientError::V5),
+        }
+    }
+
+    /// Subscribes all topics with a single SUBSCRIBE.
+    pub fn try_subscribe_many(&self, topics: Vec<(String, QoS)>) -> Result<(), MqttClientError> {
+        match self {
+            MqttClient::V4(client) => client
+                .try_subscribe_many(
+                    topics
+                        .into_iter()
+                        .map(|(topic, qos)| SubscribeFilter::new(topic, qos)),
+                )
+                .map_err(MqttClientError::V4),
+            MqttClient::V5(client, _) => client
+                .try_subscribe_many(
+                    topics
+                        .into_iter()
+                        .map(|(topic, qos)| Filter::new(topic, v5_qos(qos))),
+                )
+                .map_err(MqttClientError::V5),
+        }
+    }
+
+    pub async fn subscribe(&self, topic: &str, qos: QoS) -> Result<(), MqttClientError> {
+        match self {
+            MqttClient::V4(client) => client
+                .subscribe(topic, qos)
+                .await
+                .map_err(MqttClientError::V4),
+            MqttClient::V5(client, _) => client
+                .subscribe(topic, v5_qos(qos))
+                .await
+                .map_err(MqttClientError::V5),
+        }
+    }
+
+    pub fn try_unsubscribe(&self, topic: &str) -> Result<(), MqttClientError> {
+        match self {
+            MqttClient::V4(client) => client.try_unsubscribe(topic).map_err(MqttClientError::V4),
+            MqttClient::V5(client, _) => client.try_unsubscribe(topic).map_err(MqttClientError::V5),
+        }
+    }
+
+    pub async fn unsubscribe(&self, topic: &str) -> Result<(), MqttClientError> {
+        match self {
+            MqttClient::V4(client) => client.unsubscribe(topic).await.map_err(MqttClientError::V4),
+            MqttClient::V5(client, _) => {
+                client.unsubscribe(topic).await.map_err(MqttClientError::V5)
+            }
+        }
+    }
+
+    pub fn try_publish(
+        &self,
+        topic: String,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+        properties: &MqttMessageProperties,
+    ) -> Result<(), MqttClientError> {
+        match self {
+            MqttClient::V4(client) => client
+                .try_publish(topic, qos, retain, payload)
+                .map_err(MqttClientError::V4),
+            MqttClient::V5(client, topic_aliases) => {
+                // The message is handed over to the request channel while holding the lock, so
+                // the event loop can't start the next connection in between
+                let mut topic_aliases = topic_aliases.lock().unwrap();
+                let (topic, properties) =
+                    publish_properties(&mut topic_aliases, topic, qos, properties);
+                let topic_alias = properties.topic_alias;
+                let result = client
+                    .try_publish_with_properties(topic, v5_qos(qos), retain, payload, properties)
+                    .map_err(MqttClientError::V5);
+                if let (Err(_), Some(topic_alias)) = (&result, topic_alias) {
+                    topic_aliases.release(topic_alias);
+                }
+                result
+            }
+        }
+    }
+
+    pub fn try_disconnect(&self) -> Result<(), MqttClientError> {
+        match self {
+            MqttClient::V4(client) => client.try_disconnect().map_err(MqttClientError::V4),
+            MqttClient::V5(client, _) => client.try_disconnect().map_err(MqttClientError::V5),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MqttClientError {
+    V4(ClientError),
+    V5(v5::ClientError),
+}
+
+/// The event loop of a client of either protocol version.
+pub enum MqttClientEventLoop {
+    V4(EventLoop),
+    V5(v5::EventLoop, Arc<Mutex<MqttTopicAliases>>),
+}
+
+impl MqttClientEventLoop {
+    pub async fn poll(&mut self) -> Result<MqttEvent, MqttConnectionError> {
+        match self {
+            MqttClientEventLoop::V4(event_loop) => match event_loop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => Ok(MqttEvent::ConnAck),
+                Ok(Event::Incoming(Packet::Publish(publish))) => Ok(MqttEvent::Publish {
+                    topic: publish.topic,
+                    payload: publish.payload.to_vec(),
+                    properties: MqttMessageProperties::default(),
+                }),
+                Ok(event) => Ok(MqttEvent::Other(format!("{:?}", event))),
+                Err(err) => Err(MqttConnectionError::V4(err)),
+            },
+            MqttClientEventLoop::V5(event_loop, topic_aliases) => match event_loop.poll().await {
+                Ok(v5::Event::Incoming(V5Packet::ConnAck(connack))) => {
+                    let topic_alias_max = connack
+                        .properties
+                        .and_then(|properties| properties.topic_alias_max)
+                        .unwrap_or(0);
+                    let previous_aliases = topic_aliases.lock().unwrap().reset(topic_alias_max);
+                    restore_aliased_topics(event_loop, &previous_aliases);
+                    Ok(MqttEvent::ConnAck)
+                }
+                // Incoming topic aliases are resolved by rumqttc
+                Ok(v5::Event::Incoming(V5Packet::Publish(publish))) => {
+                    let properties = publish.properties.unwrap_or_default();
+                    Ok(MqttEvent::Publish {
+                        topic: String::from_utf8_lossy(publish.topic.as_ref()).to_string(),
+                        payload: publish.payload.to_vec(),
+                        properties: MqttMessageProperties {
+                            user_properties: properties.user_properties,
+                            content_type: properties.content_type,
+                            message_expiry_interval: properties.message_expiry_interval,
+                        },
+                    })
+                }
+                Ok(v5::Event::Incoming(packet)) => match ack_failure(&packet) {
+                    Some(failure) => Ok(MqttEvent::AckFailure(failure)),
+                    None => Ok(MqttEvent::Other(format!(
+                        "{:?}",
+                        v5::Event::Incoming(packet)
+                    ))),
+                },
+                Ok(event) => Ok(MqttEvent::Other(format!("{:?}", event))),
+                Err(err) => {
+                    topic_aliases.lock().unwrap().disconnected();
+                    Err(MqttConnectionError::V5(err))
+                }
+            },
+        }
+    }
+}
+
+/// The events of the event loop which are handled by the connection.
+pub enum MqttEvent {
+    ConnAck,
+    Publish {
+        topic: String,
+        payload: Vec<u8>,
+        properties: MqttMessageProperties,
+    },
+    /// The broker rejected a subscription or a message with the described reason code
+    AckFailure(String),
+    /// Any other event for tracing
+    Other(String),
+}
+
+/// Describes the failing reason code of a MQTT 5 acknowledgement. Reason codes below 0x80, like
+/// no matching subscribers, are not failures.
+fn ack_failure(packet: &V5Packet) -> Option<String> {
+    let (name, pkid, reason, reason_string) = match packet {
+        V5Packet::SubAck(suback) => (
+            "SUBACK",
+            suback.pkid,
+            suback
+                .return_codes
+                .iter()
+                .find(|code| !matches!(code, SubscribeReasonCode::Success(_)))
+                .map(|code| format!("{:?}", code))?,
+            suback
+                .properties
+                .as_ref()
+                .and_then(|properties| properties.reason_string.clone()),
+        ),
+        V5Packet::UnsubAck(unsuback) => (
+            "UNSUBACK",
+            unsuback.pkid,
+            unsuback
+                .reasons
+                .iter()
+                .find(|reason| {
+                    !matches!(
+                        reason,
+                        UnsubAckReason::Success | UnsubAckReason::NoSubscriptionExisted
+                    )
+                })
+                .map(|reason| format!("{:?}", reason))?,
+            unsuback
+                .properties
+                .as_ref()
+                .and_then(|properties| properties.reason_string.clone()),
+        ),
+        V5Packet::PubAck(puback)
+            if !matches!(
+                puback.reason,
+                PubAckReason::Success | PubAckReason::NoMatchingSubscribers
+            ) =>
+        {
+            (
+                "PUBACK",
+                puback.pkid,
+                format!("{:?}", puback.reason),
+                puback
+                    .properties
+                    .as_ref()
+                    .and_then(|properties| properties.reason_string.clone()),
+            )
+        }
+        V5Packet::PubRec(pubrec)
+            if !matches!(
+                pubrec.reason,
+                PubRecReason::Success | PubRecReason::NoMatchingSubscribers
+            ) =>
+        {
+            (
+                "PUBREC",
+                pubrec.pkid,
+                format!("{:?}", pubrec.reason),
+                pubrec
+                    .properties
+                    .as_ref()
+                    .and_then(|properties| properties.reason_string.clone()),
+            )
+        }
+        _ => return None,
+    };
+    Some(match reason_string {
+        Some(reason_string) => format!("{} {}: {} ({})", name, pkid, reason, reason_string),
+        None => format!("{} {}: {}", name, pkid, reason),
+    })
+}
+
+/// The MQTT 5 errors contain the reason codes of the broker.
+#[derive(Debug)]
+pub enum MqttConnectionError {
+    V4(ConnectionError),
+    V5(v5::ConnectionError),
+}
+
+impl fmt::Display for MqttConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MqttConnectionError::V4(err) => write!(f, "{}", err),
+            MqttConnectionError::V5(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Only messages with QoS 0 use topic aliases, because messages with a higher QoS may be
+/// retransmitted on a connection which doesn't know the alias.
+fn publish_properties(
+    topic_aliases: &mut MqttTopicAliases,
+    topic: String,
+    qos: QoS,
+    properties: &MqttMessageProperties,
+) -> (String, PublishProperties) {
+    let (topic, topic_alias) = match qos {
+        QoS::AtMostOnce => topic_aliases.alias(topic),
+        _ => (topic, None),
+    };
+    let properties = PublishProperties {
+        user_properties: properties.user_properties.clone(),
+        content_type: properties.content_type.clone(),
+        message_expiry_interval: properties.message_expiry_interval,
+        topic_alias,
+        ..Default::default()
+    };
+    (topic, properties)
+}
+
+/// rumqttc keeps the requests of a failed connection pending and sends them with the next
+/// connection, which doesn't know the topic aliases of the previous connection. Replaces the
+/// aliases of the pending messages with their topics.
+fn restore_aliased_topics(event_loop: &mut v5::EventLoop, aliases: &HashMap<u16, String>) {
+    let pending: Vec<V5Request> =
+        std::mem::replace(&mut event_loop.pending, Vec::new().into_iter()).collect();
+    event_loop.pending = pending
+        .into_iter()
+        .map(|request| match request {
+            V5Request::Publish(mut publish) => {
+                let topic_alias = publish
+                    .properties
+                    .as_mut()
+                    .and_then(|properties| properties.topic_alias.take());
+                if let Some(topic) = topic_alias.and_then(|alias| aliases.get(&alias)) {
+                    if publish.topic.is_empty() {
+                        publish.topic = topic.clone().into();
+                    }
+                }
+                V5Request::Publish(publish)
+            }
+            request => request,
+        })
+        .collect::<Vec<V5Request>>()
+        .into_iter();
+}
+
+fn v5_qos(qos: QoS) -> v5::mqttbytes::QoS {
+    match qos {
+        QoS::AtMostOnce => v5::mqttbytes::QoS::AtMostOnce,
+        QoS::AtLeastOnce => v5::mqttbytes::QoS::AtLeastOnce,
+        QoS::ExactlyOnce => v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rumqttc::v5::mqttbytes::v5::PubAck;
+    use rumqttc::v5::mqttbytes::v5::SubAck;
+
+    use super::*;
+
+    #[test]
+    fn failing_reason_codes_are_ack_failures() {
+        let suback = V5Packet::SubAck(SubAck {
+            pkid: 1,
+            return_codes: vec![
+                SubscribeReasonCode::Success(v5::mqttbytes::QoS::AtLeastOnce),
+                SubscribeReasonCode::NotAuthorized,
+            ],
+            properties: None,
+        });
+        assert_eq!(
+            ack_failure(&suback),
+            Some(String::from("SUBACK 1: NotAuthorized"))
+        );
+        let mut puback = PubAck::new(2, None);
+        puback.reason = PubAckReason::QuotaExceeded;
+        assert_eq!(
+            ack_failure(&V5Packet::PubAck(puback)),
+            Some(String::from("PUBACK 2: QuotaExceeded"))
+        );
+    }
+
+    #[test]
+    fn successful_reason_codes_are_no_ack_failures() {
+        let mut puback = PubAck::new(1, None);
+        assert_eq!(ack_failure(&V5Packet::PubAck(puback.clone())), None);
+        puback.reason = PubAckReason::NoMatchingSubscribers;
+        assert_eq!(ack_failure(&V5Packet::PubAck(puback)), None);
+    }
+
+    #[test]
+    fn topic_aliases_are_only_assigned_while_connected() {
+        let mut topic_aliases = MqttTopicAliases::new(10);
+        assert_eq!(
+            topic_aliases.alias(String::from("a")),
+            (String::from("a"), None)
+        );
+        topic_aliases.reset(2);
+        assert_eq!(
+            topic_aliases.alias(String::from("a")),
+            (String::from("a"), Some(1))
+        );
+        assert_eq!(
+            topic_aliases.alias(String::from("a")),
+            (String::new(), Some(1))
+        );
+        topic_aliases.disconnected();
+        assert_eq!(
+            topic_aliases.alias(String::from("a")),
+            (String::from("a"), None)
+        );
+        let previous_aliases = topic_aliases.reset(2);
+        assert_eq!(previous_aliases.get(&1), Some(&String::from("a")));
+        assert_eq!(
+            topic_aliases.alias(String::from("b")),
+            (String::from("b"), Some(1))
+        );
+    }
+
+    #[test]
+    fn only_the_most_recent_topic_alias_is_released() {
+        let mut topic_aliases = MqttTopicAliases::new(10);
+        topic_aliases.reset(10);
+        topic_aliases.alias(String::from("a"));
+        topic_aliases.alias(String::from("b"));
+        topic_aliases.release(1);
+        topic_aliases.release(2);
+        assert_eq!(
+            topic_aliases.alias(String::from("a")),
+            (String::new(), Some(1))
+        );
+        assert_eq!(
+            topic_aliases.alias(String::from("c")),
+            (String::from("c"), Some(2))
+        );
+    }
+}
@@ -0,0 +1,702 @@
+use std::convert::AsRef;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::thread::JoinHandle;
+use std::thread::ThreadId;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use async_std::task;
+use log::debug;
+use log::error;
+use log::trace;
+use rumqttc::v5;
+use rumqttc::ConnectionError;
+use rumqttc::QoS;
+use serde_json::json;
+use serde_json::Value;
+use tokio::runtime;
+use tokio::sync::Notify;
+use tokio::time;
+
+use crate::behaviour::components::create_received_package;
+use crate::behaviour::components::mqtt_qos;
+use crate::behaviour::components::MqttMessageProperties;
+use crate::behaviour::entity::client::MqttClient;
+use crate::behaviour::entity::client::MqttClientError;
+use crate::behaviour::entity::client::MqttClientEventLoop;
+use crate::behaviour::entity::client::MqttClientOptions;
+use crate::behaviour::entity::client::MqttConnectionError;
+use crate::behaviour::entity::client::MqttEvent;
+use crate::behaviour::entity::client::MqttProtocolVersion;
+use crate::behaviour::entity::offline_queue::MqttOfflineQueue;
+use crate::behaviour::entity::offline_queue::MqttPublishRequest;
+use crate::behaviour::entity::reconnect::MqttReconnectPolicy;
+use crate::behaviour::entity::subscriptions::MqttSubscriptions;
+use crate::behaviour::entity::transport::create_transport;
+use crate::behaviour::entity::MqttBrokerProperties;
+use crate::model::PropertyInstanceGetter;
+use crate::model::ReactiveEntityInstance;
+use crate::reactive::BehaviourCreationError;
+
+/// The minimum keep alive in seconds which is accepted by rumqttc.
+const MIN_KEEP_ALIVE: u64 = 5;
+
+/// The maximum size of incoming and outgoing packets in bytes. rumqttc only allows 10 KiB by
+/// default, which is too small for retained configs or images.
+const DEFAULT_MAX_PACKET_SIZE: u64 = 256 * 1024;
+
+/// A client connection to a MQTT broker. The connection is configured by the properties of the
+/// mqtt_broker entity.
+pub struct MqttConnection {
+    pub hostname: String,
+
+    pub port: u16,
+
+    client: MqttClient,
+
+    subscriptions: Arc<Mutex<MqttSubscriptions>>,
+
+    offline_queue: Arc<Mutex<MqttOfflineQueue>>,
+
+    shutdown: Arc<Notify>,
+
+    /// The event loop stopped after the reconnect policy gave up
+    gave_up: Arc<AtomicBool>,
+
+    event_loop_thread: Option<JoinHandle<()>>,
+
+    event_loop_thread_id: ThreadId,
+}
+
+impl MqttConnection {
+    /// The password isn't part of the graph, it's kept by the plugin.
+    pub fn connect(
+        e: Arc<ReactiveEntityInstance>,
+        password: &str,
+        subscriptions: Arc<Mutex<MqttSubscriptions>>,
+        offline_queue: Arc<Mutex<MqttOfflineQueue>>,
+    ) -> Result<MqttConnection, BehaviourCreationError> {
+        let hostname = e
+            .as_string(MqttBrokerProperties::HOSTNAME.as_ref())
+            .unwrap_or_else(|| {
+                MqttBrokerProperties::HOSTNAME
+                    .default_value()
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string()
+            });
+        let port = e
+            .as_i64(MqttBrokerProperties::PORT.as_ref())
+            .unwrap_or(1883) as u16;
+
+        let protocol_version = e
+            .as_u64(MqttBrokerProperties::PROTOCOL_VERSION.as_ref())
+            .unwrap_or(4);
+        let protocol_version = match MqttProtocolVersion::from_level(protocol_version) {
+            Some(protocol_version) => protocol_version,
+            None => {
+                error!(
+                    "Unsupported protocol version {} for MQTT broker {}:{}",
+                    protocol_version,
+                    hostname.clone(),
+                    port
+                );
+                return Err(BehaviourCreationError.into());
+            }
+        };
+
+        let mqtt_client_id = e
+            .as_string(MqttBrokerProperties::CLIENT_ID.as_ref())
+            .unwrap_or_default();
+        let mqtt_client_id = match mqtt_client_id.is_empty() {
+            true => format!("inexor-{}", e.id),
+            false => mqtt_client_id,
+        };
+        // rumqttc panics on invalid client ids
+        if mqtt_client_id.starts_with(' ') {
+            error!(
+                "Invalid client id \"{}\" for MQTT broker {}:{}",
+                mqtt_client_id,
+                hostname.clone(),
+                port
+            );
+            return Err(BehaviourCreationError.into());
+        }
+        // rumqttc panics on a keep alive of less than 5 seconds
+        let keep_alive = e
+            .as_u64(MqttBrokerProperties::KEEP_ALIVE.as_ref())
+            .unwrap_or(60);
+        if keep_alive < MIN_KEEP_ALIVE {
+            let err = format!(
+                "The keep alive of {} seconds is less than {} seconds, using {} seconds",
+                keep_alive, MIN_KEEP_ALIVE, MIN_KEEP_ALIVE
+            );
+            error!("MQTT broker {}:{}: {}", hostname.clone(), port, err);
+            set_state(&e, MqttBrokerProperties::LAST_ERROR, json!(err));
+        }
+        let keep_alive = keep_alive.max(MIN_KEEP_ALIVE);
+        let clean_session = e
+            .as_bool(MqttBrokerProperties::CLEAN_SESSION.as_ref())
+            .unwrap_or(true);
+        let max_inflight = e
+            .as_u64(MqttBrokerProperties::MAX_INFLIGHT.as_ref())
+            .map(|max_inflight| u16::try_from(max_inflight).unwrap_or(u16::MAX))
+            .unwrap_or(100)
+            .max(1);
+        let max_packet_size = e
+            .as_u64(MqttBrokerProperties::MAX_PACKET_SIZE.as_ref())
+            .unwrap_or(DEFAULT_MAX_PACKET_SIZE) as usize;
+        let topic_alias_max = e
+            .as_u64(MqttBrokerProperties::TOPIC_ALIAS_MAX.as_ref())
+            .map(|topic_alias_max| u16::try_from(topic_alias_max).unwrap_or(u16::MAX))
+            .unwrap_or(10);
+        // A channel without capacity would block the event loop
+        let request_channel_capacity = e
+            .as_u64(MqttBrokerProperties::REQUEST_CHANNEL_CAPACITY.as_ref())
+            .unwrap_or(10)
+            .max(1) as usize;
+        // A persistent session delivers the messages which have been queued by the broker
+        let offline_queue_size = e
+            .as_u64(MqttBrokerProperties::OFFLINE_QUEUE_SIZE.as_ref())
+            .unwrap_or(100) as usize;
+        offline_queue
+            .lock()
+            .unwrap()
+            .set_capacity(offline_queue_size);
+        subscriptions
+            .lock()
+            .unwrap()
+            .set_undelivered_capacity(match clean_session {
+                true => 0,
+                false => offline_queue_size,
+            });
+        let username = e
+            .as_string(MqttBrokerProperties::USERNAME.as_ref())
+            .unwrap_or_default();
+        let credentials = match username.is_empty() {
+            true => None,
+            false => {
+                // Never log the password
+                let password = match read_password(&e, password) {
+                    Some(password) => password,
+                    None => return Err(BehaviourCreationError.into()),
+                };
+                debug!(
+                    "Authenticating at MQTT broker {}:{} as {}",
+                    hostname.clone(),
+                    port,
+                    username
+                );
+                Some((username, password))
+            }
+        };
+        let last_will = create_last_will(&e);
+        if let Some(last_will) = &last_will {
+            debug!(
+                "Last will of MQTT broker {}:{} on topic {}",
+                hostname.clone(),
+                port,
+                last_will.topic
+            );
+        }
+        let (broker_address, transport) = match create_transport(&e, hostname.as_str(), port) {
+            Some(transport) => transport,
+            None => return Err(BehaviourCreationError.into()),
+        };
+
+        let (mqtt_client, event_loop) = MqttClientOptions {
+            protocol_version,
+            client_id: mqtt_client_id,
+            broker_address,
+            port,
+            keep_alive: Duration::from_secs(keep_alive),
+            clean_session,
+            max_inflight,
+            max_packet_size,
+            request_channel_capacity,
+            topic_alias_max,
+            credentials,
+            last_will,
+            transport,
+        }
+        .create_client();
+
+        let shutdown = Arc::new(Notify::new());
+        let gave_up = Arc::new(AtomicBool::new(false));
+
+        // rumqttc requires a tokio runtime, so the event loop runs in its own thread
+        let mqtt_event_loop = MqttEventLoop {
+            entity: e.clone(),
+            event_loop,
+            client: mqtt_client.clone(),
+            subscriptions: subscriptions.clone(),
+            offline_queue: offline_queue.clone(),
+            reconnect_policy: MqttReconnectPolicy::new(&e),
+            shutdown: shutdown.clone(),
+            gave_up: gave_up.clone(),
+            hostname: hostname.clone(),
+            port,
+        };
+        let thread_name = format!("{}-{}", e.type_name.clone(), e.id.to_string());
+        let event_loop_thread = thread::Builder::new().name(thread_name).spawn(move || {
+            match runtime::Builder::new_current_thread().enable_all().build() {
+                Ok(runtime) => runtime.block_on(mqtt_event_loop.run()),
+                Err(err) => error!("Failed to create runtime for MQTT event loop: {}", err),
+            }
+        });
+        let event_loop_thread = match event_loop_thread {
+            Ok(event_loop_thread) => event_loop_thread,
+            Err(err) => {
+                error!(
+                    "Failed to start event loop for MQTT broker {}:{} : {}",
+                    hostname.clone(),
+                    port,
+                    err
+                );
+                return Err(BehaviourCreationError.into());
+            }
+        };
+
+        let event_loop_thread_id = event_loop_thread.thread().id();
+
+        Ok(MqttConnection {
+            hostname,
+            port,
+            client: mqtt_client,
+            subscriptions,
+            offline_queue,
+            shutdown,
+            gave_up,
+            event_loop_thread: Some(event_loop_thread),
+            event_loop_thread_id,
+        })
+    }
+
+    /// Returns true if the connection won't be established anymore, because the reconnect policy
+    /// gave up. Only changing the configuration of the broker connects again.
+    pub fn gave_up(&self) -> bool {
+        self.gave_up.load(Ordering::SeqCst)
+    }
+
+    /// Publishes the message or queues it while disconnected. Returns an error if the message has
+    /// been dropped.
+    pub fn publish(
+        &self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+        properties: MqttMessageProperties,
+    ) -> Result<(), String> {
+        // The offline queue would keep the message forever
+        if self.gave_up() {
+            return Err(format!(
+                "Dropped message to topic {}: Gave up to connect to MQTT broker {}:{}",
+                topic, self.hostname, self.port
+            ));
+        }
+        let request = MqttPublishRequest {
+            topic: topic.to_string(),
+            qos,
+            retain,
+            payload,
+            properties,
+        };
+        // Never blocks, because the event loop and the flows must not wait for the broker
+        self.offline_queue
+            .lock()
+            .unwrap()
+            .publish(request)
+            .map_err(|request| {
+                format!(
+                    "Dropped message to topic {}: Not connected and the offline queue is disabled",
+                    request.topic
+                )
+            })
+    }
+
+    /// Subscribes the topic unless another mqtt_subscribes relation already did. Returns the
+    /// messages of the topic which have been received before.
+    pub fn subscribe(&self, topic: &str, qos: QoS) -> Vec<Value> {
+        let undelivered = self.subscriptions.lock().unwrap().subscribe(topic, qos);
+        self.send_subscriptions();
+        undelivered
+    }
+
+    /// Unsubscribes the topic if no mqtt_subscribes relation uses it anymore.
+    pub fn unsubscribe(&self, topic: &str, qos: QoS) {
+        self.subscriptions.lock().unwrap().unsubscribe(topic, qos);
+        self.send_subscriptions();
+    }
+
+    /// Sends the pending SUBSCRIBE and UNSUBSCRIBE requests.
+    fn send_subscriptions(&self) {
+        // Blocking the event loop thread would dead lock because it drains the requests
+        if thread::current().id() == self.event_loop_thread_id {
+            self.subscriptions.lock().unwrap().flush();
+            return;
+        }
+        // The event loop needs the lock while the requests are sent
+        let (client, requests) = match self.subscriptions.lock().unwrap().take_pending() {
+            Some(pending) => pending,
+            None => return,
+        };
+        let mut failed = Vec::new();
+        for request in requests {
+            if let Err(err) = task::block_on(request.send(&client)) {
+                error!(
+                    "Failed to send request for topic {} to MQTT broker {}:{}: {:?}",
+                    request.topic,
+                    self.hostname.clone(),
+                    self.port,
+                    err
+                );
+                failed.push(request.topic);
+            }
+        }
+        if !failed.is_empty() {
+            self.subscriptions.lock().unwrap().restore_pending(failed);
+        }
+    }
+
+    /// Stops the event loop and waits until the DISCONNECT has been sent. Waiting ensures that
+    /// the state of a previous connection doesn't overwrite the state of the next connection.
+    pub fn disconnect(mut self) {
+        self.shutdown.notify_one();
+        if let Some(event_loop_thread) = self.event_loop_thread.take() {
+            // The event loop can't wait for itself
+            if thread::current().id() != self.event_loop_thread_id {
+                let _ = event_loop_thread.join();
+            }
+        }
+    }
+}
+
+/// Automatically stop the event loop on destruction
+impl Drop for MqttConnection {
+    fn drop(&mut self) {
+        self.shutdown.notify_one();
+    }
+}
+
+/// The event loop of a connection to a MQTT broker.
+struct MqttEventLoop {
+    entity: Arc<ReactiveEntityInstance>,
+
+    event_loop: MqttClientEventLoop,
+
+    client: MqttClient,
+
+    subscriptions: Arc<Mutex<MqttSubscriptions>>,
+
+    offline_queue: Arc<Mutex<MqttOfflineQueue>>,
+
+    reconnect_policy: MqttReconnectPolicy,
+
+    shutdown: Arc<Notify>,
+
+    gave_up: Arc<AtomicBool>,
+
+    hostname: String,
+
+    port: u16,
+}
+
+impl MqttEventLoop {
+    async fn run(mut self) {
+        let hostname = self.hostname.clone();
+        let port = self.port;
+        debug!("Connecting to MQTT broker {}:{}", hostname.clone(), port);
+        let entity = self.entity.clone();
+        set_state(&entity, MqttBrokerProperties::CONNECTED, json!(false));
+        let mut connected = false;
+        let mut reconnect_attempts: u64 = 0;
+
+        loop {
+            let result = tokio::select! {
+                _ = self.shutdown.notified() => break,
+                result = self.event_loop.poll() => result,
+            };
+            match result {
+                Err(err) => {
+                    connected = false;
+                    let err_message = err.to_string();
+                    set_state(&entity, MqttBrokerProperties::CONNECTED, json!(false));
+                    set_state(
+                        &entity,
+                        MqttBrokerProperties::LAST_ERROR,
+                        json!(err_message.clone()),
+                    );
+                    reconnect_attempts += 1;
+                    set_state(
+                        &entity,
+                        MqttBrokerProperties::RECONNECT_ATTEMPTS,
+                        json!(reconnect_attempts),
+                    );
+                    match err {
+                        MqttConnectionError::V4(ConnectionError::Io(err))
+                        | MqttConnectionError::V5(v5::ConnectionError::Io(err)) => {
+                            error!(
+                                "Failed to connect to MQTT broker {}:{} : {:?}",
+                                hostname.clone(),
+                                port,
+                                err
+                            );
+                        }
+                        err => {
+                            error!(
+                                "Connection error on MQTT broker {}:{} : {}",
+                                hostname.clone(),
+                                port,
+                                err
+                            );
+                        }
+                    }
+                    self.subscriptions.lock().unwrap().disconnected();
+                    self.offline_queue.lock().unwrap().disconnected();
+                    match self.reconnect_policy.delay(reconnect_attempts) {
+                        Some(delay) => {
+                            debug!(
+                                "Reconnecting to MQTT broker {}:{} in {} ms (attempt {})",
+                                hostname.clone(),
+                                port,
+                                delay.as_millis(),
+                                reconnect_attempts
+                            );
+                            set_state(
+                                &entity,
+                                MqttBrokerProperties::RECONNECT_DELAY,
+                                json!(delay.as_millis() as u64),
+                            );
+                            tokio::select! {
+                                _ = self.shutdown.notified() => break,
+                                _ = time::sleep(delay) => {}
+                            }
+                        }
+                        None => {
+                            error!(
+                                "Giving up to connect to MQTT broker {}:{} after {} attempts",
+                                hostname.clone(),
+                                port,
+                                reconnect_attempts - 1
+                            );
+                            self.gave_up.store(true, Ordering::SeqCst);
+                            set_state(&entity, MqttBrokerProperties::RECONNECT_DELAY, json!(0));
+                            set_state(
+                                &entity,
+                                MqttBrokerProperties::LAST_ERROR,
+                                json!(format!(
+                                    "Gave up to connect after {} attempts: {}",
+                                    reconnect_attempts - 1,
+                                    err_message
+                                )),
+                            );
+                            break;
+                        }
+                    }
+                }
+                Ok(MqttEvent::ConnAck) => {
+                    debug!("Connected to MQTT broker {}:{}", hostname.clone(), port);
+                    connected = true;
+                    reconnect_attempts = 0;
+                    set_state(
+                        &entity,
+                        MqttBrokerProperties::RECONNECT_ATTEMPTS,
+                        json!(reconnect_attempts),
+                    );
+                    set_state(&entity, MqttBrokerProperties::RECONNECT_DELAY, json!(0));
+                    set_state(
+                        &entity,
+                        MqttBrokerProperties::CONNECTED_SINCE,
+                        json!(unix_timestamp()),
+                    );
+                    set_state(&entity, MqttBrokerProperties::CONNECTED, json!(true));
+                    self.subscriptions
+                        .lock()
+                        .unwrap()
+                        .connected(self.client.clone());
+                    self.publish_birth_message();
+                    self.offline_queue
+                        .lock()
+                        .unwrap()
+                        .connected(self.client.clone());
+                }
+                Ok(MqttEvent::Publish {
+                    topic,
+                    payload,
+                    properties,
+                }) => {
+                    trace!("Topic: {}", topic);
+                    trace!(
+                        "Payload (RAW): {}",
+                        String::from_utf8_lossy(payload.as_ref())
+                    );
+                    // The payload is decoded by the subscribers according to their mode
+                    let value =
+                        create_received_package(topic.as_str(), payload.as_ref(), &properties);
+                    if !self
+                        .subscriptions
+                        .lock()
+                        .unwrap()
+                        .hold_undelivered(topic.as_str(), &value)
+                    {
+                        set_state(&entity, MqttBrokerProperties::RECEIVED_PACKAGE, value);
+                    }
+                }
+                Ok(MqttEvent::AckFailure(failure)) => {
+                    error!(
+                        "MQTT broker {}:{} rejected {}",
+                        hostname.clone(),
+                        port,
+                        failure
+                    );
+                    set_state(&entity, MqttBrokerProperties::LAST_ERROR, json!(failure));
+                }
+                Ok(MqttEvent::Other(event)) => {
+                    trace!("Event {}", event);
+                }
+            }
+            // Polling drained the request channel
+            if connected {
+                self.subscriptions.lock().unwrap().flush();
+                self.offline_queue.lock().unwrap().flush();
+            }
+        }
+        if connected {
+            // The DISCONNECT is sent by the next poll
+            let _ = self.client.try_disconnect();
+            let _ = time::timeout(Duration::from_secs(1), self.event_loop.poll()).await;
+        }
+        set_state(&entity, MqttBrokerProperties::CONNECTED, json!(false));
+        debug!(
+            "Disconnected client connection to MQTT broker {}:{}",
+            hostname.clone(),
+            port
+        );
+    }
+
+    /// Announces that the connection has been (re-)established.
+    fn publish_birth_message(&self) {
+        let topic = self
+            .entity
+            .as_string(MqttBrokerProperties::BIRTH_TOPIC.as_ref())
+            .unwrap_or_default();
+        if topic.is_empty() {
+            return;
+        }
+        let payload = self
+            .entity
+            .as_string(MqttBrokerProperties::BIRTH_PAYLOAD.as_ref())
+            .unwrap_or_default();
+        let qos = self
+            .entity
+            .as_u64(MqttBrokerProperties::BIRTH_QOS.as_ref())
+            .and_then(mqtt_qos)
+            .unwrap_or(QoS::AtMostOnce);
+        let retain = self
+            .entity
+            .as_bool(MqttBrokerProperties::BIRTH_RETAIN.as_ref())
+            .unwrap_or(false);
+        debug!("Publishing birth message to topic {}", topic);
+        // The event loop drains the requests, so it must not wait for them
+        if let Err(err) = self.client.try_publish(
+            topic.clone(),
+            qos,
+            retain,
+            payload.into_bytes(),
+            &MqttMessageProperties::default(),
+        ) {
+            error!(
+                "Failed to publish birth message to topic {}: {:?}",
+                topic, err
+            );
+        }
+    }
+}
+
+/// Takes the password out of the graph. The password property is cleared without propagating the
+/// change, so the password can't be read back.
+pub fn take_password(e: &ReactiveEntityInstance) -> String {
+    match e.properties.get(MqttBrokerProperties::PASSWORD.as_ref()) {
+        Some(property) => {
+            let password = property.as_string().unwrap_or_default();
+            if !password.is_empty() {
+                property.set_no_propagate(json!(""));
+            }
+            password
+        }
+        None => String::new(),
+    }
+}
+
+/// The password file takes precedence over the password which has been taken from the password
+/// property. Returns none if the password file couldn't be read.
+fn read_password(e: &ReactiveEntityInstance, password: &str) -> Option<String> {
+    let password_file = e
+        .as_string(MqttBrokerProperties::PASSWORD_FILE.as_ref())
+        .unwrap_or_default();
+    let password_file = password_file.trim();
+    if password_file.is_empty() {
+        return Some(password.to_string());
+    }
+    match std::fs::read_to_string(password_file) {
+        // Editors usually terminate the last line
+        Ok(password) => Some(password.trim_end_matches(&['\r', '\n'][..]).to_string()),
+        Err(err) => {
+            error!(
+                "Failed to read {} of mqtt broker {} from {}: {}",
+                MqttBrokerProperties::PASSWORD_FILE.as_ref(),
+                e.id,
+                password_file,
+                err
+            );
+            None
+        }
+    }
+}
+
+/// The broker publishes the last will if the connection is lost unexpectedly.
+fn create_last_will(e: &ReactiveEntityInstance) -> Option<MqttPublishRequest> {
+    let topic = e
+        .as_string(MqttBrokerProperties::WILL_TOPIC.as_ref())
+        .unwrap_or_default();
+    if topic.is_empty() {
+        return None;
+    }
+    let payload = e
+        .as_string(MqttBrokerProperties::WILL_PAYLOAD.as_ref())
+        .unwrap_or_default();
+    let qos = e
+        .as_u64(MqttBrokerProperties::WILL_QOS.as_ref())
+        .and_then(mqtt_qos)
+        .unwrap_or(QoS::AtMostOnce);
+    let retain = e
+        .as_bool(MqttBrokerProperties::WILL_RETAIN.as_ref())
+        .unwrap_or(false);
+    Some(MqttPublishRequest {
+        topic,
+        qos,
+        retain,
+        payload: payload.into_bytes(),
+        properties: MqttMessageProperties::default(),
+    })
+}
+
+/// Updates an output property of the broker. Entities created with an older type may not have it.
+fn set_state(entity: &ReactiveEntityInstance, property: MqttBrokerProperties, value: Value) {
+    if let Some(property) = entity.properties.get(property.as_ref()) {
+        property.set(value);
+    }
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
@@ -1,37 +1,226 @@
 use std::sync::Arc;
+use std::sync::Weak;
 
 use crate::di::*;
 use async_trait::async_trait;
+use indradb::EdgeKey;
 use log::debug;
+use log::error;
 use uuid::Uuid;
 
+use crate::behaviour::entity::homie_device::HomieDevice;
+use crate::behaviour::entity::importer::MqttPluginImportContext;
 use crate::behaviour::entity::mqtt_broker::MqttBroker;
+use crate::behaviour::entity::mqtt_ha_discovery::MqttHaDiscovery;
+use crate::behaviour::entity::mqtt_server::MqttServer;
+use crate::behaviour::entity::HomieDeviceProperties;
+use crate::behaviour::entity::MqttHaDiscoveryProperties;
+use crate::model::PropertyInstanceGetter;
 use crate::model::ReactiveEntityInstance;
+use crate::model::ReactiveRelationInstance;
+use crate::plugins::plugin_context::PluginContext;
+use crate::plugins::ComponentBehaviourProvider;
 use crate::plugins::EntityBehaviourProvider;
+use crate::plugins::RelationBehaviourProvider;
 
 const MQTT_BROKER: &'static str = "mqtt_broker";
 
+const MQTT_SERVER: &'static str = "mqtt_server";
+
+const MQTT_HA_DISCOVERY: &'static str = "mqtt_ha_discovery";
+
+const HOMIE_DEVICE: &'static str = "homie_device";
+
 #[wrapper]
 pub struct MqttBrokerStorage(
     std::sync::RwLock<std::collections::HashMap<Uuid, std::sync::Arc<MqttBroker>>>,
 );
 
+#[wrapper]
+pub struct MqttServerStorage(
+    std::sync::RwLock<std::collections::HashMap<Uuid, std::sync::Arc<MqttServer>>>,
+);
+
+#[wrapper]
+pub struct MqttHaDiscoveryStorage(
+    std::sync::RwLock<std::collections::HashMap<Uuid, std::sync::Arc<MqttHaDiscovery>>>,
+);
+
+#[wrapper]
+pub struct HomieDeviceStorage(
+    std::sync::RwLock<std::collections::HashMap<Uuid, std::sync::Arc<HomieDevice>>>,
+);
+
+/// The passwords which have been taken out of the graph, by the id of the mqtt_broker. The
+/// password is kept until the entity instance is removed, so a recreated behaviour still
+/// authenticates.
+#[wrapper]
+pub struct MqttBrokerPasswordStorage(
+    std::sync::RwLock<std::collections::HashMap<Uuid, std::sync::Arc<std::sync::Mutex<String>>>>,
+);
+
+/// The instance managers of the plugin context are required to import devices
+#[wrapper]
+pub struct MqttPluginContextStorage(std::sync::RwLock<Option<std::sync::Arc<dyn PluginContext>>>);
+
+/// The entity instances whose behaviours reference a mqtt_broker, by the id of the broker
+#[wrapper]
+pub struct MqttBrokerDependentsStorage(
+    std::sync::RwLock<
+        std::collections::HashMap<Uuid, Vec<(String, std::sync::Arc<ReactiveEntityInstance>)>>,
+    >,
+);
+
+/// The relation instances whose behaviours reference their mqtt_broker, by the id of the broker
+#[wrapper]
+pub struct MqttBrokerRelationDependentsStorage(
+    std::sync::RwLock<
+        std::collections::HashMap<Uuid, Vec<std::sync::Arc<ReactiveRelationInstance>>>,
+    >,
+);
+
+/// The component behaviours which reference a mqtt_broker are recreated with the broker
+#[wrapper]
+pub struct MqttComponentBehaviourProviderStorage(
+    std::sync::RwLock<Option<std::sync::Weak<dyn ComponentBehaviourProvider>>>,
+);
+
+/// The relation behaviours which reference a mqtt_broker are recreated with the broker
+#[wrapper]
+pub struct MqttRelationBehaviourProviderStorage(
+    std::sync::RwLock<Option<std::sync::Weak<dyn RelationBehaviourProvider>>>,
+);
+
 #[provides]
 fn create_mqtt_brokers_storage() -> MqttBrokerStorage {
     MqttBrokerStorage(std::sync::RwLock::new(std::collections::HashMap::new()))
 }
 
+#[provides]
+fn create_mqtt_servers_storage() -> MqttServerStorage {
+    MqttServerStorage(std::sync::RwLock::new(std::collections::HashMap::new()))
+}
+
+#[provides]
+fn create_mqtt_ha_discoveries_storage() -> MqttHaDiscoveryStorage {
+    MqttHaDiscoveryStorage(std::sync::RwLock::new(std::collections::HashMap::new()))
+}
+
+#[provides]
+fn create_homie_devices_storage() -> HomieDeviceStorage {
+    HomieDeviceStorage(std::sync::RwLock::new(std::collections::HashMap::new()))
+}
+
+#[provides]
+fn create_mqtt_broker_passwords_storage() -> MqttBrokerPasswordStorage {
+    MqttBrokerPasswordStorage(std::sync::RwLock::new(std::collections::HashMap::new()))
+}
+
+#[provides]
+fn create_mqtt_plugin_context_storage() -> MqttPluginContextStorage {
+    MqttPluginContextStorage(std::sync::RwLock::new(None))
+}
+
+#[provides]
+fn create_mqtt_broker_dependents_storage() -> MqttBrokerDependentsStorage {
+    MqttBrokerDependentsStorage(std::sync::RwLock::new(std::collections::HashMap::new()))
+}
+
+#[provides]
+fn create_mqtt_broker_relation_dependents_storage() -> MqttBrokerRelationDependentsStorage {
+    MqttBrokerRelationDependentsStorage(std::sync::RwLock::new(std::collections::HashMap::new()))
+}
+
+#[provides]
+fn create_mqtt_component_behaviour_provider_storage() -> MqttComponentBehaviourProviderStorage {
+    MqttComponentBehaviourProviderStorage(std::sync::RwLock::new(None))
+}
+
+#[provides]
+fn create_mqtt_relation_behaviour_provider_storage() -> MqttRelationBehaviourProviderStorage {
+    MqttRelationBehaviourProviderStorage(std::sync::RwLock::new(None))
+}
+
 #[async_trait]
 pub trait MqttEntityBehaviourProvider: EntityBehaviourProvider + Send + Sync {
     fn create_broker(&self, entity_instance: Arc<ReactiveEntityInstance>);
 
     fn remove_broker(&self, entity_instance: Arc<ReactiveEntityInstance>);
 
+    fn create_server(&self, entity_instance: Arc<ReactiveEntityInstance>);
+
+    fn remove_server(&self, entity_instance: Arc<ReactiveEntityInstance>);
+
+    fn create_ha_discovery(&self, entity_instance: Arc<ReactiveEntityInstance>);
+
+    fn remove_ha_discovery(&self, entity_instance: Arc<ReactiveEntityInstance>);
+
+    fn create_homie_device(&self, entity_instance: Arc<ReactiveEntityInstance>);
+
+    fn remove_homie_device(&self, entity_instance: Arc<ReactiveEntityInstance>);
+
     fn remove_by_id(&self, id: Uuid);
+
+    fn get_broker(&self, id: Uuid) -> Option<Arc<MqttBroker>>;
+
+    /// Returns the broker which is referenced by the id in the given property. The behaviour of
+    /// the entity instance is recreated whenever a behaviour for this broker is created, so it
+    /// doesn't matter if the broker behaviour doesn't exist yet or is replaced later.
+    fn get_linked_broker(
+        &self,
+        entity_instance: Arc<ReactiveEntityInstance>,
+        property: &str,
+        behaviour: &str,
+    ) -> Option<Arc<MqttBroker>>;
+
+    /// Stops recreating the behaviour of the entity instance.
+    fn unlink_broker(&self, id: Uuid, behaviour: &str);
+
+    /// Returns the outbound broker of the given relation instance. Like get_linked_broker, the
+    /// behaviour of the relation instance is recreated whenever a behaviour for the broker is
+    /// created.
+    fn get_relation_broker(
+        &self,
+        relation_instance: Arc<ReactiveRelationInstance>,
+        behaviour: &str,
+    ) -> Option<Arc<MqttBroker>>;
+
+    /// Stops recreating the behaviour of the relation instance.
+    fn unlink_relation(&self, edge_key: &EdgeKey);
+
+    fn set_context(&self, context: Arc<dyn PluginContext>);
+
+    fn set_component_behaviour_provider(
+        &self,
+        component_behaviour_provider: Weak<dyn ComponentBehaviourProvider>,
+    );
+
+    fn set_relation_behaviour_provider(
+        &self,
+        relation_behaviour_provider: Weak<dyn RelationBehaviourProvider>,
+    );
 }
 
 pub struct MqttEntityBehaviourProviderImpl {
     mqtt_brokers: MqttBrokerStorage,
+
+    mqtt_servers: MqttServerStorage,
+
+    mqtt_ha_discoveries: MqttHaDiscoveryStorage,
+
+    homie_devices: HomieDeviceStorage,
+
+    mqtt_broker_passwords: MqttBrokerPasswordStorage,
+
+    context: MqttPluginContextStorage,
+
+    broker_dependents: MqttBrokerDependentsStorage,
+
+    broker_relation_dependents: MqttBrokerRelationDependentsStorage,
+
+    component_behaviour_provider: MqttComponentBehaviourProviderStorage,
+
+    relation_behaviour_provider: MqttRelationBehaviourProviderStorage,
 }
 
 interfaces!(MqttEntityBehaviourProviderImpl: dyn EntityBehaviourProvider);
@@ -42,6 +231,15 @@ impl MqttEntityBehaviourProviderImpl {
     fn new() -> Self {
         Self {
             mqtt_brokers: create_mqtt_brokers_storage(),
+            mqtt_servers: create_mqtt_servers_storage(),
+            mqtt_ha_discoveries: create_mqtt_ha_discoveries_storage(),
+            homie_devices: create_homie_devices_storage(),
+            mqtt_broker_passwords: create_mqtt_broker_passwords_storage(),
+            context: create_mqtt_plugin_context_storage(),
+            broker_dependents: create_mqtt_broker_dependents_storage(),
+            broker_relation_dependents: create_mqtt_broker_relation_dependents_storage(),
+            component_behaviour_provider: create_mqtt_component_behaviour_provider_storage(),
+            relation_behaviour_provider: create_mqtt_relation_behaviour_provider_storage(),
         }
     }
 }
@@ -51,12 +249,100 @@ impl MqttEntityBehaviourProviderImpl {
 impl MqttEntityBehaviourProvider for MqttEntityBehaviourProviderImpl {
     fn create_broker(&self, entity_instance: Arc<ReactiveEntityInstance>) {
         let id = entity_instance.id;
-        let broker = MqttBroker::new(entity_instance.clone());
+        // The previous behaviour has to close its connection first, the broker may reject a
+        // second connection with the same client id
+        self.mqtt_brokers.0.write().unwrap().remove(&id);
+        let password = self
+            .mqtt_broker_passwords
+            .0
+            .write()
+            .unwrap()
+            .entry(id)
+            .or_default()
+            .clone();
+        let broker = MqttBroker::new(entity_instance.clone(), password);
         if broker.is_ok() {
             let broker = Arc::new(broker.unwrap());
-            self.mqtt_brokers.0.write().unwrap().insert(id, broker);
+            self.mqtt_brokers
+                .0
+                .write()
+                .unwrap()
+                .insert(id, broker.clone());
             entity_instance.add_behaviour(MQTT_BROKER);
             debug!("Added behaviour {} to entity instance {}", MQTT_BROKER, id);
+            // The dependent behaviours wait for the broker or reference a previous behaviour
+            let dependents = self
+                .broker_dependents
+                .0
+                .read()
+                .unwrap()
+                .get(&id)
+                .cloned()
+                .unwrap_or_default();
+            for (behaviour, dependent) in dependents {
+                match behaviour.as_str() {
+                    MQTT_HA_DISCOVERY => {
+                        // The imported instances are kept
+                        let ha_discovery = self
+                            .mqtt_ha_discoveries
+                            .0
+                            .read()
+                            .unwrap()
+                            .get(&dependent.id)
+                            .cloned();
+                        match ha_discovery {
+                            Some(ha_discovery) => ha_discovery.relink(broker.clone()),
+                            None => self.create_ha_discovery(dependent),
+                        }
+                    }
+                    HOMIE_DEVICE => {
+                        // The materialized nodes are kept
+                        let homie_device = self
+                            .homie_devices
+                            .0
+                            .read()
+                            .unwrap()
+                            .get(&dependent.id)
+                            .cloned();
+                        match homie_device {
+                            Some(homie_device) => homie_device.relink(broker.clone()),
+                            None => self.create_homie_device(dependent),
+                        }
+                    }
+                    _ => {
+                        let component_behaviour_provider = self
+                            .component_behaviour_provider
+                            .0
+                            .read()
+                            .unwrap()
+                            .as_ref()
+                            .and_then(Weak::upgrade);
+                        if let Some(component_behaviour_provider) = component_behaviour_provider {
+                            component_behaviour_provider.add_behaviours_to_entity(dependent);
+                        }
+                    }
+                }
+            }
+            let relation_dependents = self
+                .broker_relation_dependents
+                .0
+                .read()
+                .unwrap()
+                .get(&id)
+                .cloned()
+                .unwrap_or_default();
+            let relation_behaviour_provider = self
+                .relation_behaviour_provider
+                .0
+                .read()
+                .unwrap()
+                .as_ref()
+                .and_then(Weak::upgrade);
+            if let Some(relation_behaviour_provider) = relation_behaviour_provider {
+                for dependent in relation_dependents {
+                    relation_behaviour_provider.add_behaviours(dependent);
+                }
+            }
         }
     }
 
@@ -73,7 +359,136 @@ impl MqttEntityBehaviourProvider for MqttEntityBehaviourProviderImpl {
         );
     }
 
+    fn create_server(&self, entity_instance: Arc<ReactiveEntityInstance>) {
+        let id = entity_instance.id;
+        // The previous behaviour has to release the listen address first
+        self.mqtt_servers.0.write().unwrap().remove(&id);
+        let server = MqttServer::new(entity_instance.clone());
+        if server.is_ok() {
+            let server = Arc::new(server.unwrap());
+            self.mqtt_servers.0.write().unwrap().insert(id, server);
+            entity_instance.add_behaviour(MQTT_SERVER);
+            debug!("Added behaviour {} to entity instance {}", MQTT_SERVER, id);
+        }
+    }
+
+    fn remove_server(&self, entity_instance: Arc<ReactiveEntityInstance>) {
+        self.mqtt_servers
+            .0
+            .write()
+            .unwrap()
+            .remove(&entity_instance.id);
+        entity_instance.remove_behaviour(MQTT_SERVER);
+        debug!(
+            "Removed behaviour {} from entity instance {}",
+            MQTT_SERVER, entity_instance.id
+        );
+    }
+
+    fn create_ha_discovery(&self, entity_instance: Arc<ReactiveEntityInstance>) {
+        let id = entity_instance.id;
+        let context = self.context.0.read().unwrap().clone();
+        if context.is_none() {
+            error!(
+                "Can't add behaviour {} to entity instance {}: No plugin context",
+                MQTT_HA_DISCOVERY, id
+            );
+            return;
+        }
+        let broker = match self.get_linked_broker(
+            entity_instance.clone(),
+            MqttHaDiscoveryProperties::BROKER.as_ref(),
+            MQTT_HA_DISCOVERY,
+        ) {
+            Some(broker) => broker,
+            None => return,
+        };
+        // The previous behaviour removes its observer and its imported instances first
+        self.mqtt_ha_discoveries.0.write().unwrap().remove(&id);
+        let context = Arc::new(MqttPluginImportContext(context.unwrap()));
+        let ha_discovery = MqttHaDiscovery::new(entity_instance.clone(), broker, context);
+        if ha_discovery.is_ok() {
+            let ha_discovery = Arc::new(ha_discovery.unwrap());
+            self.mqtt_ha_discoveries
+                .0
+                .write()
+                .unwrap()
+                .insert(id, ha_discovery);
+            entity_instance.add_behaviour(MQTT_HA_DISCOVERY);
+            debug!(
+                "Added behaviour {} to entity instance {}",
+                MQTT_HA_DISCOVERY, id
+            );
+        }
+    }
+
+    fn remove_ha_discovery(&self, entity_instance: Arc<ReactiveEntityInstance>) {
+        self.unlink_broker(entity_instance.id, MQTT_HA_DISCOVERY);
+        self.mqtt_ha_discoveries
+            .0
+            .write()
+            .unwrap()
+            .remove(&entity_instance.id);
+        entity_instance.remove_behaviour(MQTT_HA_DISCOVERY);
+        debug!(
+            "Removed behaviour {} from entity instance {}",
+            MQTT_HA_DISCOVERY, entity_instance.id
+        );
+    }
+
+    fn create_homie_device(&self, entity_instance: Arc<ReactiveEntityInstance>) {
+        let id = entity_instance.id;
+        let context = self.context.0.read().unwrap().clone();
+        if context.is_none() {
+            error!(
+                "Can't add behaviour {} to entity instance {}: No plugin context",
+                HOMIE_DEVICE, id
+            );
+            return;
+        }
+        let broker = match self.get_linked_broker(
+            entity_instance.clone(),
+            HomieDeviceProperties::BROKER.as_ref(),
+            HOMIE_DEVICE,
+        ) {
+            Some(broker) => broker,
+            None => return,
+        };
+        // The previous behaviour removes its observer and its materialized nodes first
+        self.homie_devices.0.write().unwrap().remove(&id);
+        let context = Arc::new(MqttPluginImportContext(context.unwrap()));
+        let homie_device = HomieDevice::new(entity_instance.clone(), broker, context);
+        if homie_device.is_ok() {
+            let homie_device = Arc::new(homie_device.unwrap());
+            self.homie_devices
+                .0
+                .write()
+                .unwrap()
+                .insert(id, homie_device);
+            entity_instance.add_behaviour(HOMIE_DEVICE);
+            debug!("Added behaviour {} to entity instance {}", HOMIE_DEVICE, id);
+        }
+    }
+
+    fn remove_homie_device(&self, entity_instance: Arc<ReactiveEntityInstance>) {
+        self.unlink_broker(entity_instance.id, HOMIE_DEVICE);
+        self.homie_devices
+            .0
+            .write()
+            .unwrap()
+            .remove(&entity_instance.id);
+        entity_instance.remove_behaviour(HOMIE_DEVICE);
+        debug!(
+            "Removed behaviour {} from entity instance {}",
+            HOMIE_DEVICE, entity_instance.id
+        );
+    }
+
     fn remove_by_id(&self, id: Uuid) {
+        self.unlink_broker(id, MQTT_HA_DISCOVERY);
+        self.unlink_broker(id, HOMIE_DEVICE);
+        // The entity instance has been removed, so its password isn't needed anymore
+        self.mqtt_broker_passwords.0.write().unwrap().remove(&id);
         if self.mqtt_brokers.0.write().unwrap().contains_key(&id) {
             self.mqtt_brokers.0.write().unwrap().remove(&id);
             debug!(
@@ -81,6 +496,146 @@ impl MqttEntityBehaviourProvider for MqttEntityBehaviourProviderImpl {
                 MQTT_BROKER, id
             );
         }
+        if self.mqtt_servers.0.write().unwrap().contains_key(&id) {
+            self.mqtt_servers.0.write().unwrap().remove(&id);
+            debug!(
+                "Removed behaviour {} from entity instance {}",
+                MQTT_SERVER, id
+            );
+        }
+        if self
+            .mqtt_ha_discoveries
+            .0
+            .write()
+            .unwrap()
+            .contains_key(&id)
+        {
+            self.mqtt_ha_discoveries.0.write().unwrap().remove(&id);
+            debug!(
+                "Removed behaviour {} from entity instance {}",
+                MQTT_HA_DISCOVERY, id
+            );
+        }
+        if self.homie_devices.0.write().unwrap().contains_key(&id) {
+            self.homie_devices.0.write().unwrap().remove(&id);
+            debug!(
+                "Removed behaviour {} from entity instance {}",
+                HOMIE_DEVICE, id
+            );
+        }
+    }
+
+    fn get_broker(&self, id: Uuid) -> Option<Arc<MqttBroker>> {
+        self.mqtt_brokers.0.read().unwrap().get(&id).cloned()
+    }
+
+    fn get_linked_broker(
+        &self,
+        entity_instance: Arc<ReactiveEntityInstance>,
+        property: &str,
+        behaviour: &str,
+    ) -> Option<Arc<MqttBroker>> {
+        // The broker is referenced by the id of its entity instance
+        let broker_id = entity_instance.as_string(property).unwrap_or_default();
+        let broker_id = match Uuid::parse_str(broker_id.as_str()) {
+            Ok(broker_id) => broker_id,
+            Err(_) => {
+                error!(
+                    "Can't add behaviour {} to entity instance {}: Invalid mqtt broker id {}",
+                    behaviour, entity_instance.id, broker_id
+                );
+                return None;
+            }
+        };
+        {
+            let mut broker_dependents = self.broker_dependents.0.write().unwrap();
+            let dependents = broker_dependents.entry(broker_id).or_insert_with(Vec::new);
+            if !dependents
+                .iter()
+                .any(|(name, dependent)| name == behaviour && dependent.id == entity_instance.id)
+            {
+                dependents.push((behaviour.to_string(), entity_instance.clone()));
+            }
+        }
+        let broker = self.get_broker(broker_id);
+        if broker.is_none() {
+            debug!(
+                "Behaviour {} of entity instance {} waits for the behaviour of mqtt broker {}",
+                behaviour, entity_instance.id, broker_id
+            );
+        }
+        broker
+    }
+
+    fn unlink_broker(&self, id: Uuid, behaviour: &str) {
+        let mut broker_dependents = self.broker_dependents.0.write().unwrap();
+        for dependents in broker_dependents.values_mut() {
+            dependents.retain(|(name, dependent)| name != behaviour || dependent.id != id);
+        }
+        broker_dependents.retain(|_, dependents| !dependents.is_empty());
+    }
+
+    fn get_relation_broker(
+        &self,
+        relation_instance: Arc<ReactiveRelationInstance>,
+        behaviour: &str,
+    ) -> Option<Arc<MqttBroker>> {
+        let broker_id = relation_instance.outbound.id;
+        let edge_key = relation_instance.get_key();
+        {
+            let mut broker_relation_dependents = self.broker_relation_dependents.0.write().unwrap();
+            let dependents = broker_relation_dependents
+                .entry(broker_id)
+                .or_insert_with(Vec::new);
+            if !dependents
+                .iter()
+                .any(|dependent| dependent.get_key() == edge_key)
+            {
+                dependents.push(relation_instance.clone());
+            }
+        }
+        let broker = self.get_broker(broker_id);
+        if broker.is_none() {
+            debug!(
+                "Behaviour {} of relation instance {:?} waits for the behaviour of mqtt broker {}",
+                behaviour, edge_key, broker_id
+            );
+        }
+        broker
+    }
+
+    fn unlink_relation(&self, edge_key: &EdgeKey) {
+        let mut broker_relation_dependents = self.broker_relation_dependents.0.write().unwrap();
+        for dependents in broker_relation_dependents.values_mut() {
+            dependents.retain(|dependent| dependent.get_key().as_ref() != Some(edge_key));
+        }
+        broker_relation_dependents.retain(|_, dependents| !dependents.is_empty());
+    }
+
+    fn set_context(&self, context: Arc<dyn PluginContext>) {
+        self.context.0.write().unwrap().replace(context);
+    }
+
+    fn set_component_behaviour_provider(
+        &self,
+        component_behaviour_provider: Weak<dyn ComponentBehaviourProvider>,
+    ) {
+        self.component_behaviour_provider
+            .0
+            .write()
+            .unwrap()
+            .replace(component_behaviour_provider);
+    }
+
+    fn set_relation_behaviour_provider(
+        &self,
+        relation_behaviour_provider: Weak<dyn RelationBehaviourProvider>,
+    ) {
+        self.relation_behaviour_provider
+            .0
+            .write()
+            .unwrap()
+            .replace(relation_behaviour_provider);
     }
 }
 
@@ -88,6 +643,9 @@ impl EntityBehaviourProvider for MqttEntityBehaviourProviderImpl {
     fn add_behaviours(&self, entity_instance: Arc<ReactiveEntityInstance>) {
         match entity_instance.clone().type_name.as_str() {
             MQTT_BROKER => self.create_broker(entity_instance),
+            MQTT_SERVER => self.create_server(entity_instance),
+            MQTT_HA_DISCOVERY => self.create_ha_discovery(entity_instance),
+            HOMIE_DEVICE => self.create_homie_device(entity_instance),
             _ => {}
         }
     }
@@ -95,6 +653,9 @@ impl EntityBehaviourProvider for MqttEntityBehaviourProviderImpl {
     fn remove_behaviours(&self, entity_instance: Arc<ReactiveEntityInstance>) {
         match entity_instance.clone().type_name.as_str() {
             MQTT_BROKER => self.remove_broker(entity_instance),
+            MQTT_SERVER => self.remove_server(entity_instance),
+            MQTT_HA_DISCOVERY => self.remove_ha_discovery(entity_instance),
+            HOMIE_DEVICE => self.remove_homie_device(entity_instance),
             _ => {}
         }
     }
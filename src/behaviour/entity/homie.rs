@@ -0,0 +1,183 @@
+use serde_json::json;
+use serde_json::Value;
+
+/// The datatypes of properties of the Homie convention 4.0.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum HomieDatatype {
+    Integer,
+    Float,
+    Boolean,
+    String,
+    Enum,
+    Color,
+    Datetime,
+    Duration,
+}
+
+impl HomieDatatype {
+    pub fn parse(datatype: &str) -> Option<Self> {
+        match datatype {
+            "integer" => Some(HomieDatatype::Integer),
+            "float" => Some(HomieDatatype::Float),
+            "boolean" => Some(HomieDatatype::Boolean),
+            "string" => Some(HomieDatatype::String),
+            "enum" => Some(HomieDatatype::Enum),
+            "color" => Some(HomieDatatype::Color),
+            "datetime" => Some(HomieDatatype::Datetime),
+            "duration" => Some(HomieDatatype::Duration),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HomieDatatype::Integer => "integer",
+            HomieDatatype::Float => "float",
+            HomieDatatype::Boolean => "boolean",
+            HomieDatatype::String => "string",
+            HomieDatatype::Enum => "enum",
+            HomieDatatype::Color => "color",
+            HomieDatatype::Datetime => "datetime",
+            HomieDatatype::Duration => "duration",
+        }
+    }
+}
+
+/// The attributes of a property of a Homie node.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HomieProperty {
+    pub name: String,
+
+    /// The property can't be materialized until the datatype is known
+    pub datatype: Option<HomieDatatype>,
+
+    /// The range `from:to` of numbers, the values of enums or the color format
+    pub format: String,
+
+    /// Settable properties accept values on the topic `.../set`
+    pub settable: bool,
+
+    pub unit: String,
+}
+
+impl HomieProperty {
+    /// Sets an attribute like `$datatype`. Returns false if the attribute isn't known.
+    pub fn set_attribute(&mut self, attribute: &str, value: &str) -> bool {
+        match attribute {
+            "$name" => self.name = value.to_string(),
+            "$datatype" => self.datatype = HomieDatatype::parse(value),
+            "$format" => self.format = value.to_string(),
+            "$settable" => self.settable = value == "true",
+            "$unit" => self.unit = value.to_string(),
+            _ => return false,
+        }
+        true
+    }
+
+    /// The value of a property which hasn't been published yet.
+    pub fn default_value(&self) -> Value {
+        match self.datatype {
+            Some(HomieDatatype::Integer) => json!(0),
+            Some(HomieDatatype::Float) => json!(0.0),
+            Some(HomieDatatype::Boolean) => json!(false),
+            _ => json!(""),
+        }
+    }
+
+    /// Converts the payload of the property topic into a typed value.
+    pub fn decode(&self, payload: &str) -> Result<Value, String> {
+        let value = match self.datatype {
+            Some(HomieDatatype::Integer) => payload
+                .parse::<i64>()
+                .map(|value| json!(value))
+                .map_err(|err| err.to_string())?,
+            Some(HomieDatatype::Float) => payload
+                .parse::<f64>()
+                .map(|value| json!(value))
+                .map_err(|err| err.to_string())?,
+            Some(HomieDatatype::Boolean) => match payload {
+                "true" => json!(true),
+                "false" => json!(false),
+                _ => return Err(format!("{} is not a boolean", payload)),
+            },
+            _ => json!(payload),
+        };
+        self.validate(&value)?;
+        Ok(value)
+    }
+
+    /// Converts a value into the payload of the topic `.../set`.
+    pub fn encode(&self, value: &Value) -> Result<String, String> {
+        self.validate(value)?;
+        match (self.datatype, value) {
+            (Some(HomieDatatype::Integer), value) => value
+                .as_i64()
+                .map(|value| value.to_string())
+                .ok_or(format!("{} is not an integer", value)),
+            (Some(HomieDatatype::Float), value) => value
+                .as_f64()
+                .map(|value| value.to_string())
+                .ok_or(format!("{} is not a float", value)),
+            (Some(HomieDatatype::Boolean), value) => value
+                .as_bool()
+                .map(|value| value.to_string())
+                .ok_or(format!("{} is not a boolean", value)),
+            (_, Value::String(value)) => Ok(value.clone()),
+            (_, value) => Ok(value.to_string()),
+        }
+    }
+
+    /// Checks the range of numbers and the values of enums against the format.
+    fn validate(&self, value: &Value) -> Result<(), String> {
+        if self.format.is_empty() {
+            return Ok(());
+        }
+        match self.datatype {
+            Some(HomieDatatype::Integer) | Some(HomieDatatype::Float) => {
+                let (from, to) = match self.format.split_once(':') {
+                    Some(range) => range,
+                    None => return Ok(()),
+                };
+                let number = match value.as_f64() {
+                    Some(number) => number,
+                    None => return Ok(()),
+                };
+                let below = matches!(from.parse::<f64>(), Ok(from) if number < from);
+                let above = matches!(to.parse::<f64>(), Ok(to) if number > to);
+                match below || above {
+                    true => Err(format!("{} is not in the range {}", number, self.format)),
+                    false => Ok(()),
+                }
+            }
+            Some(HomieDatatype::Enum) => {
+                let value = value.as_str().unwrap_or_default();
+                match self.format.split(',').any(|allowed| allowed == value) {
+                    true => Ok(()),
+                    false => Err(format!("{} is not one of {}", value, self.format)),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The attributes as JSON, so they are available in the graph.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "datatype": self.datatype.map(|datatype| datatype.as_str()).unwrap_or_default(),
+            "format": self.format,
+            "settable": self.settable,
+            "unit": self.unit
+        })
+    }
+}
+
+/// Splits a comma separated list like `$nodes` or `$properties`.
+pub fn homie_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|id| id.trim())
+        .filter(|id| !id.is_empty())
+        .map(|id| id.to_string())
+        .collect()
+}
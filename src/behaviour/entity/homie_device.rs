@@ -0,0 +1,533 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::convert::AsRef;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Weak;
+
+use log::debug;
+use log::error;
+use rumqttc::QoS;
+use serde_json::json;
+use serde_json::Map;
+use serde_json::Value;
+
+use crate::behaviour::components::is_valid_topic_filter;
+use crate::behaviour::components::MqttEndpointProperties;
+use crate::behaviour::components::MqttPayloadMode;
+use crate::behaviour::components::MqttTopicProperties;
+use crate::behaviour::entity::homie::homie_list;
+use crate::behaviour::entity::homie::HomieProperty;
+use crate::behaviour::entity::importer::MqttImport;
+use crate::behaviour::entity::importer::MqttImportContext;
+use crate::behaviour::entity::importer::MqttImporter;
+use crate::behaviour::entity::importer::LABEL;
+use crate::behaviour::entity::mqtt_broker::MqttBroker;
+use crate::behaviour::entity::HomieDeviceProperties;
+use crate::behaviour::entity::HomieNodeProperties;
+use crate::behaviour::entity::MqttBrokerProperties;
+use crate::builder::EntityInstanceBuilder;
+use crate::model::PropertyInstanceGetter;
+use crate::model::ReactiveEntityInstance;
+use crate::reactive::entity::Disconnectable;
+use crate::reactive::BehaviourCreationError;
+
+/// The entity type of the materialized nodes.
+const HOMIE_NODE: &str = "homie_node";
+
+/// A message which is received below the device topic.
+struct HomieMessage {
+    /// The topic levels below the device
+    levels: Vec<String>,
+
+    payload: String,
+}
+
+/// A device following the Homie convention 4.0.
+///
+/// The attributes of the device, its nodes and their properties are discovered below
+/// `base_topic/device_id`. Each node is materialized as a homie_node entity which has a property
+/// for each Homie property. The values are converted according to `$datatype` and `$format`.
+/// Writing into a settable property publishes the value to `.../set`.
+pub struct HomieDevice {
+    pub entity: Arc<ReactiveEntityInstance>,
+
+    pub handle_id: u128,
+
+    /// Receives the topic filter of the device
+    importer: MqttImporter<HomieMessage>,
+}
+
+impl HomieDevice {
+    pub fn new<'a>(
+        e: Arc<ReactiveEntityInstance>,
+        broker: Arc<MqttBroker>,
+        context: Arc<dyn MqttImportContext>,
+    ) -> Result<HomieDevice, BehaviourCreationError> {
+        let state = e.properties.get(HomieDeviceProperties::STATE.as_ref());
+        if state.is_none() {
+            return Err(BehaviourCreationError.into());
+        }
+        let handle_id = state.unwrap().id.as_u128();
+
+        let base_topic = e
+            .as_string(HomieDeviceProperties::BASE_TOPIC.as_ref())
+            .unwrap_or_else(|| {
+                HomieDeviceProperties::BASE_TOPIC
+                    .default_value()
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string()
+            });
+        let device_id = e
+            .as_string(HomieDeviceProperties::DEVICE_ID.as_ref())
+            .unwrap_or_default();
+        let device_topic = format!("{}/{}", base_topic, device_id);
+        let topic = format!("{}/#", device_topic);
+        if device_id.is_empty() || !is_valid_topic_filter(topic.as_str()) {
+            error!("Invalid device id {} of Homie device {}", device_id, e.id);
+            return Err(BehaviourCreationError.into());
+        }
+
+        let device = HomieDeviceImport {
+            entity: e.clone(),
+            device_id,
+            device_topic: device_topic.clone(),
+            broker: Arc::downgrade(&broker.entity),
+            context,
+            model: HomieDeviceModel::default(),
+            nodes: HashMap::new(),
+            receiving: Arc::new(Mutex::new(HashSet::new())),
+        };
+        let prefix = format!("{}/", device_topic);
+        let importer = MqttImporter::start(
+            &e,
+            handle_id,
+            broker,
+            topic,
+            device,
+            move |topic, payload| {
+                topic
+                    .strip_prefix(prefix.as_str())
+                    .map(|levels| HomieMessage {
+                        levels: levels.split('/').map(String::from).collect(),
+                        payload: String::from_utf8_lossy(payload.as_slice()).to_string(),
+                    })
+            },
+        )?;
+
+        Ok(HomieDevice {
+            entity: e.clone(),
+            handle_id,
+            importer,
+        })
+    }
+
+    /// Continues the import with the next behaviour of the broker. The materialized nodes are
+    /// kept.
+    pub fn relink(&self, broker: Arc<MqttBroker>) {
+        debug!("Relinking homie_device {}", self.handle_id);
+        self.importer.relink(broker);
+    }
+
+    pub fn type_name(&self) -> String {
+        self.entity.type_name.clone()
+    }
+}
+
+/// The attributes of a node which have been received so far.
+#[derive(Clone, Debug, Default)]
+struct HomieNodeModel {
+    name: String,
+
+    node_type: String,
+
+    /// The ids of the properties of `$properties`
+    properties: Option<Vec<String>>,
+
+    attributes: HashMap<String, HomieProperty>,
+
+    /// The payloads of the property topics
+    values: HashMap<String, String>,
+}
+
+impl HomieNodeModel {
+    /// Returns the description of the node as soon as the datatypes of all properties are known.
+    fn description(&self) -> Option<HomieNodeDescription> {
+        let mut properties = BTreeMap::new();
+        for property_id in self.properties.as_ref()? {
+            let property = self.attributes.get(property_id)?;
+            if property.datatype.is_none() {
+                return None;
+            }
+            properties.insert(property_id.clone(), property.clone());
+        }
+        Some(HomieNodeDescription {
+            name: self.name.clone(),
+            node_type: self.node_type.clone(),
+            properties,
+        })
+    }
+}
+
+/// The structure of a materialized node. The node is materialized again if it changes.
+#[derive(Clone, Debug, PartialEq)]
+struct HomieNodeDescription {
+    name: String,
+
+    node_type: String,
+
+    properties: BTreeMap<String, HomieProperty>,
+}
+
+#[derive(Debug, Default)]
+struct HomieDeviceModel {
+    /// The ids of the nodes of `$nodes`
+    nodes: Vec<String>,
+
+    node_models: HashMap<String, HomieNodeModel>,
+}
+
+/// A node which has been materialized as entity.
+struct HomieNodeInstance {
+    description: HomieNodeDescription,
+
+    entity: Arc<ReactiveEntityInstance>,
+
+    /// The observers of the settable properties
+    handles: Vec<(String, u128)>,
+}
+
+struct HomieDeviceImport {
+    entity: Arc<ReactiveEntityInstance>,
+
+    device_id: String,
+
+    device_topic: String,
+
+    /// The observers of the settable properties outlive a removed broker
+    broker: Weak<ReactiveEntityInstance>,
+
+    context: Arc<dyn MqttImportContext>,
+
+    model: HomieDeviceModel,
+
+    /// The materialized nodes by node id
+    nodes: HashMap<String, HomieNodeInstance>,
+
+    /// The property topics whose received value is being set. The value isn't published again.
+    receiving: Arc<Mutex<HashSet<String>>>,
+}
+
+impl MqttImport for HomieDeviceImport {
+    type Message = HomieMessage;
+
+    fn import(&mut self, message: HomieMessage) {
+        self.received(message.levels, message.payload);
+    }
+
+    fn shutdown(&mut self) {
+        let node_ids: Vec<String> = self.nodes.keys().cloned().collect();
+        for node_id in node_ids {
+            self.remove_node(node_id.as_str());
+        }
+    }
+}
+
+impl HomieDeviceImport {
+    fn received(&mut self, levels: Vec<String>, payload: String) {
+        let levels: Vec<&str> = levels.iter().map(String::as_str).collect();
+        match levels.as_slice() {
+            ["$name"] => self.set_device_property(HomieDeviceProperties::NAME, json!(payload)),
+            ["$state"] => self.set_device_property(HomieDeviceProperties::STATE, json!(payload)),
+            ["$nodes"] => {
+                self.model.nodes = homie_list(payload.as_str());
+                self.set_device_property(HomieDeviceProperties::NODES, json!(self.model.nodes));
+                let node_ids: Vec<String> = self.nodes.keys().cloned().collect();
+                for node_id in node_ids {
+                    if !self.model.nodes.contains(&node_id) {
+                        self.remove_node(node_id.as_str());
+                    }
+                }
+                for node_id in self.model.nodes.clone() {
+                    self.update_node(node_id.as_str());
+                }
+            }
+            [node_id, "$name"] => {
+                self.node_model(node_id).name = payload;
+                self.update_node(node_id);
+            }
+            [node_id, "$type"] => {
+                self.node_model(node_id).node_type = payload;
+                self.update_node(node_id);
+            }
+            [node_id, "$properties"] => {
+                self.node_model(node_id).properties = Some(homie_list(payload.as_str()));
+                self.update_node(node_id);
+            }
+            [node_id, property_id]
+                if !node_id.starts_with('$') && !property_id.starts_with('$') =>
+            {
+                self.node_model(node_id)
+                    .values
+                    .insert(property_id.to_string(), payload.clone());
+                self.set_value(node_id, property_id, payload.as_str());
+            }
+            [node_id, property_id, attribute] if attribute.starts_with('$') => {
+                let changed = self
+                    .node_model(node_id)
+                    .attributes
+                    .entry(property_id.to_string())
+                    .or_default()
+                    .set_attribute(attribute, payload.as_str());
+                if changed {
+                    self.update_node(node_id);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn node_model(&mut self, node_id: &str) -> &mut HomieNodeModel {
+        self.model
+            .node_models
+            .entry(node_id.to_string())
+            .or_default()
+    }
+
+    /// Materializes the node as soon as it is complete and again whenever its structure changes.
+    fn update_node(&mut self, node_id: &str) {
+        if !self.model.nodes.iter().any(|id| id == node_id) {
+            return;
+        }
+        let description = match self
+            .model
+            .node_models
+            .get(node_id)
+            .and_then(HomieNodeModel::description)
+        {
+            Some(description) => description,
+            None => return,
+        };
+        if let Some(node) = self.nodes.get(node_id) {
+            if node.description == description {
+                return;
+            }
+        }
+        self.remove_node(node_id);
+        self.create_node(node_id, description);
+    }
+
+    fn create_node(&mut self, node_id: &str, description: HomieNodeDescription) {
+        let label = match description.name.is_empty() {
+            true => node_id.to_string(),
+            false => description.name.clone(),
+        };
+        let mut builder = EntityInstanceBuilder::new(HOMIE_NODE);
+        builder.property(
+            HomieNodeProperties::DEVICE_ID.as_ref(),
+            json!(self.device_id),
+        );
+        builder.property(HomieNodeProperties::NODE_ID.as_ref(), json!(node_id));
+        builder.property(HomieNodeProperties::NAME.as_ref(), json!(description.name));
+        builder.property(
+            HomieNodeProperties::TYPE.as_ref(),
+            json!(description.node_type),
+        );
+        builder.property(LABEL, json!(label));
+        let mut attributes = Map::new();
+        let values = self
+            .model
+            .node_models
+            .get(node_id)
+            .map(|node_model| node_model.values.clone())
+            .unwrap_or_default();
+        for (property_id, property) in description.properties.iter() {
+            if is_reserved(property_id.as_str()) {
+                error!(
+                    "Can't materialize property {} of Homie node {}/{}: The name is reserved",
+                    property_id, self.device_topic, node_id
+                );
+                continue;
+            }
+            let value = values
+                .get(property_id)
+                .and_then(|payload| property.decode(payload.as_str()).ok())
+                .unwrap_or(property.default_value());
+            builder.property(property_id.as_str(), value);
+            attributes.insert(property_id.clone(), property.to_json());
+        }
+        builder.property(
+            HomieNodeProperties::PROPERTIES.as_ref(),
+            Value::Object(attributes),
+        );
+        let entity = match self.context.create_entity(builder.get()) {
+            Some(entity) => entity,
+            None => {
+                error!(
+                    "Failed to materialize Homie node {}/{}",
+                    self.device_topic, node_id
+                );
+                return;
+            }
+        };
+        debug!(
+            "Materialized Homie node {}/{} as entity instance {}",
+            self.device_topic, node_id, entity.id
+        );
+        let handles = description
+            .properties
+            .iter()
+            .filter(|(property_id, property)| property.settable && !is_reserved(property_id))
+            .filter_map(|(property_id, property)| {
+                self.observe_settable(&entity, node_id, property_id, property)
+                    .map(|handle_id| (property_id.clone(), handle_id))
+            })
+            .collect();
+        self.nodes.insert(
+            node_id.to_string(),
+            HomieNodeInstance {
+                description,
+                entity,
+                handles,
+            },
+        );
+    }
+
+    /// Publishes the values which are written into the property to the topic `.../set`.
+    fn observe_settable(
+        &self,
+        entity: &ReactiveEntityInstance,
+        node_id: &str,
+        property_id: &str,
+        property: &HomieProperty,
+    ) -> Option<u128> {
+        let property_instance = entity.properties.get(property_id)?;
+        let handle_id = property_instance.id.as_u128();
+        let property_topic = self.property_topic(node_id, property_id);
+        let set_topic = format!("{}/set", property_topic);
+        let property = property.clone();
+        let broker = self.broker.clone();
+        let receiving = self.receiving.clone();
+        property_instance
+            .stream
+            .read()
+            .unwrap()
+            .observe_with_handle(
+                move |v| {
+                    if receiving.lock().unwrap().contains(&property_topic) {
+                        return;
+                    }
+                    let payload = match property.encode(v) {
+                        Ok(payload) => payload,
+                        Err(err) => {
+                            error!("Can't set {}: {}", property_topic, err);
+                            return;
+                        }
+                    };
+                    let broker = match broker.upgrade() {
+                        Some(broker) => broker,
+                        None => return,
+                    };
+                    if let Some(send_package) = broker
+                        .properties
+                        .get(MqttBrokerProperties::SEND_PACKAGE.as_ref())
+                    {
+                        send_package.set(json!({
+                            MqttTopicProperties::TOPIC.as_ref(): set_topic,
+                            MqttTopicProperties::MODE.as_ref(): MqttPayloadMode::Raw.as_ref(),
+                            MqttTopicProperties::QOS.as_ref(): QoS::AtLeastOnce as u8,
+                            MqttTopicProperties::RETAIN.as_ref(): false,
+                            MqttEndpointProperties::PAYLOAD.as_ref(): payload
+                        }));
+                    }
+                },
+                handle_id,
+            );
+        Some(handle_id)
+    }
+
+    fn remove_node(&mut self, node_id: &str) {
+        let node = match self.nodes.remove(node_id) {
+            Some(node) => node,
+            None => return,
+        };
+        for (property_id, handle_id) in node.handles {
+            if let Some(property) = node.entity.properties.get(property_id.as_str()) {
+                property.stream.read().unwrap().remove(handle_id);
+            }
+        }
+        debug!(
+            "Removing Homie node {}/{} (entity instance {})",
+            self.device_topic, node_id, node.entity.id
+        );
+        self.context.delete_entity(node.entity.id);
+    }
+
+    /// Sets the received value of a property of a materialized node.
+    fn set_value(&self, node_id: &str, property_id: &str, payload: &str) {
+        let node = match self.nodes.get(node_id) {
+            Some(node) => node,
+            None => return,
+        };
+        let property = match node.description.properties.get(property_id) {
+            Some(property) => property,
+            None => return,
+        };
+        let value = match property.decode(payload) {
+            Ok(value) => value,
+            Err(err) => {
+                debug!(
+                    "Invalid value of {}: {}",
+                    self.property_topic(node_id, property_id),
+                    err
+                );
+                return;
+            }
+        };
+        if let Some(property_instance) = node.entity.properties.get(property_id) {
+            // The observer of a settable property is called while the value is set
+            let property_topic = self.property_topic(node_id, property_id);
+            self.receiving
+                .lock()
+                .unwrap()
+                .insert(property_topic.clone());
+            property_instance.set(value);
+            self.receiving.lock().unwrap().remove(&property_topic);
+        }
+    }
+
+    fn set_device_property(&self, property: HomieDeviceProperties, value: Value) {
+        if let Some(property) = self.entity.properties.get(property.as_ref()) {
+            property.set(value);
+        }
+    }
+
+    fn property_topic(&self, node_id: &str, property_id: &str) -> String {
+        format!("{}/{}/{}", self.device_topic, node_id, property_id)
+    }
+}
+
+/// The properties of the homie_node entity type and the label can't be used by Homie properties.
+fn is_reserved(property_id: &str) -> bool {
+    property_id == LABEL
+        || HomieNodeProperties::properties()
+            .iter()
+            .any(|property| property.name.as_str() == property_id)
+}
+
+impl Disconnectable for HomieDevice {
+    fn disconnect(&self) {
+        debug!("Disconnecting homie_device {}", self.handle_id);
+        // The import removes the materialized nodes before it stops
+        self.importer.disconnect();
+    }
+}
+
+/// Automatically disconnect streams on destruction
+impl Drop for HomieDevice {
+    fn drop(&mut self) {
+        self.disconnect();
+    }
+}
@@ -0,0 +1,253 @@
+use std::convert::AsRef;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::sync::Weak;
+use std::thread;
+use std::thread::JoinHandle;
+
+use indradb::EdgeKey;
+use log::error;
+use rumqttc::QoS;
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::behaviour::components::get_received_payload;
+use crate::behaviour::components::MqttTopicProperties;
+use crate::behaviour::entity::mqtt_broker::MqttBroker;
+use crate::behaviour::entity::MqttBrokerProperties;
+use crate::model::EntityInstance;
+use crate::model::ReactiveEntityInstance;
+use crate::model::RelationInstance;
+use crate::plugins::plugin_context::PluginContext;
+use crate::reactive::entity::Disconnectable;
+use crate::reactive::BehaviourCreationError;
+
+/// The property of the labeled component.
+pub const LABEL: &str = "label";
+
+/// Imports the messages of a topic into the graph.
+pub trait MqttImport: Send + 'static {
+    /// The message which is extracted from a received package
+    type Message: Send + 'static;
+
+    /// Handles a message on the importer thread.
+    fn import(&mut self, message: Self::Message);
+
+    /// Removes the imported instances before the importer thread stops.
+    fn shutdown(&mut self);
+}
+
+/// Creates and deletes the imported instances.
+pub trait MqttImportContext: Send + Sync {
+    /// Returns None if the entity instance can't be created.
+    fn create_entity(&self, entity_instance: EntityInstance)
+        -> Option<Arc<ReactiveEntityInstance>>;
+
+    fn delete_entity(&self, id: Uuid);
+
+    /// Returns None if the relation instance can't be created.
+    fn create_relation(&self, relation_instance: RelationInstance) -> Option<EdgeKey>;
+
+    fn delete_relation(&self, edge_key: EdgeKey);
+}
+
+/// Imports into the instance managers of the plugin context, which also create the behaviours of
+/// the imported instances.
+pub struct MqttPluginImportContext(pub Arc<dyn PluginContext>);
+
+impl MqttImportContext for MqttPluginImportContext {
+    fn create_entity(
+        &self,
+        entity_instance: EntityInstance,
+    ) -> Option<Arc<ReactiveEntityInstance>> {
+        self.0
+            .get_entity_instance_manager()
+            .create(entity_instance)
+            .ok()
+    }
+
+    fn delete_entity(&self, id: Uuid) {
+        self.0.get_entity_instance_manager().delete(id);
+    }
+
+    fn create_relation(&self, relation_instance: RelationInstance) -> Option<EdgeKey> {
+        self.0
+            .get_relation_instance_manager()
+            .create(relation_instance)
+            .ok()
+            .and_then(|relation_instance| relation_instance.get_key())
+    }
+
+    fn delete_relation(&self, edge_key: EdgeKey) {
+        self.0.get_relation_instance_manager().delete(edge_key);
+    }
+}
+
+enum MqttImporterMessage<M> {
+    Import(M),
+    Shutdown,
+}
+
+/// Observes the received packages of a broker.
+type MqttImporterObserver = Arc<dyn Fn(&Value) + Send + Sync>;
+
+/// Forwards the packages which are received on a topic to an import running on its own thread,
+/// because the instance managers can't be called from within an observer of the broker.
+///
+/// The importer is disconnected by the behaviour which owns it. If the behaviour of the broker is
+/// recreated, the import continues with the next behaviour and keeps the imported instances.
+pub struct MqttImporter<M> {
+    handle_id: u128,
+
+    broker: RwLock<Weak<MqttBroker>>,
+
+    /// The entity of the broker, whose received packages are observed
+    broker_entity: RwLock<Weak<ReactiveEntityInstance>>,
+
+    topic: String,
+
+    observer: MqttImporterObserver,
+
+    sender: Mutex<mpsc::Sender<MqttImporterMessage<M>>>,
+
+    importer_thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl<M: Send + 'static> MqttImporter<M> {
+    /// Starts the import and subscribes the topic. The extractor converts the topic and the
+    /// payload of a received package into a message or skips the package.
+    pub fn start<I, F>(
+        e: &ReactiveEntityInstance,
+        handle_id: u128,
+        broker: Arc<MqttBroker>,
+        topic: String,
+        import: I,
+        extractor: F,
+    ) -> Result<MqttImporter<M>, BehaviourCreationError>
+    where
+        I: MqttImport<Message = M>,
+        F: Fn(&str, Vec<u8>) -> Option<M> + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let thread_name = format!("{}-{}", e.type_name.clone(), e.id.to_string());
+        let importer_thread = thread::Builder::new()
+            .name(thread_name)
+            .spawn(move || run(import, receiver));
+        let importer_thread = match importer_thread {
+            Ok(importer_thread) => importer_thread,
+            Err(err) => {
+                error!(
+                    "Failed to start the import of {} {}: {}",
+                    e.type_name, e.id, err
+                );
+                return Err(BehaviourCreationError.into());
+            }
+        };
+
+        let observed_sender = Mutex::new(sender.clone());
+        let observer: MqttImporterObserver = Arc::new(move |v: &Value| {
+            let received_topic = match v
+                .get(MqttTopicProperties::TOPIC.as_ref())
+                .and_then(Value::as_str)
+            {
+                Some(received_topic) => received_topic,
+                None => return,
+            };
+            let payload = get_received_payload(v).unwrap_or_default();
+            if let Some(message) = extractor(received_topic, payload) {
+                let _ = observed_sender
+                    .lock()
+                    .unwrap()
+                    .send(MqttImporterMessage::Import(message));
+            }
+        });
+
+        let importer = MqttImporter {
+            handle_id,
+            broker: RwLock::new(Weak::new()),
+            broker_entity: RwLock::new(Weak::new()),
+            topic,
+            observer,
+            sender: Mutex::new(sender),
+            importer_thread: Mutex::new(Some(importer_thread)),
+        };
+        importer.relink(broker);
+        Ok(importer)
+    }
+
+    /// Receives the packages of the given behaviour of the broker. The behaviour of the broker
+    /// doesn't know the topics of a previous behaviour, so the topic is subscribed again.
+    pub fn relink(&self, broker: Arc<MqttBroker>) {
+        let previous_entity = self.broker_entity.read().unwrap().upgrade();
+        if let Some(previous_entity) = previous_entity {
+            if !Arc::ptr_eq(&previous_entity, &broker.entity) {
+                self.unobserve(&previous_entity);
+            }
+        }
+        let observer = self.observer.clone();
+        broker
+            .entity
+            .properties
+            .get(MqttBrokerProperties::RECEIVED_PACKAGE.as_ref())
+            .unwrap()
+            .stream
+            .read()
+            .unwrap()
+            .observe_with_handle(move |v| observer(v), self.handle_id);
+
+        // Subscribe after observing, because the retained messages are delivered immediately
+        broker.subscribe(self.topic.as_str(), QoS::AtLeastOnce);
+        *self.broker_entity.write().unwrap() = Arc::downgrade(&broker.entity);
+        *self.broker.write().unwrap() = Arc::downgrade(&broker);
+    }
+}
+
+impl<M> MqttImporter<M> {
+    fn unobserve(&self, broker_entity: &ReactiveEntityInstance) {
+        if let Some(property) = broker_entity
+            .properties
+            .get(MqttBrokerProperties::RECEIVED_PACKAGE.as_ref())
+        {
+            property.stream.read().unwrap().remove(self.handle_id);
+        }
+    }
+}
+
+fn run<I: MqttImport>(mut import: I, receiver: mpsc::Receiver<MqttImporterMessage<I::Message>>) {
+    while let Ok(message) = receiver.recv() {
+        match message {
+            MqttImporterMessage::Import(message) => import.import(message),
+            MqttImporterMessage::Shutdown => break,
+        }
+    }
+    import.shutdown();
+}
+
+impl<M> Disconnectable for MqttImporter<M> {
+    fn disconnect(&self) {
+        // The observer outlives a removed behaviour of the broker
+        let broker_entity = self.broker_entity.read().unwrap().upgrade();
+        if let Some(broker_entity) = broker_entity {
+            self.unobserve(&broker_entity);
+        }
+        let broker = self.broker.read().unwrap().upgrade();
+        if let Some(broker) = broker {
+            broker.unsubscribe(self.topic.as_str(), QoS::AtLeastOnce);
+        }
+        // The import removes the imported instances before the thread stops
+        let _ = self
+            .sender
+            .lock()
+            .unwrap()
+            .send(MqttImporterMessage::Shutdown);
+        let importer_thread = self.importer_thread.lock().unwrap().take();
+        if let Some(importer_thread) = importer_thread {
+            // The import can't wait for itself
+            if thread::current().id() != importer_thread.thread().id() {
+                let _ = importer_thread.join();
+            }
+        }
+    }
+}
@@ -1,5 +1,17 @@
 pub use properties::*;
+pub mod client;
+pub mod connection;
 pub mod entity_behaviour_provider;
+pub mod homie;
+pub mod homie_device;
+pub mod importer;
 
 pub mod mqtt_broker;
+pub mod mqtt_ha_discovery;
+pub mod mqtt_server;
+pub mod offline_queue;
 pub mod properties;
+pub mod reconnect;
+pub mod subscriptions;
+pub mod tls;
+pub mod transport;
@@ -1,25 +1,29 @@
 use std::convert::AsRef;
+use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::sync::Weak;
+use std::thread;
+use std::thread::JoinHandle;
 use std::time::Duration;
 
-use async_std::task;
 use log::debug;
 use log::error;
-use log::trace;
-use rumqttc::Client;
-use rumqttc::ConnectionError;
-use rumqttc::Event;
-use rumqttc::MqttOptions;
-use rumqttc::Packet::Publish;
 use rumqttc::QoS;
 use serde_json::json;
-use serde_json::Error;
-use serde_json::Value;
 
+use crate::behaviour::components::mqtt_qos;
 use crate::behaviour::components::MqttEndpointProperties;
+use crate::behaviour::components::MqttMessageProperties;
 use crate::behaviour::components::MqttPayload;
 use crate::behaviour::components::MqttPayloadMode;
 use crate::behaviour::components::MqttTopicProperties;
+use crate::behaviour::entity::connection::take_password;
+use crate::behaviour::entity::connection::MqttConnection;
+use crate::behaviour::entity::offline_queue::MqttOfflineQueue;
+use crate::behaviour::entity::subscriptions::MqttSubscriptions;
 use crate::behaviour::entity::MqttBrokerProperties;
 use crate::model::PropertyInstanceGetter;
 use crate::model::ReactiveEntityInstance;
@@ -31,13 +35,33 @@ pub struct MqttBroker {
 
     pub handle_id: u128,
 
-    stopper: crossbeam::channel::Sender<()>,
+    connection: Arc<RwLock<Option<MqttConnection>>>,
+
+    subscriptions: Arc<Mutex<MqttSubscriptions>>,
+
+    reconnect_sender: Mutex<mpsc::Sender<MqttReconnectRequest>>,
+
+    /// Replaces the connection, so the observers of the configuration don't wait for the event
+    /// loop of the previous connection
+    reconnect_thread: Mutex<Option<JoinHandle<()>>>,
 }
 
-impl MqttBroker {
-    pub fn new<'a>(e: Arc<ReactiveEntityInstance>) -> Result<MqttBroker, BehaviourCreationError> {
-        let (tx, rx) = crossbeam::channel::bounded(1);
+/// Requests to the thread which replaces the connection of a broker.
+enum MqttReconnectRequest {
+    Reconnect,
+    Shutdown,
+}
+
+/// Changes of the configuration within this time are applied by a single reconnect.
+const RECONNECT_DEBOUNCE: Duration = Duration::from_millis(100);
 
+impl MqttBroker {
+    /// The password has been taken out of the graph by a previous behaviour of the entity, so it
+    /// is kept by the caller across behaviours. A password in the graph replaces it.
+    pub fn new<'a>(
+        e: Arc<ReactiveEntityInstance>,
+        password: Arc<Mutex<String>>,
+    ) -> Result<MqttBroker, BehaviourCreationError> {
         // TODO: Validate properties
         let send_package = e
             .properties
@@ -56,26 +80,61 @@ impl MqttBroker {
         // let received_package = received_package.unwrap();
 
         let handle_id = send_package.id.as_u128();
-        let hostname = e
-            .as_string(MqttBrokerProperties::HOSTNAME.as_ref())
-            .unwrap_or(MqttBrokerProperties::HOSTNAME.default_value());
-        let port = e
-            .as_i64(MqttBrokerProperties::PORT.as_ref())
-            .unwrap_or(1833) as u16;
 
-        let mqtt_client_id = format!("inexor-{}", e.id);
-        let mqtt_options = MqttOptions::new(mqtt_client_id, hostname.clone(), port);
-        // mqtt_options.set_keep_alive(5);
+        // The topics are subscribed as soon as the connection has been established
+        let subscribe_all = e
+            .as_bool(MqttBrokerProperties::SUBSCRIBE_ALL.as_ref())
+            .unwrap_or(false);
+        let subscriptions = Arc::new(Mutex::new(MqttSubscriptions::new(subscribe_all)));
 
-        let (mut mqtt_client, mut connection) = Client::new(mqtt_options, 10);
+        // The messages are kept across reconnects
+        let offline_queue = Arc::new(Mutex::new(MqttOfflineQueue::new(0)));
 
-        // Subscribe all topics (the routing is done in the relationship
-        let _ = mqtt_client.subscribe("#", QoS::AtMostOnce);
+        let taken_password = take_password(&e);
+        if !taken_password.is_empty() {
+            *password.lock().unwrap() = taken_password;
+        }
 
-        let mut mqtt_client_publisher = mqtt_client.clone();
+        let connection = MqttConnection::connect(
+            e.clone(),
+            password.lock().unwrap().as_str(),
+            subscriptions.clone(),
+            offline_queue.clone(),
+        )?;
+        let connection = Arc::new(RwLock::new(Some(connection)));
 
-        let mqtt_hostname = hostname.clone();
-        let mqtt_port = port.clone();
+        let (reconnect_sender, receiver) = mpsc::channel();
+        let reconnect_thread = {
+            let entity = e.clone();
+            let password = password.clone();
+            let connection = connection.clone();
+            let subscriptions = subscriptions.clone();
+            let offline_queue = offline_queue.clone();
+            let thread_name = format!("{}-{}-reconnect", e.type_name.clone(), e.id.to_string());
+            thread::Builder::new().name(thread_name).spawn(move || {
+                run_reconnects(
+                    entity,
+                    password,
+                    connection,
+                    subscriptions,
+                    offline_queue,
+                    receiver,
+                )
+            })
+        };
+        let reconnect_thread = match reconnect_thread {
+            Ok(reconnect_thread) => reconnect_thread,
+            Err(err) => {
+                error!(
+                    "Failed to start reconnects of mqtt broker {}: {}",
+                    e.id, err
+                );
+                return Err(BehaviourCreationError.into());
+            }
+        };
+
+        let publisher_connection = connection.clone();
+        let publisher_entity = Arc::downgrade(&e);
         send_package.stream.read().unwrap().observe_with_handle(
             move |v| {
                 let topic = v.get(MqttTopicProperties::TOPIC.as_ref());
@@ -85,145 +144,219 @@ impl MqttBroker {
                     return;
                 }
                 let topic = topic.unwrap().as_str().unwrap();
-                let mode = mode.unwrap().as_str().unwrap().into();
-                // let mode = mode.unwrap().as_str().unwrap().into();
-                let payload = match mode {
-                    MqttPayloadMode::Json => MqttPayload::Json(payload.unwrap().clone()),
-                    MqttPayloadMode::Raw => MqttPayload::Raw(payload.unwrap().clone()),
+                let mode: MqttPayloadMode = mode.unwrap().as_str().unwrap().into();
+                let qos = v
+                    .get(MqttTopicProperties::QOS.as_ref())
+                    .and_then(|qos| qos.as_u64())
+                    .and_then(mqtt_qos)
+                    .unwrap_or(QoS::AtLeastOnce);
+                let retain = v
+                    .get(MqttTopicProperties::RETAIN.as_ref())
+                    .and_then(|retain| retain.as_bool())
+                    .unwrap_or(false);
+                let payload = payload.unwrap();
+                // The user properties, the content type and the message expiry of MQTT 5
+                let properties = MqttMessageProperties::from_package(v);
+                let connection = publisher_connection.read().unwrap();
+                let connection = match connection.as_ref() {
+                    Some(connection) => connection,
+                    None => {
+                        error!("Failed to publish to topic {} Error: Not connected", topic);
+                        set_last_error(&publisher_entity, "Not connected");
+                        return;
+                    }
+                };
+                let mqtt_hostname = connection.hostname.clone();
+                let mqtt_port = connection.port;
+                // Publishing an empty retained message clears the retained message
+                let clear_retained = retain && payload.is_null();
+                let payload = MqttPayload::new(mode, payload.clone());
+                let payload = match clear_retained {
+                    true => Vec::new(),
+                    false => match payload.to_bytes() {
+                        Some(payload) => payload,
+                        None => {
+                            error!(
+                                "Failed to publish to topic {}:{}/{} Error: Payload doesn't match mode {}",
+                                mqtt_hostname.clone(),
+                                mqtt_port,
+                                topic.clone(),
+                                mode
+                            );
+                            return;
+                        }
+                    },
                 };
                 debug!(
-                    "Publishing to topic {}:{}/{} ---> {}",
+                    "Publishing to topic {}:{}/{} (retain: {}) ---> {}",
                     mqtt_hostname.clone(),
                     mqtt_port,
                     topic.clone(),
-                    payload.to_string()
+                    retain,
+                    String::from_utf8_lossy(payload.as_ref())
                 );
-                let result = mqtt_client_publisher.publish(
-                    topic.clone(),
-                    QoS::AtLeastOnce,
-                    false,
-                    payload.to_string(),
-                );
-                match result {
-                    Ok(_) => {}
-                    Err(err) => error!(
-                        "Failed to publish to topic {}:{}/{} Error: {:?}",
+                if let Err(err) = connection.publish(topic, qos, retain, payload, properties) {
+                    error!(
+                        "Failed to publish to topic {}:{}/{} Error: {}",
                         mqtt_hostname.clone(),
                         mqtt_port,
                         topic.clone(),
                         err
-                    ),
+                    );
+                    set_last_error(&publisher_entity, err.as_str());
                 }
             },
             handle_id,
         );
 
-        let mut mqtt_client_subscriber = mqtt_client.clone();
-        let entity = e.clone();
-        let thread_name = format!("{}-{}", e.type_name.clone(), e.id.to_string());
-        let _handler = task::Builder::new().name(thread_name).spawn(async move {
-            debug!("Connecting to MQTT broker {}:{}", hostname.clone(), port);
-
-            let received_package = entity
-                .properties
-                .get(MqttBrokerProperties::RECEIVED_PACKAGE.as_ref())
-                .unwrap();
-            // if received_package.is_none() {
-            //     return Err(BehaviourCreationError.into());
-            // }
-            // let received_package = received_package.unwrap();
-
-            for result in connection.iter() {
-                match result {
-                    Err(err) => {
-                        match err {
-                            ConnectionError::Io(err) => {
-                                error!(
-                                    "Failed to connect to MQTT broker {}:{} : {:?}",
-                                    hostname.clone(),
-                                    port,
-                                    err
-                                );
-                            }
-                            _ => {}
-                        }
-                        std::thread::sleep(Duration::from_millis(2000))
-                    }
-                    Ok(Event::Incoming(event)) => {
-                        trace!("Incoming event {:?}", event);
-                        match event {
-                            Publish(publish) => {
-                                trace!("Topic: {}", publish.topic);
-                                let payload = String::from_utf8_lossy(publish.payload.as_ref());
-                                trace!("Payload (RAW): {}", payload);
-                                let payload_json: Result<Value, Error> =
-                                    serde_json::from_str(payload.as_ref());
-                                if payload_json.is_ok() {
-                                    let payload = payload_json.unwrap();
-                                    trace!("Payload (JSON): {}", payload);
-                                    let value: Value = json!({
-                                        MqttTopicProperties::TOPIC.as_ref(): publish.topic,
-                                        // MqttTopicProperties::MODE.as_ref(): mode.clone(),
-                                        MqttEndpointProperties::PAYLOAD.as_ref(): payload
-                                    });
-                                    received_package.set(value);
-                                } else {
-                                    trace!("Payload (JSON): {}", payload);
-                                    let value: Value = json!({
-                                        MqttTopicProperties::TOPIC.as_ref(): publish.topic,
-                                        // MqttTopicProperties::MODE.as_ref(): mode.clone(),
-                                        MqttEndpointProperties::PAYLOAD.as_ref(): payload.clone()
-                                    });
-                                    received_package.set(value);
-                                }
-                            }
-                            _ => {}
+        // Changing the configuration replaces the connection. The subscriptions are kept.
+        for property in MqttBrokerProperties::connection_properties() {
+            let is_password = property.as_ref() == MqttBrokerProperties::PASSWORD.as_ref();
+            if let Some(property) = e.properties.get(property.as_ref()) {
+                let entity = e.clone();
+                let password = password.clone();
+                let reconnect_sender = Mutex::new(reconnect_sender.clone());
+                property.stream.read().unwrap().observe_with_handle(
+                    move |_| {
+                        // An empty password removes the password
+                        if is_password {
+                            *password.lock().unwrap() = take_password(&entity);
                         }
-                        // TODO: Handle received data
-                        // event.
-                        // let mut map: Map<String, Value> = Map::new();
-                        // map.insert("", )
-                        // let value = Value::Object(map);
-                        // let package: Value = json!({
-                        //     MqttTopicProperties::TOPIC.as_ref(): topic,
-                        //     MqttTopicProperties::MODE.as_ref(): mode.clone(),
-                        //     MqttEndpointProperties::PAYLOAD.as_ref(): payload
-                        // });
-                        // received_package.set(value);
-                    }
-                    _ => {}
-                }
-                match rx.try_recv() {
-                    // Stop thread
-                    Ok(_) => break,
-                    // About ~ 100fps
-                    Err(_) => std::thread::sleep(Duration::from_millis(100)),
-                }
+                        let _ = reconnect_sender
+                            .lock()
+                            .unwrap()
+                            .send(MqttReconnectRequest::Reconnect);
+                    },
+                    handle_id,
+                );
             }
-            let _ = mqtt_client_subscriber.disconnect();
-            debug!(
-                "Disconnected client connection to MQTT broker {}:{}",
-                hostname.clone(),
-                port
-            );
-        });
+        }
 
         Ok(MqttBroker {
             entity: e.clone(),
             handle_id,
-            stopper: tx.clone(),
+            connection,
+            subscriptions,
+            reconnect_sender: Mutex::new(reconnect_sender),
+            reconnect_thread: Mutex::new(Some(reconnect_thread)),
         })
     }
 
+    /// Subscribes the topic unless another mqtt_subscribes relation already did.
+    ///
+    /// Delivers the messages of the topic which have been received before.
+    pub fn subscribe(&self, topic: &str, qos: QoS) {
+        // The subscriptions are restored as soon as the next connection has been established
+        let undelivered = match self.connection.read().unwrap().as_ref() {
+            Some(connection) => connection.subscribe(topic, qos),
+            None => self.subscriptions.lock().unwrap().subscribe(topic, qos),
+        };
+        if let Some(property) = self
+            .entity
+            .properties
+            .get(MqttBrokerProperties::RECEIVED_PACKAGE.as_ref())
+        {
+            for package in undelivered {
+                property.set(package);
+            }
+        }
+    }
+
+    /// Unsubscribes the topic if no mqtt_subscribes relation uses it anymore.
+    pub fn unsubscribe(&self, topic: &str, qos: QoS) {
+        match self.connection.read().unwrap().as_ref() {
+            Some(connection) => connection.unsubscribe(topic, qos),
+            None => self.subscriptions.lock().unwrap().unsubscribe(topic, qos),
+        }
+    }
+
     pub fn type_name(&self) -> String {
         self.entity.type_name.clone()
     }
 }
 
+fn set_last_error(entity: &Weak<ReactiveEntityInstance>, error: &str) {
+    if let Some(entity) = entity.upgrade() {
+        if let Some(property) = entity
+            .properties
+            .get(MqttBrokerProperties::LAST_ERROR.as_ref())
+        {
+            property.set(json!(error));
+        }
+    }
+}
+
+/// Reconnects once for the changes of the configuration which arrive in quick succession, e.g.
+/// of the hostname and the port.
+fn run_reconnects(
+    entity: Arc<ReactiveEntityInstance>,
+    password: Arc<Mutex<String>>,
+    connection: Arc<RwLock<Option<MqttConnection>>>,
+    subscriptions: Arc<Mutex<MqttSubscriptions>>,
+    offline_queue: Arc<Mutex<MqttOfflineQueue>>,
+    receiver: mpsc::Receiver<MqttReconnectRequest>,
+) {
+    while let Ok(MqttReconnectRequest::Reconnect) = receiver.recv() {
+        loop {
+            match receiver.recv_timeout(RECONNECT_DEBOUNCE) {
+                Ok(MqttReconnectRequest::Reconnect) => continue,
+                Err(RecvTimeoutError::Timeout) => break,
+                Ok(MqttReconnectRequest::Shutdown) | Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+        reconnect(
+            entity.clone(),
+            password.clone(),
+            connection.clone(),
+            subscriptions.clone(),
+            offline_queue.clone(),
+        );
+    }
+}
+
+/// Tears down the current connection and connects with the current configuration.
+fn reconnect(
+    entity: Arc<ReactiveEntityInstance>,
+    password: Arc<Mutex<String>>,
+    connection: Arc<RwLock<Option<MqttConnection>>>,
+    subscriptions: Arc<Mutex<MqttSubscriptions>>,
+    offline_queue: Arc<Mutex<MqttOfflineQueue>>,
+) {
+    // The lock must not be held while waiting for the event loop, which may publish
+    let previous_connection = connection.write().unwrap().take();
+    if let Some(previous_connection) = previous_connection {
+        debug!(
+            "Reconfiguring connection to MQTT broker {}:{}",
+            previous_connection.hostname.clone(),
+            previous_connection.port
+        );
+        previous_connection.disconnect();
+    }
+    let subscribe_all = entity
+        .as_bool(MqttBrokerProperties::SUBSCRIBE_ALL.as_ref())
+        .unwrap_or(false);
+    {
+        let mut subscriptions = subscriptions.lock().unwrap();
+        subscriptions.disconnected();
+        subscriptions.set_subscribe_all(subscribe_all);
+    }
+    offline_queue.lock().unwrap().disconnected();
+    let password = password.lock().unwrap().clone();
+    match MqttConnection::connect(
+        entity.clone(),
+        password.as_str(),
+        subscriptions,
+        offline_queue,
+    ) {
+        Ok(next_connection) => {
+            *connection.write().unwrap() = Some(next_connection);
+        }
+        Err(_) => error!("Failed to reconnect MQTT broker {}", entity.id),
+    }
+}
+
 impl Disconnectable for MqttBroker {
     fn disconnect(&self) {
-        // Stop event loop thread
-        let _ = self.stopper.send(());
         debug!("Disconnecting mqtt broker {}", self.handle_id);
         let property = self
             .entity
@@ -237,6 +370,28 @@ impl Disconnectable for MqttBroker {
                 .unwrap()
                 .remove(self.handle_id);
         }
+        for property in MqttBrokerProperties::connection_properties() {
+            if let Some(property) = self.entity.properties.get(property.as_ref()) {
+                property.stream.read().unwrap().remove(self.handle_id);
+            }
+        }
+        // A pending reconnect must not replace the connection after it has been stopped
+        let _ = self
+            .reconnect_sender
+            .lock()
+            .unwrap()
+            .send(MqttReconnectRequest::Shutdown);
+        let reconnect_thread = self.reconnect_thread.lock().unwrap().take();
+        if let Some(reconnect_thread) = reconnect_thread {
+            if thread::current().id() != reconnect_thread.thread().id() {
+                let _ = reconnect_thread.join();
+            }
+        }
+        // Stop event loop thread
+        let connection = self.connection.write().unwrap().take();
+        if let Some(connection) = connection {
+            connection.disconnect();
+        }
     }
 }
 
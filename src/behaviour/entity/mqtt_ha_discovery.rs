@@ -0,0 +1,400 @@
+use std::collections::HashMap;
+use std::convert::AsRef;
+use std::sync::Arc;
+
+use indradb::EdgeKey;
+use log::debug;
+use log::error;
+use serde_json::json;
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::behaviour::components::MqttEndpointProperties;
+use crate::behaviour::components::MqttPayloadMode;
+use crate::behaviour::components::MqttTopicProperties;
+use crate::behaviour::entity::importer::MqttImport;
+use crate::behaviour::entity::importer::MqttImportContext;
+use crate::behaviour::entity::importer::MqttImporter;
+use crate::behaviour::entity::importer::LABEL;
+use crate::behaviour::entity::mqtt_broker::MqttBroker;
+use crate::behaviour::entity::MqttHaDiscoveryProperties;
+use crate::behaviour::entity::MqttPublisherProperties;
+use crate::behaviour::entity::MqttSubscriberProperties;
+use crate::builder::EntityInstanceBuilder;
+use crate::builder::RelationInstanceBuilder;
+use crate::model::PropertyInstanceGetter;
+use crate::model::ReactiveEntityInstance;
+use crate::reactive::entity::Disconnectable;
+use crate::reactive::BehaviourCreationError;
+use crate::reactive::NamedProperties;
+
+/// The origin of the discovery configs which are published by this plugin. These configs
+/// describe entities of the graph and aren't imported again.
+pub const HA_ORIGIN: &str = env!("CARGO_PKG_NAME");
+
+/// The parts of a Home Assistant discovery config which are materialized in the graph.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HaDiscoveryConfig {
+    /// The label of the created entities and relations
+    pub name: String,
+
+    /// The subscriber receives the state of the device
+    pub state_topic: Option<String>,
+
+    /// The publisher sends commands to the device
+    pub command_topic: Option<String>,
+
+    /// The name of the application which published the config
+    pub origin: Option<String>,
+}
+
+impl HaDiscoveryConfig {
+    /// Parses the payload of a discovery message. Returns None if the config has been cleared.
+    ///
+    /// Supports the abbreviated keys and the base topic `~`.
+    pub fn parse(object_id: &str, payload: &[u8]) -> Result<Option<Self>, String> {
+        if payload.is_empty() {
+            return Ok(None);
+        }
+        let config: Value = serde_json::from_slice(payload).map_err(|err| err.to_string())?;
+        if !config.is_object() {
+            return Err(String::from("The config is not an object"));
+        }
+        let base_topic = config.get("~").and_then(Value::as_str);
+        let topic = |key: &str, abbreviation: &str| {
+            config
+                .get(key)
+                .or_else(|| config.get(abbreviation))
+                .and_then(Value::as_str)
+                .filter(|topic| !topic.is_empty())
+                .map(|topic| expand_base_topic(topic, base_topic))
+        };
+        let name = config
+            .get("name")
+            .and_then(Value::as_str)
+            .filter(|name| !name.is_empty())
+            .unwrap_or(object_id)
+            .to_string();
+        let origin = config
+            .get("origin")
+            .or_else(|| config.get("o"))
+            .and_then(|origin| origin.get("name"))
+            .and_then(Value::as_str)
+            .map(String::from);
+        Ok(Some(HaDiscoveryConfig {
+            name,
+            state_topic: topic("state_topic", "stat_t"),
+            command_topic: topic("command_topic", "cmd_t"),
+            origin,
+        }))
+    }
+
+    /// Returns true if the config has been published by the mqtt_ha_exposed of a graph.
+    pub fn is_exposed(&self) -> bool {
+        self.origin.as_deref() == Some(HA_ORIGIN)
+    }
+}
+
+/// Returns the object id of a discovery topic `prefix/component/[node_id/]object_id/config`.
+pub fn discovery_object_id(discovery_prefix: &str, topic: &str) -> Option<String> {
+    let levels: Vec<&str> = topic
+        .strip_prefix(discovery_prefix)?
+        .strip_prefix('/')?
+        .split('/')
+        .collect();
+    match levels.as_slice() {
+        [_, object_id, "config"] | [_, _, object_id, "config"] => Some(object_id.to_string()),
+        _ => None,
+    }
+}
+
+/// A topic starting or ending with `~` is relative to the base topic.
+fn expand_base_topic(topic: &str, base_topic: Option<&str>) -> String {
+    match base_topic {
+        Some(base_topic) if topic.starts_with('~') => format!("{}{}", base_topic, &topic[1..]),
+        Some(base_topic) if topic.ends_with('~') => {
+            format!("{}{}", &topic[..topic.len() - 1], base_topic)
+        }
+        _ => topic.to_string(),
+    }
+}
+
+/// A discovery message, which is received on the discovery topic of the device.
+struct HaDiscoveryMessage {
+    topic: String,
+
+    object_id: String,
+
+    payload: Vec<u8>,
+}
+
+/// Imports the devices which announce themselves via Home Assistant MQTT discovery.
+///
+/// Each discovered device gets a mqtt_subscriber for its state topic and a mqtt_publisher for its
+/// command topic, which are connected to the broker. The instances are removed as soon as the
+/// config of the device is cleared.
+pub struct MqttHaDiscovery {
+    pub entity: Arc<ReactiveEntityInstance>,
+
+    pub handle_id: u128,
+
+    /// Receives the discovery topic filter, which also matches the configs of the optional node ids
+    importer: MqttImporter<HaDiscoveryMessage>,
+}
+
+impl MqttHaDiscovery {
+    pub fn new<'a>(
+        e: Arc<ReactiveEntityInstance>,
+        broker: Arc<MqttBroker>,
+        context: Arc<dyn MqttImportContext>,
+    ) -> Result<MqttHaDiscovery, BehaviourCreationError> {
+        let discovered = e
+            .properties
+            .get(MqttHaDiscoveryProperties::DISCOVERED.as_ref());
+        if discovered.is_none() {
+            return Err(BehaviourCreationError.into());
+        }
+        let handle_id = discovered.unwrap().id.as_u128();
+
+        let discovery_prefix = e
+            .as_string(MqttHaDiscoveryProperties::DISCOVERY_PREFIX.as_ref())
+            .unwrap_or_else(|| {
+                MqttHaDiscoveryProperties::DISCOVERY_PREFIX
+                    .default_value()
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string()
+            });
+        let topic = format!("{}/#", discovery_prefix);
+
+        let import = HaDiscoveryImport {
+            entity: e.clone(),
+            broker_id: broker.entity.id,
+            context,
+            discovered: HashMap::new(),
+        };
+        let importer = MqttImporter::start(
+            &e,
+            handle_id,
+            broker,
+            topic,
+            import,
+            move |topic, payload| {
+                discovery_object_id(discovery_prefix.as_str(), topic).map(|object_id| {
+                    HaDiscoveryMessage {
+                        topic: topic.to_string(),
+                        object_id,
+                        payload,
+                    }
+                })
+            },
+        )?;
+
+        Ok(MqttHaDiscovery {
+            entity: e.clone(),
+            handle_id,
+            importer,
+        })
+    }
+
+    /// Continues the import with the next behaviour of the broker. The imported instances are
+    /// kept.
+    pub fn relink(&self, broker: Arc<MqttBroker>) {
+        debug!("Relinking mqtt_ha_discovery {}", self.handle_id);
+        self.importer.relink(broker);
+    }
+
+    pub fn type_name(&self) -> String {
+        self.entity.type_name.clone()
+    }
+}
+
+/// The instances which have been created for a discovered device.
+struct HaDiscoveredDevice {
+    config: HaDiscoveryConfig,
+
+    entities: Vec<Uuid>,
+
+    relations: Vec<EdgeKey>,
+}
+
+struct HaDiscoveryImport {
+    entity: Arc<ReactiveEntityInstance>,
+
+    broker_id: Uuid,
+
+    context: Arc<dyn MqttImportContext>,
+
+    /// The discovered devices by discovery topic
+    discovered: HashMap<String, HaDiscoveredDevice>,
+}
+
+impl MqttImport for HaDiscoveryImport {
+    type Message = HaDiscoveryMessage;
+
+    fn import(&mut self, message: HaDiscoveryMessage) {
+        match HaDiscoveryConfig::parse(message.object_id.as_str(), message.payload.as_slice()) {
+            // The entities of the graph which are exposed to Home Assistant exist already
+            Ok(Some(config)) if config.is_exposed() => self.remove(message.topic.as_str()),
+            Ok(Some(config)) => self.import_device(message.topic, config),
+            Ok(None) => self.remove(message.topic.as_str()),
+            Err(err) => error!("Invalid discovery config {}: {}", message.topic, err),
+        }
+        self.set_discovered();
+    }
+
+    fn shutdown(&mut self) {
+        let topics: Vec<String> = self.discovered.keys().cloned().collect();
+        for topic in topics {
+            self.remove(topic.as_str());
+        }
+    }
+}
+
+impl HaDiscoveryImport {
+    fn import_device(&mut self, topic: String, config: HaDiscoveryConfig) {
+        if let Some(device) = self.discovered.get(&topic) {
+            if device.config == config {
+                return;
+            }
+        }
+        // Updates replace the previously created instances
+        self.remove(topic.as_str());
+        debug!("Importing discovered device {} ({})", config.name, topic);
+        let mut device = HaDiscoveredDevice {
+            config: config.clone(),
+            entities: Vec::new(),
+            relations: Vec::new(),
+        };
+        if let Some(state_topic) = config.state_topic {
+            let mut properties = MqttEndpointProperties::properties();
+            properties.append(&mut MqttSubscriberProperties::properties());
+            if let Some(subscriber_id) =
+                self.create_entity("mqtt_subscriber", properties, &config.name)
+            {
+                device.entities.push(subscriber_id);
+                if let Some(edge_key) = self.create_relation(
+                    self.broker_id,
+                    "mqtt_subscribes",
+                    subscriber_id,
+                    state_topic.as_str(),
+                    &config.name,
+                ) {
+                    device.relations.push(edge_key);
+                }
+            }
+        }
+        if let Some(command_topic) = config.command_topic {
+            let mut properties = MqttEndpointProperties::properties();
+            properties.append(&mut MqttPublisherProperties::properties());
+            if let Some(publisher_id) =
+                self.create_entity("mqtt_publisher", properties, &config.name)
+            {
+                device.entities.push(publisher_id);
+                if let Some(edge_key) = self.create_relation(
+                    publisher_id,
+                    "mqtt_publishes",
+                    self.broker_id,
+                    command_topic.as_str(),
+                    &config.name,
+                ) {
+                    device.relations.push(edge_key);
+                }
+            }
+        }
+        self.discovered.insert(topic, device);
+    }
+
+    fn remove(&mut self, topic: &str) {
+        let device = match self.discovered.remove(topic) {
+            Some(device) => device,
+            None => return,
+        };
+        debug!(
+            "Removing discovered device {} ({})",
+            device.config.name, topic
+        );
+        for edge_key in device.relations {
+            self.context.delete_relation(edge_key);
+        }
+        for id in device.entities {
+            self.context.delete_entity(id);
+        }
+    }
+
+    fn create_entity(
+        &self,
+        type_name: &str,
+        properties: NamedProperties,
+        label: &str,
+    ) -> Option<Uuid> {
+        let mut builder = EntityInstanceBuilder::new(type_name);
+        for property in properties {
+            builder.property(property.name.as_str(), property.value);
+        }
+        builder.property(LABEL, json!(label));
+        match self.context.create_entity(builder.get()) {
+            Some(entity_instance) => Some(entity_instance.id),
+            None => {
+                error!(
+                    "Failed to create {} for discovered device {}",
+                    type_name, label
+                );
+                None
+            }
+        }
+    }
+
+    fn create_relation(
+        &self,
+        outbound_id: Uuid,
+        type_name: &str,
+        inbound_id: Uuid,
+        topic: &str,
+        label: &str,
+    ) -> Option<EdgeKey> {
+        let relation_instance = RelationInstanceBuilder::new(outbound_id, type_name, inbound_id)
+            .property(MqttTopicProperties::TOPIC.as_ref(), json!(topic))
+            .property(
+                MqttTopicProperties::MODE.as_ref(),
+                json!(MqttPayloadMode::Raw.as_ref()),
+            )
+            .property(MqttTopicProperties::QOS.as_ref(), json!(0))
+            .property(MqttTopicProperties::RETAIN.as_ref(), json!(false))
+            .property(LABEL, json!(label))
+            .get();
+        let edge_key = self.context.create_relation(relation_instance);
+        if edge_key.is_none() {
+            error!(
+                "Failed to create {} for discovered device {}",
+                type_name, label
+            );
+        }
+        edge_key
+    }
+
+    fn set_discovered(&self) {
+        if let Some(property) = self
+            .entity
+            .properties
+            .get(MqttHaDiscoveryProperties::DISCOVERED.as_ref())
+        {
+            property.set(json!(self.discovered.len()));
+        }
+    }
+}
+
+impl Disconnectable for MqttHaDiscovery {
+    fn disconnect(&self) {
+        debug!("Disconnecting mqtt_ha_discovery {}", self.handle_id);
+        // The import removes the imported instances before it stops
+        self.importer.disconnect();
+    }
+}
+
+/// Automatically disconnect streams on destruction
+impl Drop for MqttHaDiscovery {
+    fn drop(&mut self) {
+        self.disconnect();
+    }
+}
@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+use std::convert::AsRef;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use log::debug;
+use log::error;
+use rumqttd::Broker;
+use rumqttd::Config;
+use rumqttd::ConnectionSettings;
+use rumqttd::ConsoleSettings;
+use rumqttd::RouterConfig;
+use rumqttd::ServerSettings;
+use serde_json::json;
+use tokio::io::copy_bidirectional;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::runtime;
+use tokio::sync::Notify;
+use tokio::sync::Semaphore;
+use tokio::time;
+
+use crate::behaviour::entity::MqttServerProperties;
+use crate::model::PropertyInstanceGetter;
+use crate::model::ReactiveEntityInstance;
+use crate::reactive::entity::Disconnectable;
+use crate::reactive::BehaviourCreationError;
+
+/// The address of the embedded broker of the process on the loopback interface.
+static EMBEDDED_BROKER: Mutex<Option<SocketAddr>> = Mutex::new(None);
+
+/// The connections of a mqtt_server are limited by the mqtt_server itself.
+const EMBEDDED_BROKER_MAX_CONNECTIONS: usize = 100000;
+
+/// An embedded MQTT broker which accepts MQTT 3.1.1 connections of devices and of mqtt_broker
+/// entities.
+///
+/// rumqttd can't be stopped, so a single broker is started by the first mqtt_server and shared by
+/// all mqtt_servers of the process: they form a single MQTT network. The broker only listens on
+/// the loopback interface and each mqtt_server forwards the connections of its own listener to
+/// it. Removing the mqtt_server closes the listener and the forwarded connections, which releases
+/// the listen address.
+///
+/// The configuration is read once. If the port is 0, the operating system chooses the port and
+/// the chosen port is written into the property `port`.
+pub struct MqttServer {
+    pub entity: Arc<ReactiveEntityInstance>,
+
+    pub handle_id: u128,
+
+    pub listen: SocketAddr,
+
+    shutdown: Arc<Notify>,
+
+    listener_thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl MqttServer {
+    pub fn new<'a>(e: Arc<ReactiveEntityInstance>) -> Result<MqttServer, BehaviourCreationError> {
+        let running = e.properties.get(MqttServerProperties::RUNNING.as_ref());
+        if running.is_none() {
+            return Err(BehaviourCreationError.into());
+        }
+        let running = running.unwrap();
+        let handle_id = running.id.as_u128();
+
+        let listen_address = e
+            .as_string(MqttServerProperties::LISTEN_ADDRESS.as_ref())
+            .unwrap_or_else(|| {
+                MqttServerProperties::LISTEN_ADDRESS
+                    .default_value()
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string()
+            });
+        let listen_address = match listen_address.parse::<IpAddr>() {
+            Ok(listen_address) => listen_address,
+            Err(_) => {
+                error!(
+                    "Invalid listen address {} of MQTT server {}: Not an IP address",
+                    listen_address, e.id
+                );
+                running.set(json!(false));
+                return Err(BehaviourCreationError.into());
+            }
+        };
+        let port = match e
+            .as_u64(MqttServerProperties::PORT.as_ref())
+            .and_then(|port| u16::try_from(port).ok())
+        {
+            Some(port) => port,
+            None => {
+                error!("Invalid port of MQTT server {}", e.id);
+                running.set(json!(false));
+                return Err(BehaviourCreationError.into());
+            }
+        };
+        let max_connections = e
+            .as_u64(MqttServerProperties::MAX_CONNECTIONS.as_ref())
+            .unwrap_or(1000) as usize;
+
+        // The listener which accepts the connections is the one which reports the errors
+        let listener = match bind(SocketAddr::new(listen_address, port)) {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!(
+                    "MQTT server {} can't listen on {}:{}: {}",
+                    e.id, listen_address, port, err
+                );
+                running.set(json!(false));
+                return Err(BehaviourCreationError.into());
+            }
+        };
+        let listen = match listener.local_addr() {
+            Ok(listen) => listen,
+            Err(err) => {
+                error!("MQTT server {} can't listen: {}", e.id, err);
+                running.set(json!(false));
+                return Err(BehaviourCreationError.into());
+            }
+        };
+        if port != listen.port() {
+            if let Some(property) = e.properties.get(MqttServerProperties::PORT.as_ref()) {
+                property.set(json!(listen.port()));
+            }
+        }
+
+        let broker_address = match embedded_broker() {
+            Ok(broker_address) => broker_address,
+            Err(err) => {
+                error!("MQTT server {} can't start the broker: {}", listen, err);
+                running.set(json!(false));
+                return Err(BehaviourCreationError.into());
+            }
+        };
+
+        let shutdown = Arc::new(Notify::new());
+
+        running.set(json!(true));
+
+        // tokio requires a runtime, so the listener runs in its own thread
+        let listener_shutdown = shutdown.clone();
+        let thread_name = format!("{}-{}", e.type_name.clone(), e.id.to_string());
+        let listener_thread = thread::Builder::new().name(thread_name).spawn(move || {
+            match runtime::Builder::new_current_thread().enable_all().build() {
+                // Dropping the runtime closes the forwarded connections
+                Ok(runtime) => runtime.block_on(forward(
+                    listener,
+                    broker_address,
+                    max_connections,
+                    listener_shutdown,
+                )),
+                Err(err) => error!(
+                    "Failed to create runtime for MQTT server {}: {}",
+                    listen, err
+                ),
+            }
+        });
+        let listener_thread = match listener_thread {
+            Ok(listener_thread) => listener_thread,
+            Err(err) => {
+                error!("Failed to start MQTT server {}: {}", listen, err);
+                running.set(json!(false));
+                return Err(BehaviourCreationError.into());
+            }
+        };
+        debug!(
+            "Started MQTT server {} for up to {} connections",
+            listen, max_connections
+        );
+
+        Ok(MqttServer {
+            entity: e.clone(),
+            handle_id,
+            listen,
+            shutdown,
+            listener_thread: Mutex::new(Some(listener_thread)),
+        })
+    }
+
+    pub fn type_name(&self) -> String {
+        self.entity.type_name.clone()
+    }
+}
+
+/// Returns the address of the embedded broker of the process and starts the broker on first use.
+/// The broker keeps running until the process exits.
+fn embedded_broker() -> std::io::Result<SocketAddr> {
+    let mut embedded_broker = EMBEDDED_BROKER.lock().unwrap();
+    if let Some(broker_address) = *embedded_broker {
+        return Ok(broker_address);
+    }
+    // rumqttd binds the port itself, so a free port is probed once per process
+    let broker_address = bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))?.local_addr()?;
+    let mut broker = Broker::new(create_config(broker_address));
+    thread::Builder::new()
+        .name(String::from("mqtt_server-broker"))
+        .spawn(move || {
+            if let Err(err) = broker.start() {
+                error!("Embedded MQTT broker {} failed: {}", broker_address, err);
+            }
+            // The broker only returns if it failed, so the next mqtt_server starts another one
+            let mut embedded_broker = EMBEDDED_BROKER.lock().unwrap();
+            if *embedded_broker == Some(broker_address) {
+                *embedded_broker = None;
+            }
+        })?;
+    debug!("Started embedded MQTT broker {}", broker_address);
+    *embedded_broker = Some(broker_address);
+    Ok(broker_address)
+}
+
+/// Binds a non blocking listener, which can be handed over to the runtime.
+fn bind(address: SocketAddr) -> std::io::Result<std::net::TcpListener> {
+    let listener = std::net::TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Accepts connections until the server is stopped and forwards each connection to the broker.
+/// Connections beyond the maximum number of connections are closed right away.
+async fn forward(
+    listener: std::net::TcpListener,
+    broker_address: SocketAddr,
+    max_connections: usize,
+    shutdown: Arc<Notify>,
+) {
+    let connections = Arc::new(Semaphore::new(max_connections));
+    let listener = match TcpListener::from_std(listener) {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("MQTT server can't accept connections: {}", err);
+            return;
+        }
+    };
+    loop {
+        let accepted = tokio::select! {
+            _ = shutdown.notified() => break,
+            accepted = listener.accept() => accepted,
+        };
+        let (mut stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                // For example if the process ran out of file descriptors
+                error!("MQTT server failed to accept a connection: {}", err);
+                time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let connection = match connections.clone().try_acquire_owned() {
+            Ok(connection) => connection,
+            Err(_) => {
+                error!(
+                    "MQTT server refused the connection of {}: More than {} connections",
+                    peer, max_connections
+                );
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            // The connection is released when the forwarding ends
+            let _connection = connection;
+            let mut broker_stream = match connect(broker_address).await {
+                Ok(broker_stream) => broker_stream,
+                Err(err) => {
+                    error!(
+                        "MQTT server can't forward the connection of {}: {}",
+                        peer, err
+                    );
+                    return;
+                }
+            };
+            // MQTT packets are small, they shouldn't wait for more data
+            let _ = stream.set_nodelay(true);
+            let _ = broker_stream.set_nodelay(true);
+            let _ = copy_bidirectional(&mut stream, &mut broker_stream).await;
+            debug!("MQTT server closed the connection of {}", peer);
+        });
+    }
+}
+
+/// Connects to the embedded broker, which may still be starting.
+async fn connect(broker_address: SocketAddr) -> std::io::Result<TcpStream> {
+    let mut attempts = 0;
+    loop {
+        match TcpStream::connect(broker_address).await {
+            Err(err) if err.kind() == ErrorKind::ConnectionRefused && attempts < 50 => {
+                attempts += 1;
+                time::sleep(Duration::from_millis(20)).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Configures a single listener for MQTT 3.1.1 connections. The console of rumqttd listens on
+/// an arbitrary port of the loopback interface.
+fn create_config(listen: SocketAddr) -> Config {
+    let name = String::from("embedded");
+    let mut v4 = HashMap::new();
+    v4.insert(
+        name.clone(),
+        ServerSettings {
+            name,
+            listen,
+            tls: None,
+            next_connection_delay_ms: 1,
+            connections: ConnectionSettings {
+                connection_timeout_ms: 60000,
+                throttle_delay_ms: 0,
+                // The default max packet size of a mqtt_broker
+                max_payload_size: 256 * 1024,
+                max_inflight_count: 500,
+                max_inflight_size: 1024,
+                auth: None,
+                dynamic_filters: true,
+            },
+        },
+    );
+    let mut console = ConsoleSettings::default();
+    console.listen = String::from("127.0.0.1:0");
+    Config {
+        id: 0,
+        router: RouterConfig {
+            instant_ack: true,
+            max_segment_size: 104857600,
+            max_segment_count: 10,
+            max_read_len: 10240,
+            max_connections: EMBEDDED_BROKER_MAX_CONNECTIONS,
+            initialized_filters: None,
+        },
+        v4,
+        console,
+        ..Default::default()
+    }
+}
+
+impl Disconnectable for MqttServer {
+    fn disconnect(&self) {
+        debug!("Disconnecting mqtt server {}", self.handle_id);
+        self.shutdown.notify_one();
+        // Waiting releases the listen address before a new mqtt_server is created
+        let listener_thread = self.listener_thread.lock().unwrap().take();
+        if let Some(listener_thread) = listener_thread {
+            let _ = listener_thread.join();
+            if let Some(running) = self
+                .entity
+                .properties
+                .get(MqttServerProperties::RUNNING.as_ref())
+            {
+                running.set(json!(false));
+            }
+        }
+    }
+}
+
+/// Automatically disconnect streams on destruction
+impl Drop for MqttServer {
+    fn drop(&mut self) {
+        self.disconnect();
+    }
+}
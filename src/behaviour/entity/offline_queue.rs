@@ -0,0 +1,127 @@
+use std::collections::VecDeque;
+
+use log::debug;
+use log::error;
+use rumqttc::QoS;
+
+use crate::behaviour::components::MqttMessageProperties;
+use crate::behaviour::entity::client::MqttClient;
+
+/// A message which has been published by a mqtt_publisher.
+pub struct MqttPublishRequest {
+    pub topic: String,
+
+    pub qos: QoS,
+
+    pub retain: bool,
+
+    pub payload: Vec<u8>,
+
+    pub properties: MqttMessageProperties,
+}
+
+/// Bounded queue of the messages which are published while the broker is disconnected.
+///
+/// The messages are published in order as soon as the connection has been (re-)established. If the
+/// queue is full, the oldest message is dropped.
+pub struct MqttOfflineQueue {
+    requests: VecDeque<MqttPublishRequest>,
+
+    /// The maximum number of queued messages or 0 to disable the queue
+    capacity: usize,
+
+    /// The client of the established connection
+    client: Option<MqttClient>,
+}
+
+impl MqttOfflineQueue {
+    pub fn new(capacity: usize) -> Self {
+        MqttOfflineQueue {
+            requests: VecDeque::new(),
+            capacity,
+            client: None,
+        }
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.requests.len() > capacity {
+            self.drop_oldest();
+        }
+    }
+
+    /// Publishes the message or queues it if it can't be published right now. Never blocks, the
+    /// message is queued if the request channel of the client is full. Returns the message if it
+    /// has been dropped because the queue is disabled.
+    pub fn publish(&mut self, request: MqttPublishRequest) -> Result<(), MqttPublishRequest> {
+        // Queued messages have to be published first
+        self.flush();
+        if let Some(client) = &self.client {
+            if self.requests.is_empty()
+                && client
+                    .try_publish(
+                        request.topic.clone(),
+                        request.qos,
+                        request.retain,
+                        request.payload.clone(),
+                        &request.properties,
+                    )
+                    .is_ok()
+            {
+                return Ok(());
+            }
+        }
+        if self.capacity == 0 {
+            return Err(request);
+        }
+        if self.requests.len() >= self.capacity {
+            self.drop_oldest();
+        }
+        debug!("Queued message to topic {}", request.topic);
+        self.requests.push_back(request);
+        Ok(())
+    }
+
+    /// Publishes as many queued messages as the request channel of the client accepts.
+    ///
+    /// Uses try_publish because this is also called by the event loop which drains the request
+    /// channel.
+    pub fn flush(&mut self) {
+        let client = match &self.client {
+            Some(client) => client,
+            None => return,
+        };
+        while let Some(request) = self.requests.pop_front() {
+            let result = client.try_publish(
+                request.topic.clone(),
+                request.qos,
+                request.retain,
+                request.payload.clone(),
+                &request.properties,
+            );
+            if result.is_err() {
+                self.requests.push_front(request);
+                return;
+            }
+            debug!("Published queued message to topic {}", request.topic);
+        }
+    }
+
+    pub fn connected(&mut self, client: MqttClient) {
+        self.client = Some(client);
+        self.flush();
+    }
+
+    pub fn disconnected(&mut self) {
+        self.client = None;
+    }
+
+    fn drop_oldest(&mut self) {
+        if let Some(request) = self.requests.pop_front() {
+            error!(
+                "Dropped queued message to topic {}: The offline queue is full",
+                request.topic
+            );
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use indradb::{Identifier, NamedProperty};
 use inexor_rgf_core_reactive::NamedProperties;
 use serde_json::json;
+use serde_json::Value;
 use strum_macros::{AsRefStr, Display, IntoStaticStr};
 
 #[allow(non_camel_case_types)]
@@ -10,27 +11,215 @@ pub enum MqttBrokerProperties {
     HOSTNAME,
     #[strum(serialize = "port")]
     PORT,
+    #[strum(serialize = "transport")]
+    TRANSPORT,
+    #[strum(serialize = "path")]
+    PATH,
+    #[strum(serialize = "username")]
+    USERNAME,
+    #[strum(serialize = "password")]
+    PASSWORD,
+    #[strum(serialize = "password_file")]
+    PASSWORD_FILE,
+    #[strum(serialize = "tls")]
+    TLS,
+    #[strum(serialize = "ca_certificate")]
+    CA_CERTIFICATE,
+    #[strum(serialize = "client_certificate")]
+    CLIENT_CERTIFICATE,
+    #[strum(serialize = "client_key")]
+    CLIENT_KEY,
+    #[strum(serialize = "insecure_skip_verify")]
+    INSECURE_SKIP_VERIFY,
+    #[strum(serialize = "protocol_version")]
+    PROTOCOL_VERSION,
+    #[strum(serialize = "client_id")]
+    CLIENT_ID,
+    #[strum(serialize = "keep_alive")]
+    KEEP_ALIVE,
+    #[strum(serialize = "clean_session")]
+    CLEAN_SESSION,
+    #[strum(serialize = "max_inflight")]
+    MAX_INFLIGHT,
+    #[strum(serialize = "max_packet_size")]
+    MAX_PACKET_SIZE,
+    #[strum(serialize = "topic_alias_max")]
+    TOPIC_ALIAS_MAX,
+    #[strum(serialize = "request_channel_capacity")]
+    REQUEST_CHANNEL_CAPACITY,
+    #[strum(serialize = "offline_queue_size")]
+    OFFLINE_QUEUE_SIZE,
+    #[strum(serialize = "subscribe_all")]
+    SUBSCRIBE_ALL,
+    #[strum(serialize = "reconnect_initial_delay")]
+    RECONNECT_INITIAL_DELAY,
+    #[strum(serialize = "reconnect_max_delay")]
+    RECONNECT_MAX_DELAY,
+    #[strum(serialize = "reconnect_jitter")]
+    RECONNECT_JITTER,
+    #[strum(serialize = "reconnect_max_attempts")]
+    RECONNECT_MAX_ATTEMPTS,
+    #[strum(serialize = "reconnect_give_up")]
+    RECONNECT_GIVE_UP,
+    #[strum(serialize = "will_topic")]
+    WILL_TOPIC,
+    #[strum(serialize = "will_payload")]
+    WILL_PAYLOAD,
+    #[strum(serialize = "will_qos")]
+    WILL_QOS,
+    #[strum(serialize = "will_retain")]
+    WILL_RETAIN,
+    #[strum(serialize = "birth_topic")]
+    BIRTH_TOPIC,
+    #[strum(serialize = "birth_payload")]
+    BIRTH_PAYLOAD,
+    #[strum(serialize = "birth_qos")]
+    BIRTH_QOS,
+    #[strum(serialize = "birth_retain")]
+    BIRTH_RETAIN,
     #[strum(serialize = "send_package")]
     SEND_PACKAGE,
     #[strum(serialize = "received_package")]
     RECEIVED_PACKAGE,
+    #[strum(serialize = "connected")]
+    CONNECTED,
+    #[strum(serialize = "last_error")]
+    LAST_ERROR,
+    #[strum(serialize = "connected_since")]
+    CONNECTED_SINCE,
+    #[strum(serialize = "reconnect_attempts")]
+    RECONNECT_ATTEMPTS,
+    #[strum(serialize = "reconnect_delay")]
+    RECONNECT_DELAY,
 }
 
 impl MqttBrokerProperties {
-    pub fn default_value(&self) -> String {
+    pub fn default_value(&self) -> Value {
         match self {
-            MqttBrokerProperties::HOSTNAME => String::from("localhost"),
-            MqttBrokerProperties::PORT => String::from("1833"), // TODO: i64
-            MqttBrokerProperties::SEND_PACKAGE => String::from("{}"),
-            MqttBrokerProperties::RECEIVED_PACKAGE => String::from("{}"),
+            MqttBrokerProperties::HOSTNAME => json!("localhost"),
+            MqttBrokerProperties::PORT => json!(1883),
+            MqttBrokerProperties::TRANSPORT => json!(""),
+            MqttBrokerProperties::PATH => json!("/mqtt"),
+            MqttBrokerProperties::USERNAME => json!(""),
+            MqttBrokerProperties::PASSWORD => json!(""),
+            MqttBrokerProperties::PASSWORD_FILE => json!(""),
+            MqttBrokerProperties::TLS => json!(false),
+            MqttBrokerProperties::CA_CERTIFICATE => json!(""),
+            MqttBrokerProperties::CLIENT_CERTIFICATE => json!(""),
+            MqttBrokerProperties::CLIENT_KEY => json!(""),
+            MqttBrokerProperties::INSECURE_SKIP_VERIFY => json!(false),
+            MqttBrokerProperties::PROTOCOL_VERSION => json!(4),
+            MqttBrokerProperties::CLIENT_ID => json!(""),
+            MqttBrokerProperties::KEEP_ALIVE => json!(60),
+            MqttBrokerProperties::CLEAN_SESSION => json!(true),
+            MqttBrokerProperties::MAX_INFLIGHT => json!(100),
+            MqttBrokerProperties::MAX_PACKET_SIZE => json!(262144),
+            MqttBrokerProperties::TOPIC_ALIAS_MAX => json!(10),
+            MqttBrokerProperties::REQUEST_CHANNEL_CAPACITY => json!(10),
+            MqttBrokerProperties::OFFLINE_QUEUE_SIZE => json!(100),
+            MqttBrokerProperties::SUBSCRIBE_ALL => json!(false),
+            MqttBrokerProperties::RECONNECT_INITIAL_DELAY => json!(1000),
+            MqttBrokerProperties::RECONNECT_MAX_DELAY => json!(60000),
+            MqttBrokerProperties::RECONNECT_JITTER => json!(500),
+            MqttBrokerProperties::RECONNECT_MAX_ATTEMPTS => json!(0),
+            MqttBrokerProperties::RECONNECT_GIVE_UP => json!("stop"),
+            MqttBrokerProperties::WILL_TOPIC => json!(""),
+            MqttBrokerProperties::WILL_PAYLOAD => json!(""),
+            MqttBrokerProperties::WILL_QOS => json!(0),
+            MqttBrokerProperties::WILL_RETAIN => json!(false),
+            MqttBrokerProperties::BIRTH_TOPIC => json!(""),
+            MqttBrokerProperties::BIRTH_PAYLOAD => json!(""),
+            MqttBrokerProperties::BIRTH_QOS => json!(0),
+            MqttBrokerProperties::BIRTH_RETAIN => json!(false),
+            MqttBrokerProperties::SEND_PACKAGE => json!({}),
+            MqttBrokerProperties::RECEIVED_PACKAGE => json!({}),
+            MqttBrokerProperties::CONNECTED => json!(false),
+            MqttBrokerProperties::LAST_ERROR => json!(""),
+            MqttBrokerProperties::CONNECTED_SINCE => json!(0),
+            MqttBrokerProperties::RECONNECT_ATTEMPTS => json!(0),
+            MqttBrokerProperties::RECONNECT_DELAY => json!(0),
         }
     }
     pub fn properties() -> NamedProperties {
         vec![
             NamedProperty::from(MqttBrokerProperties::HOSTNAME),
             NamedProperty::from(MqttBrokerProperties::PORT),
+            NamedProperty::from(MqttBrokerProperties::TRANSPORT),
+            NamedProperty::from(MqttBrokerProperties::PATH),
+            NamedProperty::from(MqttBrokerProperties::USERNAME),
+            NamedProperty::from(MqttBrokerProperties::PASSWORD),
+            NamedProperty::from(MqttBrokerProperties::PASSWORD_FILE),
+            NamedProperty::from(MqttBrokerProperties::TLS),
+            NamedProperty::from(MqttBrokerProperties::CA_CERTIFICATE),
+            NamedProperty::from(MqttBrokerProperties::CLIENT_CERTIFICATE),
+            NamedProperty::from(MqttBrokerProperties::CLIENT_KEY),
+            NamedProperty::from(MqttBrokerProperties::INSECURE_SKIP_VERIFY),
+            NamedProperty::from(MqttBrokerProperties::PROTOCOL_VERSION),
+            NamedProperty::from(MqttBrokerProperties::CLIENT_ID),
+            NamedProperty::from(MqttBrokerProperties::KEEP_ALIVE),
+            NamedProperty::from(MqttBrokerProperties::CLEAN_SESSION),
+            NamedProperty::from(MqttBrokerProperties::MAX_INFLIGHT),
+            NamedProperty::from(MqttBrokerProperties::MAX_PACKET_SIZE),
+            NamedProperty::from(MqttBrokerProperties::TOPIC_ALIAS_MAX),
+            NamedProperty::from(MqttBrokerProperties::REQUEST_CHANNEL_CAPACITY),
+            NamedProperty::from(MqttBrokerProperties::OFFLINE_QUEUE_SIZE),
+            NamedProperty::from(MqttBrokerProperties::SUBSCRIBE_ALL),
+            NamedProperty::from(MqttBrokerProperties::RECONNECT_INITIAL_DELAY),
+            NamedProperty::from(MqttBrokerProperties::RECONNECT_MAX_DELAY),
+            NamedProperty::from(MqttBrokerProperties::RECONNECT_JITTER),
+            NamedProperty::from(MqttBrokerProperties::RECONNECT_MAX_ATTEMPTS),
+            NamedProperty::from(MqttBrokerProperties::RECONNECT_GIVE_UP),
+            NamedProperty::from(MqttBrokerProperties::WILL_TOPIC),
+            NamedProperty::from(MqttBrokerProperties::WILL_PAYLOAD),
+            NamedProperty::from(MqttBrokerProperties::WILL_QOS),
+            NamedProperty::from(MqttBrokerProperties::WILL_RETAIN),
+            NamedProperty::from(MqttBrokerProperties::BIRTH_TOPIC),
+            NamedProperty::from(MqttBrokerProperties::BIRTH_PAYLOAD),
+            NamedProperty::from(MqttBrokerProperties::BIRTH_QOS),
+            NamedProperty::from(MqttBrokerProperties::BIRTH_RETAIN),
             NamedProperty::from(MqttBrokerProperties::SEND_PACKAGE),
             NamedProperty::from(MqttBrokerProperties::RECEIVED_PACKAGE),
+            NamedProperty::from(MqttBrokerProperties::CONNECTED),
+            NamedProperty::from(MqttBrokerProperties::LAST_ERROR),
+            NamedProperty::from(MqttBrokerProperties::CONNECTED_SINCE),
+            NamedProperty::from(MqttBrokerProperties::RECONNECT_ATTEMPTS),
+            NamedProperty::from(MqttBrokerProperties::RECONNECT_DELAY),
+        ]
+    }
+    /// The properties which configure the connection. Changing one of them reconnects the broker.
+    pub fn connection_properties() -> Vec<MqttBrokerProperties> {
+        vec![
+            MqttBrokerProperties::HOSTNAME,
+            MqttBrokerProperties::PORT,
+            MqttBrokerProperties::USERNAME,
+            MqttBrokerProperties::PASSWORD,
+            MqttBrokerProperties::PASSWORD_FILE,
+            MqttBrokerProperties::TLS,
+            MqttBrokerProperties::CA_CERTIFICATE,
+            MqttBrokerProperties::CLIENT_CERTIFICATE,
+            MqttBrokerProperties::CLIENT_KEY,
+            MqttBrokerProperties::INSECURE_SKIP_VERIFY,
+            MqttBrokerProperties::SUBSCRIBE_ALL,
+            MqttBrokerProperties::RECONNECT_INITIAL_DELAY,
+            MqttBrokerProperties::RECONNECT_MAX_DELAY,
+            MqttBrokerProperties::RECONNECT_JITTER,
+            MqttBrokerProperties::RECONNECT_MAX_ATTEMPTS,
+            MqttBrokerProperties::RECONNECT_GIVE_UP,
+            MqttBrokerProperties::WILL_TOPIC,
+            MqttBrokerProperties::WILL_PAYLOAD,
+            MqttBrokerProperties::WILL_QOS,
+            MqttBrokerProperties::WILL_RETAIN,
+            MqttBrokerProperties::CLIENT_ID,
+            MqttBrokerProperties::KEEP_ALIVE,
+            MqttBrokerProperties::CLEAN_SESSION,
+            MqttBrokerProperties::MAX_INFLIGHT,
+            MqttBrokerProperties::MAX_PACKET_SIZE,
+            MqttBrokerProperties::REQUEST_CHANNEL_CAPACITY,
+            MqttBrokerProperties::OFFLINE_QUEUE_SIZE,
+            MqttBrokerProperties::PROTOCOL_VERSION,
+            MqttBrokerProperties::TOPIC_ALIAS_MAX,
+            MqttBrokerProperties::TRANSPORT,
+            MqttBrokerProperties::PATH,
         ]
     }
 }
@@ -39,7 +228,7 @@ impl From<MqttBrokerProperties> for NamedProperty {
     fn from(p: MqttBrokerProperties) -> Self {
         NamedProperty {
             name: Identifier::new(p.to_string()).unwrap(),
-            value: json!(p.default_value()),
+            value: p.default_value(),
         }
     }
 }
@@ -49,3 +238,297 @@ impl From<MqttBrokerProperties> for String {
         p.to_string()
     }
 }
+
+#[allow(non_camel_case_types)]
+#[derive(AsRefStr, IntoStaticStr, Display)]
+pub enum MqttPublisherProperties {
+    #[strum(serialize = "clear_retained")]
+    CLEAR_RETAINED,
+    #[strum(serialize = "user_properties")]
+    USER_PROPERTIES,
+    #[strum(serialize = "content_type")]
+    CONTENT_TYPE,
+    #[strum(serialize = "message_expiry")]
+    MESSAGE_EXPIRY,
+}
+
+impl MqttPublisherProperties {
+    pub fn default_value(&self) -> Value {
+        match self {
+            MqttPublisherProperties::CLEAR_RETAINED => json!(false),
+            MqttPublisherProperties::USER_PROPERTIES => json!({}),
+            MqttPublisherProperties::CONTENT_TYPE => json!(""),
+            MqttPublisherProperties::MESSAGE_EXPIRY => json!(0),
+        }
+    }
+    pub fn properties() -> NamedProperties {
+        vec![
+            NamedProperty::from(MqttPublisherProperties::CLEAR_RETAINED),
+            NamedProperty::from(MqttPublisherProperties::USER_PROPERTIES),
+            NamedProperty::from(MqttPublisherProperties::CONTENT_TYPE),
+            NamedProperty::from(MqttPublisherProperties::MESSAGE_EXPIRY),
+        ]
+    }
+}
+
+impl From<MqttPublisherProperties> for NamedProperty {
+    fn from(p: MqttPublisherProperties) -> Self {
+        NamedProperty {
+            name: Identifier::new(p.to_string()).unwrap(),
+            value: p.default_value(),
+        }
+    }
+}
+
+impl From<MqttPublisherProperties> for String {
+    fn from(p: MqttPublisherProperties) -> Self {
+        p.to_string()
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(AsRefStr, IntoStaticStr, Display)]
+pub enum MqttSubscriberProperties {
+    #[strum(serialize = "last_topic")]
+    LAST_TOPIC,
+    #[strum(serialize = "captures")]
+    CAPTURES,
+    #[strum(serialize = "error")]
+    ERROR,
+    #[strum(serialize = "user_properties")]
+    USER_PROPERTIES,
+    #[strum(serialize = "content_type")]
+    CONTENT_TYPE,
+}
+
+impl MqttSubscriberProperties {
+    pub fn default_value(&self) -> Value {
+        match self {
+            MqttSubscriberProperties::LAST_TOPIC => json!(""),
+            MqttSubscriberProperties::CAPTURES => json!([]),
+            MqttSubscriberProperties::ERROR => json!(""),
+            MqttSubscriberProperties::USER_PROPERTIES => json!({}),
+            MqttSubscriberProperties::CONTENT_TYPE => json!(""),
+        }
+    }
+    pub fn properties() -> NamedProperties {
+        vec![
+            NamedProperty::from(MqttSubscriberProperties::LAST_TOPIC),
+            NamedProperty::from(MqttSubscriberProperties::CAPTURES),
+            NamedProperty::from(MqttSubscriberProperties::ERROR),
+            NamedProperty::from(MqttSubscriberProperties::USER_PROPERTIES),
+            NamedProperty::from(MqttSubscriberProperties::CONTENT_TYPE),
+        ]
+    }
+}
+
+impl From<MqttSubscriberProperties> for NamedProperty {
+    fn from(p: MqttSubscriberProperties) -> Self {
+        NamedProperty {
+            name: Identifier::new(p.to_string()).unwrap(),
+            value: p.default_value(),
+        }
+    }
+}
+
+impl From<MqttSubscriberProperties> for String {
+    fn from(p: MqttSubscriberProperties) -> Self {
+        p.to_string()
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(AsRefStr, IntoStaticStr, Display)]
+pub enum MqttServerProperties {
+    #[strum(serialize = "listen_address")]
+    LISTEN_ADDRESS,
+    #[strum(serialize = "port")]
+    PORT,
+    #[strum(serialize = "max_connections")]
+    MAX_CONNECTIONS,
+    #[strum(serialize = "running")]
+    RUNNING,
+}
+
+impl MqttServerProperties {
+    pub fn default_value(&self) -> Value {
+        match self {
+            MqttServerProperties::LISTEN_ADDRESS => json!("0.0.0.0"),
+            MqttServerProperties::PORT => json!(1883),
+            MqttServerProperties::MAX_CONNECTIONS => json!(1000),
+            MqttServerProperties::RUNNING => json!(false),
+        }
+    }
+    pub fn properties() -> NamedProperties {
+        vec![
+            NamedProperty::from(MqttServerProperties::LISTEN_ADDRESS),
+            NamedProperty::from(MqttServerProperties::PORT),
+            NamedProperty::from(MqttServerProperties::MAX_CONNECTIONS),
+            NamedProperty::from(MqttServerProperties::RUNNING),
+        ]
+    }
+}
+
+impl From<MqttServerProperties> for NamedProperty {
+    fn from(p: MqttServerProperties) -> Self {
+        NamedProperty {
+            name: Identifier::new(p.to_string()).unwrap(),
+            value: p.default_value(),
+        }
+    }
+}
+
+impl From<MqttServerProperties> for String {
+    fn from(p: MqttServerProperties) -> Self {
+        p.to_string()
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(AsRefStr, IntoStaticStr, Display)]
+pub enum MqttHaDiscoveryProperties {
+    #[strum(serialize = "broker")]
+    BROKER,
+    #[strum(serialize = "discovery_prefix")]
+    DISCOVERY_PREFIX,
+    #[strum(serialize = "discovered")]
+    DISCOVERED,
+}
+
+impl MqttHaDiscoveryProperties {
+    pub fn default_value(&self) -> Value {
+        match self {
+            MqttHaDiscoveryProperties::BROKER => json!(""),
+            MqttHaDiscoveryProperties::DISCOVERY_PREFIX => json!("homeassistant"),
+            MqttHaDiscoveryProperties::DISCOVERED => json!(0),
+        }
+    }
+    pub fn properties() -> NamedProperties {
+        vec![
+            NamedProperty::from(MqttHaDiscoveryProperties::BROKER),
+            NamedProperty::from(MqttHaDiscoveryProperties::DISCOVERY_PREFIX),
+            NamedProperty::from(MqttHaDiscoveryProperties::DISCOVERED),
+        ]
+    }
+}
+
+impl From<MqttHaDiscoveryProperties> for NamedProperty {
+    fn from(p: MqttHaDiscoveryProperties) -> Self {
+        NamedProperty {
+            name: Identifier::new(p.to_string()).unwrap(),
+            value: p.default_value(),
+        }
+    }
+}
+
+impl From<MqttHaDiscoveryProperties> for String {
+    fn from(p: MqttHaDiscoveryProperties) -> Self {
+        p.to_string()
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(AsRefStr, IntoStaticStr, Display)]
+pub enum HomieDeviceProperties {
+    #[strum(serialize = "broker")]
+    BROKER,
+    #[strum(serialize = "base_topic")]
+    BASE_TOPIC,
+    #[strum(serialize = "device_id")]
+    DEVICE_ID,
+    #[strum(serialize = "name")]
+    NAME,
+    #[strum(serialize = "state")]
+    STATE,
+    #[strum(serialize = "nodes")]
+    NODES,
+}
+
+impl HomieDeviceProperties {
+    pub fn default_value(&self) -> Value {
+        match self {
+            HomieDeviceProperties::BROKER => json!(""),
+            HomieDeviceProperties::BASE_TOPIC => json!("homie"),
+            HomieDeviceProperties::DEVICE_ID => json!(""),
+            HomieDeviceProperties::NAME => json!(""),
+            HomieDeviceProperties::STATE => json!(""),
+            HomieDeviceProperties::NODES => json!([]),
+        }
+    }
+    pub fn properties() -> NamedProperties {
+        vec![
+            NamedProperty::from(HomieDeviceProperties::BROKER),
+            NamedProperty::from(HomieDeviceProperties::BASE_TOPIC),
+            NamedProperty::from(HomieDeviceProperties::DEVICE_ID),
+            NamedProperty::from(HomieDeviceProperties::NAME),
+            NamedProperty::from(HomieDeviceProperties::STATE),
+            NamedProperty::from(HomieDeviceProperties::NODES),
+        ]
+    }
+}
+
+impl From<HomieDeviceProperties> for NamedProperty {
+    fn from(p: HomieDeviceProperties) -> Self {
+        NamedProperty {
+            name: Identifier::new(p.to_string()).unwrap(),
+            value: p.default_value(),
+        }
+    }
+}
+
+impl From<HomieDeviceProperties> for String {
+    fn from(p: HomieDeviceProperties) -> Self {
+        p.to_string()
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(AsRefStr, IntoStaticStr, Display)]
+pub enum HomieNodeProperties {
+    #[strum(serialize = "device_id")]
+    DEVICE_ID,
+    #[strum(serialize = "node_id")]
+    NODE_ID,
+    #[strum(serialize = "name")]
+    NAME,
+    #[strum(serialize = "type")]
+    TYPE,
+    #[strum(serialize = "properties")]
+    PROPERTIES,
+}
+
+impl HomieNodeProperties {
+    pub fn default_value(&self) -> Value {
+        match self {
+            HomieNodeProperties::DEVICE_ID => json!(""),
+            HomieNodeProperties::NODE_ID => json!(""),
+            HomieNodeProperties::NAME => json!(""),
+            HomieNodeProperties::TYPE => json!(""),
+            HomieNodeProperties::PROPERTIES => json!({}),
+        }
+    }
+    pub fn properties() -> NamedProperties {
+        vec![
+            NamedProperty::from(HomieNodeProperties::DEVICE_ID),
+            NamedProperty::from(HomieNodeProperties::NODE_ID),
+            NamedProperty::from(HomieNodeProperties::NAME),
+            NamedProperty::from(HomieNodeProperties::TYPE),
+            NamedProperty::from(HomieNodeProperties::PROPERTIES),
+        ]
+    }
+}
+
+impl From<HomieNodeProperties> for NamedProperty {
+    fn from(p: HomieNodeProperties) -> Self {
+        NamedProperty {
+            name: Identifier::new(p.to_string()).unwrap(),
+            value: p.default_value(),
+        }
+    }
+}
+
+impl From<HomieNodeProperties> for String {
+    fn from(p: HomieNodeProperties) -> Self {
+        p.to_string()
+    }
+}
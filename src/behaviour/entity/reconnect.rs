@@ -0,0 +1,103 @@
+use std::convert::AsRef;
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::behaviour::entity::MqttBrokerProperties;
+use crate::model::PropertyInstanceGetter;
+use crate::model::ReactiveEntityInstance;
+
+/// What happens after the maximum number of reconnect attempts has been reached.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MqttReconnectGiveUp {
+    /// Stops the event loop. Publishing fails until the configuration of the broker changes or
+    /// the broker is recreated.
+    Stop,
+    /// Starts over with the initial delay.
+    Reset,
+}
+
+impl From<&str> for MqttReconnectGiveUp {
+    fn from(give_up: &str) -> Self {
+        match give_up {
+            "reset" => MqttReconnectGiveUp::Reset,
+            _ => MqttReconnectGiveUp::Stop,
+        }
+    }
+}
+
+/// Exponential backoff between the reconnect attempts of a broker.
+#[derive(Clone, Debug)]
+pub struct MqttReconnectPolicy {
+    /// The delay before the first reconnect attempt in milliseconds
+    pub initial_delay: u64,
+
+    /// The upper bound of the delay in milliseconds
+    pub max_delay: u64,
+
+    /// The maximum random delay in milliseconds which is added to each delay
+    pub jitter: u64,
+
+    /// The maximum number of reconnect attempts or 0 for unlimited attempts
+    pub max_attempts: u64,
+
+    pub give_up: MqttReconnectGiveUp,
+}
+
+impl MqttReconnectPolicy {
+    pub fn new(e: &ReactiveEntityInstance) -> Self {
+        let initial_delay = e
+            .as_u64(MqttBrokerProperties::RECONNECT_INITIAL_DELAY.as_ref())
+            .unwrap_or(1000);
+        let max_delay = e
+            .as_u64(MqttBrokerProperties::RECONNECT_MAX_DELAY.as_ref())
+            .unwrap_or(60000)
+            .max(initial_delay);
+        let jitter = e
+            .as_u64(MqttBrokerProperties::RECONNECT_JITTER.as_ref())
+            .unwrap_or(500);
+        let max_attempts = e
+            .as_u64(MqttBrokerProperties::RECONNECT_MAX_ATTEMPTS.as_ref())
+            .unwrap_or(0);
+        let give_up = e
+            .as_string(MqttBrokerProperties::RECONNECT_GIVE_UP.as_ref())
+            .unwrap_or_else(|| {
+                MqttBrokerProperties::RECONNECT_GIVE_UP
+                    .default_value()
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string()
+            })
+            .as_str()
+            .into();
+        MqttReconnectPolicy {
+            initial_delay,
+            max_delay,
+            jitter,
+            max_attempts,
+            give_up,
+        }
+    }
+
+    /// Returns the delay before the given reconnect attempt (starting with 1) or none if the
+    /// broker gives up.
+    pub fn delay(&self, attempt: u64) -> Option<Duration> {
+        let mut attempt = attempt.max(1);
+        if self.max_attempts > 0 && attempt > self.max_attempts {
+            match self.give_up {
+                MqttReconnectGiveUp::Stop => return None,
+                MqttReconnectGiveUp::Reset => attempt = (attempt - 1) % self.max_attempts + 1,
+            }
+        }
+        let exponent = u32::try_from(attempt - 1).unwrap_or(u32::MAX).min(63);
+        let delay = self
+            .initial_delay
+            .saturating_mul(1u64 << exponent)
+            .min(self.max_delay);
+        let jitter = match self.jitter {
+            0 => 0,
+            jitter => rand::thread_rng().gen_range(0..=jitter),
+        };
+        Some(Duration::from_millis(delay.saturating_add(jitter)))
+    }
+}
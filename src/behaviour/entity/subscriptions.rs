@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+
+use log::{debug, error};
+use rumqttc::QoS;
+use serde_json::Value;
+
+use crate::behaviour::components::topic_matches;
+use crate::behaviour::entity::client::MqttClient;
+use crate::behaviour::entity::client::MqttClientError;
+
+/// Subscribing this topic filter receives all messages of the broker.
+pub const CATCH_ALL_TOPIC: &str = "#";
+
+/// A SUBSCRIBE or, if the topic isn't used anymore, an UNSUBSCRIBE.
+pub struct MqttSubscriptionRequest {
+    pub topic: String,
+
+    pub qos: Option<QoS>,
+}
+
+impl MqttSubscriptionRequest {
+    pub fn try_send(&self, client: &MqttClient) -> Result<(), MqttClientError> {
+        match self.qos {
+            Some(qos) => client.try_subscribe(self.topic.as_str(), qos),
+            None => client.try_unsubscribe(self.topic.as_str()),
+        }
+        .map(|_| self.sent())
+    }
+
+    pub async fn send(&self, client: &MqttClient) -> Result<(), MqttClientError> {
+        match self.qos {
+            Some(qos) => client.subscribe(self.topic.as_str(), qos).await,
+            None => client.unsubscribe(self.topic.as_str()).await,
+        }
+        .map(|_| self.sent())
+    }
+
+    fn sent(&self) {
+        match self.qos {
+            Some(qos) => debug!("Subscribe topic {} with {:?}", self.topic, qos),
+            None => debug!("Unsubscribe topic {}", self.topic),
+        }
+    }
+}
+
+/// Reference counted set of the topics which are used by mqtt_subscribes relations.
+///
+/// SUBSCRIBE is issued for the first and UNSUBSCRIBE for the last relation of a topic. If
+/// multiple relations subscribe the same topic, the highest QoS of these relations is used.
+///
+/// Changed topics are pending until the request has been sent. Requests which don't fit into the
+/// request channel of the client are retried by the event loop.
+pub struct MqttSubscriptions {
+    /// The number of subscribing relations per topic and QoS level
+    topics: HashMap<String, [usize; 3]>,
+
+    subscribe_all: bool,
+
+    /// The client of the established connection
+    client: Option<MqttClient>,
+
+    /// The topics whose SUBSCRIBE or UNSUBSCRIBE hasn't been sent yet
+    pending: HashSet<String>,
+
+    /// Received packages of topics which no relation has subscribed yet. A persistent session
+    /// delivers the queued messages right after connecting, before the relations are created.
+    undelivered: VecDeque<(String, Value)>,
+
+    /// The maximum number of undelivered packages or 0 to disable holding them back
+    undelivered_capacity: usize,
+}
+
+impl MqttSubscriptions {
+    pub fn new(subscribe_all: bool) -> Self {
+        MqttSubscriptions {
+            topics: HashMap::new(),
+            subscribe_all,
+            client: None,
+            pending: HashSet::new(),
+            undelivered: VecDeque::new(),
+            undelivered_capacity: 0,
+        }
+    }
+
+    pub fn set_undelivered_capacity(&mut self, capacity: usize) {
+        self.undelivered_capacity = capacity;
+        self.undelivered.truncate(capacity);
+    }
+
+    /// Takes effect with the next connection.
+    pub fn set_subscribe_all(&mut self, subscribe_all: bool) {
+        self.subscribe_all = subscribe_all;
+    }
+
+    /// Returns the undelivered packages of the topic.
+    pub fn subscribe(&mut self, topic: &str, qos: QoS) -> Vec<Value> {
+        if topic.is_empty() {
+            return Vec::new();
+        }
+        let counts = self.topics.entry(topic.to_string()).or_insert([0; 3]);
+        let previous_qos = max_qos(counts);
+        counts[qos as usize] += 1;
+        let qos = max_qos(counts);
+        if previous_qos != qos {
+            self.changed(topic);
+        }
+        self.take_undelivered(topic)
+    }
+
+    pub fn unsubscribe(&mut self, topic: &str, qos: QoS) {
+        let counts = match self.topics.get_mut(topic) {
+            Some(counts) => counts,
+            None => return,
+        };
+        if counts[qos as usize] == 0 {
+            return;
+        }
+        let previous_qos = max_qos(counts);
+        counts[qos as usize] -= 1;
+        let qos = max_qos(counts);
+        if qos.is_none() {
+            self.topics.remove(topic);
+        }
+        // Downgrades to the highest QoS of the remaining relations
+        if previous_qos != qos {
+            self.changed(topic);
+        }
+    }
+
+    /// Restores the subscriptions after the connection has been (re-)established.
+    ///
+    /// The UNSUBSCRIBEs of topics which have been released while disconnected are sent as well,
+    /// because a persistent session keeps the subscriptions of the previous connection.
+    pub fn connected(&mut self, client: MqttClient) {
+        if self.subscribe_all {
+            self.pending.clear();
+            self.pending.insert(CATCH_ALL_TOPIC.to_string());
+        } else {
+            let topics = &self.topics;
+            self.pending.retain(|topic| !topics.contains_key(topic));
+            self.pending.extend(self.topics.keys().cloned());
+        }
+        self.client = Some(client);
+        self.flush();
+    }
+
+    /// The pending UNSUBSCRIBEs are kept for the next connection.
+    pub fn disconnected(&mut self) {
+        self.client = None;
+    }
+
+    /// Sends as many pending requests as the request channel of the client accepts.
+    ///
+    /// All pending subscriptions are sent with a single SUBSCRIBE, so restoring the subscriptions
+    /// takes a single slot of the request channel. Uses try_subscribe because this is also called
+    /// by the event loop which drains the request channel.
+    pub fn flush(&mut self) {
+        let client = match &self.client {
+            Some(client) => client.clone(),
+            None => return,
+        };
+        let (subscribes, unsubscribes): (Vec<_>, Vec<_>) = self
+            .pending
+            .iter()
+            .map(|topic| self.request(topic.clone()))
+            .partition(|request| request.qos.is_some());
+        if !subscribes.is_empty() {
+            let topics = subscribes
+                .iter()
+                .filter_map(|request| request.qos.map(|qos| (request.topic.clone(), qos)))
+                .collect();
+            if client.try_subscribe_many(topics).is_err() {
+                return;
+            }
+            for request in subscribes {
+                request.sent();
+                self.pending.remove(&request.topic);
+            }
+        }
+        for request in unsubscribes {
+            if request.try_send(&client).is_err() {
+                return;
+            }
+            self.pending.remove(&request.topic);
+        }
+    }
+
+    /// Takes the pending requests and the client which should send them. The topics of the
+    /// requests which couldn't be sent have to be given back with restore_pending.
+    pub fn take_pending(&mut self) -> Option<(MqttClient, Vec<MqttSubscriptionRequest>)> {
+        let client = self.client.clone()?;
+        let topics: Vec<String> = self.pending.drain().collect();
+        let requests = topics
+            .into_iter()
+            .map(|topic| self.request(topic))
+            .collect();
+        Some((client, requests))
+    }
+
+    /// Keeps the topics pending whose requests couldn't be sent. They are retried by the event
+    /// loop or by the next connection.
+    pub fn restore_pending(&mut self, topics: Vec<String>) {
+        self.pending.extend(topics);
+    }
+
+    fn changed(&mut self, topic: &str) {
+        // The catch all subscription receives the messages of every topic
+        if !self.subscribe_all {
+            self.pending.insert(topic.to_string());
+        }
+    }
+
+    fn request(&self, topic: String) -> MqttSubscriptionRequest {
+        let qos = match self.subscribe_all {
+            true => Some(QoS::AtMostOnce),
+            false => self.topics.get(topic.as_str()).and_then(max_qos),
+        };
+        MqttSubscriptionRequest { topic, qos }
+    }
+
+    /// Holds back the received package if no relation has subscribed the topic. Returns false if
+    /// the package should be delivered.
+    pub fn hold_undelivered(&mut self, topic: &str, package: &Value) -> bool {
+        if self.undelivered_capacity == 0 || self.subscribe_all {
+            return false;
+        }
+        if self
+            .topics
+            .keys()
+            .any(|filter| topic_matches(filter, topic))
+        {
+            return false;
+        }
+        if self.undelivered.len() >= self.undelivered_capacity {
+            if let Some((topic, _)) = self.undelivered.pop_front() {
+                error!("Dropped undelivered message of topic {}", topic);
+            }
+        }
+        debug!("Holding back message of topic {} until subscribed", topic);
+        self.undelivered
+            .push_back((topic.to_string(), package.clone()));
+        true
+    }
+
+    fn take_undelivered(&mut self, filter: &str) -> Vec<Value> {
+        let (matching, undelivered): (VecDeque<_>, VecDeque<_>) = self
+            .undelivered
+            .drain(..)
+            .partition(|(topic, _)| topic_matches(filter, topic));
+        self.undelivered = undelivered;
+        matching.into_iter().map(|(_, package)| package).collect()
+    }
+}
+
+fn max_qos(counts: &[usize; 3]) -> Option<QoS> {
+    counts
+        .iter()
+        .rposition(|count| *count > 0)
+        .and_then(|level| rumqttc::qos(level as u8).ok())
+}
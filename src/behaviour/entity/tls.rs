@@ -0,0 +1,182 @@
+use std::convert::AsRef;
+use std::io::{BufReader, Cursor};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use log::error;
+use rumqttc::tokio_rustls::rustls;
+use rumqttc::tokio_rustls::rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rumqttc::tokio_rustls::rustls::{
+    Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName,
+};
+use rumqttc::TlsConfiguration;
+
+use crate::behaviour::entity::MqttBrokerProperties;
+use crate::model::PropertyInstanceGetter;
+use crate::model::ReactiveEntityInstance;
+
+/// Accepts every server certificate. Only meant for lab setups with self-signed certificates.
+struct NoServerCertVerification;
+
+impl ServerCertVerifier for NoServerCertVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Builds the TLS configuration of the given mqtt_broker entity.
+///
+/// The certificate properties contain either a path to a PEM file or the PEM itself.
+pub fn create_tls_configuration(e: &ReactiveEntityInstance) -> Option<TlsConfiguration> {
+    let ca_certificate = read_pem(e, MqttBrokerProperties::CA_CERTIFICATE)?;
+    let client_certificate = read_pem(e, MqttBrokerProperties::CLIENT_CERTIFICATE)?;
+    let client_key = read_pem(e, MqttBrokerProperties::CLIENT_KEY)?;
+    let client_auth = match (client_certificate, client_key) {
+        (Some(certificate), Some(key)) => Some((certificate, key)),
+        (None, None) => None,
+        _ => {
+            error!(
+                "Mutual TLS requires both {} and {} on mqtt broker {}",
+                MqttBrokerProperties::CLIENT_CERTIFICATE.as_ref(),
+                MqttBrokerProperties::CLIENT_KEY.as_ref(),
+                e.id
+            );
+            return None;
+        }
+    };
+    let insecure_skip_verify = e
+        .as_bool(MqttBrokerProperties::INSECURE_SKIP_VERIFY.as_ref())
+        .unwrap_or(false);
+    let root_certificates = match (insecure_skip_verify, ca_certificate) {
+        (true, _) => RootCertStore::empty(),
+        (false, Some(ca)) => parse_root_certificates(&ca)?,
+        // Use the platform certificates
+        (false, None) => load_native_root_certificates()?,
+    };
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_certificates);
+    let mut config = match client_auth {
+        Some((certificate, key)) => {
+            let certificates = parse_certificates(&certificate)?;
+            let key = parse_private_key(&key)?;
+            match config.with_single_cert(certificates, key) {
+                Ok(config) => config,
+                Err(err) => {
+                    error!("Invalid client certificate or key: {}", err);
+                    return None;
+                }
+            }
+        }
+        None => config.with_no_client_auth(),
+    };
+    if insecure_skip_verify {
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NoServerCertVerification));
+    }
+    Some(TlsConfiguration::Rustls(Arc::new(config)))
+}
+
+/// Returns the PEM of the given property. The outer option is none if the PEM couldn't be read.
+fn read_pem(e: &ReactiveEntityInstance, property: MqttBrokerProperties) -> Option<Option<Vec<u8>>> {
+    let value = e.as_string(property.as_ref()).unwrap_or_default();
+    let value = value.trim();
+    if value.is_empty() {
+        return Some(None);
+    }
+    if value.starts_with("-----BEGIN") {
+        return Some(Some(value.as_bytes().to_vec()));
+    }
+    match std::fs::read(value) {
+        Ok(pem) => Some(Some(pem)),
+        Err(err) => {
+            error!(
+                "Failed to read {} of mqtt broker {} from {}: {}",
+                property.as_ref(),
+                e.id,
+                value,
+                err
+            );
+            None
+        }
+    }
+}
+
+fn parse_root_certificates(pem: &[u8]) -> Option<RootCertStore> {
+    let mut root_certificates = RootCertStore::empty();
+    match rustls_pemfile::certs(&mut BufReader::new(Cursor::new(pem))) {
+        Ok(certificates) => {
+            root_certificates.add_parsable_certificates(&certificates);
+        }
+        Err(err) => {
+            error!("Failed to parse CA certificate: {}", err);
+            return None;
+        }
+    }
+    if root_certificates.is_empty() {
+        error!("No valid certificate found in CA certificate");
+        return None;
+    }
+    Some(root_certificates)
+}
+
+fn load_native_root_certificates() -> Option<RootCertStore> {
+    let mut root_certificates = RootCertStore::empty();
+    match rustls_native_certs::load_native_certs() {
+        Ok(certificates) => {
+            let certificates: Vec<Vec<u8>> = certificates
+                .into_iter()
+                .map(|certificate| certificate.0)
+                .collect();
+            root_certificates.add_parsable_certificates(&certificates);
+        }
+        Err(err) => {
+            error!("Failed to load the platform certificates: {}", err);
+            return None;
+        }
+    }
+    if root_certificates.is_empty() {
+        error!("No valid platform certificate found");
+        return None;
+    }
+    Some(root_certificates)
+}
+
+fn parse_certificates(pem: &[u8]) -> Option<Vec<Certificate>> {
+    match rustls_pemfile::certs(&mut BufReader::new(Cursor::new(pem))) {
+        Ok(certificates) => Some(certificates.into_iter().map(Certificate).collect()),
+        Err(err) => {
+            error!("Failed to parse client certificate: {}", err);
+            None
+        }
+    }
+}
+
+fn parse_private_key(pem: &[u8]) -> Option<PrivateKey> {
+    let mut reader = BufReader::new(Cursor::new(pem));
+    loop {
+        match rustls_pemfile::read_one(&mut reader) {
+            Ok(Some(rustls_pemfile::Item::RSAKey(key)))
+            | Ok(Some(rustls_pemfile::Item::PKCS8Key(key)))
+            | Ok(Some(rustls_pemfile::Item::ECKey(key))) => return Some(PrivateKey(key)),
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                error!("No private key found in client key");
+                return None;
+            }
+            Err(err) => {
+                error!("Failed to parse client key: {}", err);
+                return None;
+            }
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::convert::AsRef;
+
+use log::error;
+use rumqttc::TlsConfiguration;
+use rumqttc::Transport;
+
+use crate::behaviour::entity::tls::create_tls_configuration;
+use crate::behaviour::entity::MqttBrokerProperties;
+use crate::model::PropertyInstanceGetter;
+use crate::model::ReactiveEntityInstance;
+
+/// The transport of the connection to a MQTT broker.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MqttTransport {
+    Tcp,
+    Tls,
+    /// MQTT over WebSockets
+    Ws,
+    /// MQTT over secure WebSockets
+    Wss,
+}
+
+impl MqttTransport {
+    /// Falls back to the property tls if the property transport is empty.
+    pub fn new(e: &ReactiveEntityInstance) -> Result<Self, String> {
+        let transport = e
+            .as_string(MqttBrokerProperties::TRANSPORT.as_ref())
+            .unwrap_or_default();
+        match transport.as_str() {
+            "" => match e
+                .as_bool(MqttBrokerProperties::TLS.as_ref())
+                .unwrap_or(false)
+            {
+                true => Ok(MqttTransport::Tls),
+                false => Ok(MqttTransport::Tcp),
+            },
+            "tcp" => Ok(MqttTransport::Tcp),
+            "tls" => Ok(MqttTransport::Tls),
+            "ws" => Ok(MqttTransport::Ws),
+            "wss" => Ok(MqttTransport::Wss),
+            _ => Err(transport),
+        }
+    }
+}
+
+/// Returns the address of the broker and the transport of rumqttc.
+///
+/// The address of a websocket broker is the URL of the websocket endpoint.
+pub fn create_transport(
+    e: &ReactiveEntityInstance,
+    hostname: &str,
+    port: u16,
+) -> Option<(String, Transport)> {
+    let transport = match MqttTransport::new(e) {
+        Ok(transport) => transport,
+        Err(transport) => {
+            error!(
+                "Unsupported transport {} for MQTT broker {}:{}",
+                transport, hostname, port
+            );
+            return None;
+        }
+    };
+    match transport {
+        MqttTransport::Tcp => Some((hostname.to_string(), Transport::tcp())),
+        MqttTransport::Tls => Some((
+            hostname.to_string(),
+            Transport::tls_with_config(tls_configuration(e, hostname, port)?),
+        )),
+        MqttTransport::Ws => Some((websocket_url(e, "ws", hostname, port), Transport::Ws)),
+        MqttTransport::Wss => Some((
+            websocket_url(e, "wss", hostname, port),
+            Transport::wss_with_config(tls_configuration(e, hostname, port)?),
+        )),
+    }
+}
+
+fn tls_configuration(
+    e: &ReactiveEntityInstance,
+    hostname: &str,
+    port: u16,
+) -> Option<TlsConfiguration> {
+    let tls_configuration = create_tls_configuration(e);
+    if tls_configuration.is_none() {
+        error!(
+            "Failed to configure TLS for MQTT broker {}:{}",
+            hostname, port
+        );
+    }
+    tls_configuration
+}
+
+fn websocket_url(e: &ReactiveEntityInstance, scheme: &str, hostname: &str, port: u16) -> String {
+    let path = e
+        .as_string(MqttBrokerProperties::PATH.as_ref())
+        .unwrap_or_else(|| {
+            MqttBrokerProperties::PATH
+                .default_value()
+                .as_str()
+                .unwrap_or_default()
+                .to_string()
+        });
+    match path.starts_with('/') {
+        true => format!("{}://{}:{}{}", scheme, hostname, port, path),
+        false => format!("{}://{}:{}/{}", scheme, hostname, port, path),
+    }
+}
@@ -1,33 +1,45 @@
 use std::convert::AsRef;
 use std::sync::Arc;
+use std::sync::RwLock;
 
 use log::debug;
 use serde_json::{json, Value};
 
-use crate::behaviour::components::{MqttEndpointProperties, MqttTopicProperties};
-use crate::behaviour::entity::MqttBrokerProperties;
-use crate::model::PropertyInstanceGetter;
+use crate::behaviour::components::{
+    observe_topic, unobserve_topic, MqttEndpointProperties, MqttTopic, MqttTopicProperties,
+};
+use crate::behaviour::entity::{MqttBrokerProperties, MqttPublisherProperties};
+use crate::model::ReactiveEntityInstance;
 use crate::model::ReactiveRelationInstance;
 use crate::reactive::entity::Disconnectable;
 
+/// The properties of the publisher which can't be used in topic templates.
+fn ignored_properties() -> Vec<String> {
+    let mut ignored_properties = vec![MqttEndpointProperties::PAYLOAD.to_string()];
+    ignored_properties.extend(
+        MqttPublisherProperties::properties()
+            .into_iter()
+            .map(|property| property.name.as_str().to_string()),
+    );
+    ignored_properties
+}
+
 pub struct MqttPublishes {
     pub relation: Arc<ReactiveRelationInstance>,
 
     pub handle_id: u128,
+
+    pub clear_retained_handle_id: Option<u128>,
 }
 
 impl MqttPublishes {
     pub fn new<'a>(r: Arc<ReactiveRelationInstance>) -> MqttPublishes {
-        let topic = r
-            .as_string(MqttTopicProperties::TOPIC.as_ref())
-            .unwrap_or(MqttTopicProperties::TOPIC.default_value());
-        let mode = r
-            .as_string(MqttTopicProperties::MODE.as_ref())
-            .unwrap_or(MqttTopicProperties::MODE.default_value());
-
         let publisher = r.outbound.clone();
         let broker = r.inbound.clone();
 
+        // Publish at least once unless configured otherwise
+        let topic = Arc::new(RwLock::new(MqttTopic::new(&r, &publisher)));
+
         let handle_id = publisher
             .properties
             .get(MqttEndpointProperties::PAYLOAD.as_ref())
@@ -35,6 +47,26 @@ impl MqttPublishes {
             .id
             .as_u128();
 
+        // The topic may change at any time
+        let observed_topic = topic.clone();
+        observe_topic(
+            &r,
+            &publisher,
+            ignored_properties(),
+            handle_id,
+            move |relation| {
+                let next_topic = MqttTopic::new(relation, &relation.outbound);
+                let mut topic = observed_topic.write().unwrap();
+                if next_topic != *topic {
+                    debug!("Reconfigured mqtt_publishes to topic {}", next_topic.topic);
+                    *topic = next_topic;
+                }
+            },
+        );
+
+        let payload_topic = topic.clone();
+        let payload_publisher = publisher.clone();
+        let payload_broker = broker.clone();
         publisher
             .properties
             .get(MqttEndpointProperties::PAYLOAD.as_ref())
@@ -45,13 +77,20 @@ impl MqttPublishes {
             .observe_with_handle(
                 move |v| {
                     let payload = v.clone();
+                    let topic = payload_topic.read().unwrap().clone();
+                    if topic.topic.is_empty() {
+                        return;
+                    }
                     // TODO: log?
-                    let package: Value = json!({
-                        MqttTopicProperties::TOPIC.as_ref(): topic.clone(),
-                        MqttTopicProperties::MODE.as_ref(): mode.clone(),
+                    let mut package: Value = json!({
+                        MqttTopicProperties::TOPIC.as_ref(): topic.topic,
+                        MqttTopicProperties::MODE.as_ref(): topic.mode.as_ref(),
+                        MqttTopicProperties::QOS.as_ref(): topic.qos as u8,
+                        MqttTopicProperties::RETAIN.as_ref(): topic.retain,
                         MqttEndpointProperties::PAYLOAD.as_ref(): payload
                     });
-                    broker
+                    add_message_properties(&payload_publisher, &mut package);
+                    payload_broker
                         .properties
                         .get(MqttBrokerProperties::SEND_PACKAGE.as_ref())
                         .unwrap()
@@ -60,9 +99,44 @@ impl MqttPublishes {
                 handle_id,
             );
 
+        // Clears the retained message of the topic
+        let clear_retained = publisher
+            .properties
+            .get(MqttPublisherProperties::CLEAR_RETAINED.as_ref());
+        let clear_retained_handle_id = clear_retained.map(|clear_retained| {
+            let clear_retained_handle_id = clear_retained.id.as_u128();
+            clear_retained.stream.read().unwrap().observe_with_handle(
+                move |v| {
+                    if !v.as_bool().unwrap_or(false) {
+                        return;
+                    }
+                    let topic = topic.read().unwrap().clone();
+                    if topic.topic.is_empty() {
+                        return;
+                    }
+                    debug!("Clearing retained message of topic {}", topic.topic);
+                    let package: Value = json!({
+                        MqttTopicProperties::TOPIC.as_ref(): topic.topic,
+                        MqttTopicProperties::MODE.as_ref(): MqttTopicProperties::MODE.default_value(),
+                        MqttTopicProperties::QOS.as_ref(): topic.qos as u8,
+                        MqttTopicProperties::RETAIN.as_ref(): true,
+                        MqttEndpointProperties::PAYLOAD.as_ref(): Value::Null
+                    });
+                    broker
+                        .properties
+                        .get(MqttBrokerProperties::SEND_PACKAGE.as_ref())
+                        .unwrap()
+                        .set(package);
+                },
+                clear_retained_handle_id,
+            );
+            clear_retained_handle_id
+        });
+
         MqttPublishes {
             relation: r.clone(),
             handle_id,
+            clear_retained_handle_id,
         }
     }
 
@@ -74,10 +148,14 @@ impl MqttPublishes {
 impl Disconnectable for MqttPublishes {
     fn disconnect(&self) {
         debug!("Disconnecting mqtt_publishes {}", self.handle_id);
-        // let publisher = self.relation.inbound.clone();
-        let property = self
-            .relation
-            .inbound
+        let publisher = self.relation.outbound.clone();
+        unobserve_topic(
+            &self.relation,
+            &publisher,
+            &ignored_properties(),
+            self.handle_id,
+        );
+        let property = publisher
             .properties
             .get(MqttEndpointProperties::PAYLOAD.as_ref());
         if property.is_some() {
@@ -88,6 +166,31 @@ impl Disconnectable for MqttPublishes {
                 .unwrap()
                 .remove(self.handle_id);
         }
+        if let Some(clear_retained_handle_id) = self.clear_retained_handle_id {
+            if let Some(property) = publisher
+                .properties
+                .get(MqttPublisherProperties::CLEAR_RETAINED.as_ref())
+            {
+                property
+                    .stream
+                    .read()
+                    .unwrap()
+                    .remove(clear_retained_handle_id);
+            }
+        }
+    }
+}
+
+/// Adds the MQTT 5 properties of the publisher to the package.
+fn add_message_properties(publisher: &ReactiveEntityInstance, package: &mut Value) {
+    for property in [
+        MqttPublisherProperties::USER_PROPERTIES,
+        MqttPublisherProperties::CONTENT_TYPE,
+        MqttPublisherProperties::MESSAGE_EXPIRY,
+    ] {
+        if let Some(value) = publisher.properties.get(property.as_ref()) {
+            package[property.as_ref()] = value.get();
+        }
     }
 }
 
@@ -1,32 +1,51 @@
 use std::convert::AsRef;
 use std::sync::Arc;
+use std::sync::RwLock;
+use std::sync::Weak;
 
 use log::debug;
+use serde_json::json;
 
-use crate::behaviour::components::{MqttEndpointProperties, MqttTopicProperties};
-use crate::behaviour::entity::MqttBrokerProperties;
+use crate::behaviour::components::{
+    get_received_payload, observe_topic, topic_captures, unobserve_topic, MqttEndpointProperties,
+    MqttMessageProperties, MqttTopic, MqttTopicProperties,
+};
+use crate::behaviour::entity::mqtt_broker::MqttBroker;
+use crate::behaviour::entity::{MqttBrokerProperties, MqttSubscriberProperties};
 use crate::model::PropertyInstanceGetter;
 use crate::model::ReactiveRelationInstance;
 use crate::reactive::entity::Disconnectable;
 
+/// The properties of the subscriber which can't be used in topic templates.
+fn ignored_properties() -> Vec<String> {
+    let mut ignored_properties = vec![MqttEndpointProperties::PAYLOAD.to_string()];
+    ignored_properties.extend(
+        MqttSubscriberProperties::properties()
+            .into_iter()
+            .map(|property| property.name.as_str().to_string()),
+    );
+    ignored_properties
+}
+
 pub struct MqttSubscribes {
     pub relation: Arc<ReactiveRelationInstance>,
 
     pub handle_id: u128,
+
+    topic: Arc<RwLock<MqttTopic>>,
+
+    /// The broker behaviour maintains the set of subscribed topics
+    broker: Weak<MqttBroker>,
 }
 
 impl MqttSubscribes {
-    pub fn new<'a>(r: Arc<ReactiveRelationInstance>) -> MqttSubscribes {
-        let topic = r
-            .as_string(MqttTopicProperties::TOPIC.as_ref())
-            .unwrap_or(String::new());
-        let mode = r
-            .as_string(MqttTopicProperties::MODE.as_ref())
-            .unwrap_or(MqttTopicProperties::MODE.default_value());
-
-        let broker = r.outbound.clone();
+    pub fn new<'a>(r: Arc<ReactiveRelationInstance>, broker: &Arc<MqttBroker>) -> MqttSubscribes {
+        // The behaviour is recreated with the next broker behaviour
+        let broker = Arc::downgrade(broker);
         let subscriber = r.inbound.clone();
 
+        let topic = Arc::new(RwLock::new(MqttTopic::new(&r, &subscriber)));
+
         let handle_id = subscriber
             .properties
             .get(MqttEndpointProperties::PAYLOAD.as_ref())
@@ -34,7 +53,34 @@ impl MqttSubscribes {
             .id
             .as_u128();
 
-        broker
+        // Changing the topic or the QoS replaces the subscription at the broker
+        let observed_topic = topic.clone();
+        let observed_broker = broker.clone();
+        observe_topic(
+            &r,
+            &subscriber,
+            ignored_properties(),
+            handle_id,
+            move |relation| {
+                let next_topic = MqttTopic::new(relation, &relation.inbound);
+                // Don't hold the lock while subscribing, undelivered messages are delivered immediately
+                let topic =
+                    std::mem::replace(&mut *observed_topic.write().unwrap(), next_topic.clone());
+                if next_topic.topic != topic.topic || next_topic.qos != topic.qos {
+                    debug!(
+                        "Changing subscription from topic {} to {}",
+                        topic.topic, next_topic.topic
+                    );
+                    if let Some(broker) = observed_broker.upgrade() {
+                        broker.unsubscribe(topic.topic.as_str(), topic.qos);
+                        broker.subscribe(next_topic.topic.as_str(), next_topic.qos);
+                    }
+                }
+            },
+        );
+
+        let subscribed_topic = topic.clone();
+        r.outbound
             .properties
             .get(MqttBrokerProperties::RECEIVED_PACKAGE.as_ref())
             .unwrap()
@@ -43,39 +89,99 @@ impl MqttSubscribes {
             .unwrap()
             .observe_with_handle(
                 move |v| {
-                    // TODO: Unpack package (either RAW or parse JSON)
                     let packet = v.clone();
                     let received_topic = packet.get(MqttTopicProperties::TOPIC.as_ref());
                     if received_topic.is_none() {
                         return;
                     }
                     let received_topic = received_topic.unwrap().as_str().unwrap();
-                    if received_topic != topic.as_str() {
+                    // Don't hold the lock while propagating, a flow may change the topic
+                    let topic = subscribed_topic.read().unwrap().clone();
+                    let captures = topic_captures(topic.topic.as_str(), received_topic);
+                    if captures.is_none() {
                         return;
                     }
-                    let received_payload = packet.get(MqttEndpointProperties::PAYLOAD.as_ref());
+                    let received_payload = get_received_payload(&packet);
                     if received_payload.is_none() {
                         return;
                     }
+                    let payload = match topic.mode.decode(received_payload.unwrap().as_ref()) {
+                        Ok(payload) => payload,
+                        Err(err) => {
+                            debug!(
+                                "Failed to decode payload from topic {} for subscriber {}: {}",
+                                received_topic, subscriber.id, err
+                            );
+                            if let Some(property) = subscriber
+                                .properties
+                                .get(MqttSubscriberProperties::ERROR.as_ref())
+                            {
+                                property.set(json!(err.to_string()));
+                            }
+                            return;
+                        }
+                    };
+                    // Set the topic and the captures first, so they are available for the payload
+                    if let Some(property) = subscriber
+                        .properties
+                        .get(MqttSubscriberProperties::LAST_TOPIC.as_ref())
+                    {
+                        property.set(json!(received_topic));
+                    }
+                    if let Some(property) = subscriber
+                        .properties
+                        .get(MqttSubscriberProperties::CAPTURES.as_ref())
+                    {
+                        property.set(json!(captures.unwrap()));
+                    }
+                    let properties = MqttMessageProperties::from_package(&packet);
+                    if let Some(property) = subscriber
+                        .properties
+                        .get(MqttSubscriberProperties::USER_PROPERTIES.as_ref())
+                    {
+                        property.set(properties.user_properties_object());
+                    }
+                    if let Some(property) = subscriber
+                        .properties
+                        .get(MqttSubscriberProperties::CONTENT_TYPE.as_ref())
+                    {
+                        property.set(json!(properties.content_type.unwrap_or_default()));
+                    }
                     let property = subscriber
                         .properties
                         .get(MqttEndpointProperties::PAYLOAD.as_ref());
                     if property.is_none() {
                         return;
                     }
-                    property.unwrap().set(received_payload.unwrap().clone());
+                    property.unwrap().set(payload);
+                    if let Some(property) = subscriber
+                        .properties
+                        .get(MqttSubscriberProperties::ERROR.as_ref())
+                    {
+                        if !property.as_string().unwrap_or_default().is_empty() {
+                            property.set(json!(""));
+                        }
+                    }
                     debug!(
                         "Forwarded payload from topic {} to subscriber {}",
-                        topic.clone(),
-                        subscriber.id
+                        received_topic, subscriber.id
                     );
                 },
                 handle_id,
             );
 
+        // Subscribe after observing, because messages which have been received before are
+        // delivered immediately
+        if let Some(broker) = broker.upgrade() {
+            let topic = topic.read().unwrap().clone();
+            broker.subscribe(topic.topic.as_str(), topic.qos);
+        }
+
         MqttSubscribes {
             relation: r.clone(),
             handle_id,
+            topic,
+            broker,
         }
     }
 
@@ -86,17 +192,24 @@ impl MqttSubscribes {
 
 impl Disconnectable for MqttSubscribes {
     fn disconnect(&self) {
-        debug!("Disconnecting mqtt_publishes {}", self.handle_id);
-        // let publisher = self.relation.inbound.clone();
-        self.relation
-            .inbound
+        debug!("Disconnecting mqtt_subscribes {}", self.handle_id);
+        unobserve_topic(
+            &self.relation,
+            &self.relation.inbound,
+            &ignored_properties(),
+            self.handle_id,
+        );
+        let broker = self.relation.outbound.clone();
+        if let Some(property) = broker
             .properties
-            .get(MqttEndpointProperties::PAYLOAD.as_ref())
-            .unwrap()
-            .stream
-            .read()
-            .unwrap()
-            .remove(self.handle_id);
+            .get(MqttBrokerProperties::RECEIVED_PACKAGE.as_ref())
+        {
+            property.stream.read().unwrap().remove(self.handle_id);
+        }
+        if let Some(broker) = self.broker.upgrade() {
+            let topic = self.topic.read().unwrap();
+            broker.unsubscribe(topic.topic.as_str(), topic.qos);
+        }
     }
 }
 
@@ -5,6 +5,8 @@ use async_trait::async_trait;
 use indradb::EdgeKey;
 use log::debug;
 
+use crate::behaviour::entity::entity_behaviour_provider::MqttEntityBehaviourProvider;
+use crate::behaviour::entity::entity_behaviour_provider::MqttEntityBehaviourProviderImpl;
 use crate::behaviour::relation::mqtt_publishes::MqttPublishes;
 use crate::behaviour::relation::mqtt_subscribes::MqttSubscribes;
 use crate::model::ReactiveRelationInstance;
@@ -51,6 +53,8 @@ pub struct MqttRelationBehaviourProviderImpl {
     mqtt_publishes_relation_behaviour: MqttPublishesRelationBehaviourStorage,
 
     mqtt_subscribes_relation_behaviour: MqttSubscribesRelationBehaviourStorage,
+
+    entity_behaviour_provider: Wrc<MqttEntityBehaviourProviderImpl>,
 }
 
 interfaces!(MqttRelationBehaviourProviderImpl: dyn RelationBehaviourProvider);
@@ -58,10 +62,11 @@ interfaces!(MqttRelationBehaviourProviderImpl: dyn RelationBehaviourProvider);
 #[component]
 impl MqttRelationBehaviourProviderImpl {
     #[provides]
-    fn new() -> Self {
+    fn new(entity_behaviour_provider: Wrc<MqttEntityBehaviourProviderImpl>) -> Self {
         Self {
             mqtt_publishes_relation_behaviour: create_mqtt_publishes_relation_behaviour_storage(),
             mqtt_subscribes_relation_behaviour: create_mqtt_subscribes_relation_behaviour_storage(),
+            entity_behaviour_provider,
         }
     }
 }
@@ -112,7 +117,22 @@ impl MqttRelationBehaviourProvider for MqttRelationBehaviourProviderImpl {
             return;
         }
         let edge_key = edge_key.unwrap();
-        let mqtt_subscribes = Arc::new(MqttSubscribes::new(relation_instance.clone()));
+        // The previous behaviour unsubscribes at the previous broker behaviour
+        self.mqtt_subscribes_relation_behaviour
+            .0
+            .write()
+            .unwrap()
+            .remove(&edge_key);
+        // The broker behaviour maintains the set of subscribed topics. The relation is recreated
+        // as soon as the broker behaviour has been created.
+        let broker = match self
+            .entity_behaviour_provider
+            .get_relation_broker(relation_instance.clone(), MQTT_SUBSCRIBES)
+        {
+            Some(broker) => broker,
+            None => return,
+        };
+        let mqtt_subscribes = Arc::new(MqttSubscribes::new(relation_instance.clone(), &broker));
         self.mqtt_subscribes_relation_behaviour
             .0
             .write()
@@ -131,6 +151,7 @@ impl MqttRelationBehaviourProvider for MqttRelationBehaviourProviderImpl {
             return;
         }
         let edge_key = edge_key.unwrap();
+        self.entity_behaviour_provider.unlink_relation(&edge_key);
         self.mqtt_subscribes_relation_behaviour
             .0
             .write()
@@ -144,6 +165,7 @@ impl MqttRelationBehaviourProvider for MqttRelationBehaviourProviderImpl {
     }
 
     fn remove_by_key(&self, edge_key: EdgeKey) {
+        self.entity_behaviour_provider.unlink_relation(&edge_key);
         if self
             .mqtt_publishes_relation_behaviour
             .0
@@ -4,6 +4,8 @@ use crate::di::*;
 use async_trait::async_trait;
 use log::{debug, error};
 
+use crate::behaviour::components::component_behaviour_provider::MqttComponentBehaviourProviderImpl;
+use crate::behaviour::entity::entity_behaviour_provider::MqttEntityBehaviourProvider;
 use crate::behaviour::entity::entity_behaviour_provider::MqttEntityBehaviourProviderImpl;
 use crate::behaviour::relation::relation_behaviour_provider::MqttRelationBehaviourProviderImpl;
 use crate::builder::EntityInstanceBuilder;
@@ -37,6 +39,7 @@ pub struct MqttPluginImpl {
     entity_type_provider: Wrc<MqttEntityTypeProviderImpl>,
     relation_type_provider: Wrc<MqttRelationTypeProviderImpl>,
     flow_provider: Wrc<MqttFlowProviderImpl>,
+    component_behaviour_provider: Wrc<MqttComponentBehaviourProviderImpl>,
     entity_behaviour_provider: Wrc<MqttEntityBehaviourProviderImpl>,
     relation_behaviour_provider: Wrc<MqttRelationBehaviourProviderImpl>,
 
@@ -60,6 +63,16 @@ impl Plugin for MqttPluginImpl {
 
     fn init(&self) -> Result<(), PluginError> {
         debug!("MqttPluginModuleImpl::init()");
+        // Component behaviours which reference a broker are recreated with the broker behaviour
+        let component_behaviour_provider: Arc<dyn ComponentBehaviourProvider> =
+            self.component_behaviour_provider.clone();
+        self.entity_behaviour_provider
+            .set_component_behaviour_provider(Arc::downgrade(&component_behaviour_provider));
+        // The mqtt_subscribes relations are recreated with the broker behaviour as well
+        let relation_behaviour_provider: Arc<dyn RelationBehaviourProvider> =
+            self.relation_behaviour_provider.clone();
+        self.entity_behaviour_provider
+            .set_relation_behaviour_provider(Arc::downgrade(&relation_behaviour_provider));
         Ok(())
     }
 
@@ -100,6 +113,8 @@ impl Plugin for MqttPluginImpl {
     }
 
     fn set_context(&self, context: Arc<dyn PluginContext>) -> Result<(), PluginError> {
+        // The entity behaviours import instances using the instance managers
+        self.entity_behaviour_provider.set_context(context.clone());
         self.context.0.write().unwrap().replace(context);
         Ok(())
     }
@@ -137,7 +152,13 @@ impl Plugin for MqttPluginImpl {
     fn get_component_behaviour_provider(
         &self,
     ) -> Result<Arc<dyn ComponentBehaviourProvider>, PluginError> {
-        Err(PluginError::NoComponentBehaviourProvider)
+        let component_behaviour_provider = self.component_behaviour_provider.clone();
+        let component_behaviour_provider: Result<Arc<dyn ComponentBehaviourProvider>, _> =
+            <dyn query_interface::Object>::query_arc(component_behaviour_provider);
+        if component_behaviour_provider.is_err() {
+            return Err(PluginError::NoComponentBehaviourProvider);
+        }
+        Ok(component_behaviour_provider.unwrap())
     }
 
     fn get_entity_behaviour_provider(
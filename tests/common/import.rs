@@ -0,0 +1,119 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+use indradb::EdgeKey;
+use inexor_rgf_core_builder::ReactiveEntityInstanceBuilder;
+use inexor_rgf_core_builder::ReactiveRelationInstanceBuilder;
+use inexor_rgf_core_model::EntityInstance;
+use inexor_rgf_core_model::PropertyInstanceGetter;
+use inexor_rgf_core_model::ReactiveEntityInstance;
+use inexor_rgf_core_model::RelationInstance;
+use inexor_rgf_plugin_mqtt::behaviour::entity::importer::MqttImportContext;
+use inexor_rgf_plugin_mqtt::behaviour::entity::mqtt_broker::MqttBroker;
+use inexor_rgf_plugin_mqtt::behaviour::relation::mqtt_publishes::MqttPublishes;
+use inexor_rgf_plugin_mqtt::behaviour::relation::mqtt_subscribes::MqttSubscribes;
+use uuid::Uuid;
+
+/// Keeps the imported instances in memory instead of the instance managers of a plugin context.
+/// Like the relation behaviour provider, the imported relations of the broker get their
+/// behaviours.
+pub struct TestImportContext {
+    broker: Arc<MqttBroker>,
+
+    entities: RwLock<HashMap<Uuid, Arc<ReactiveEntityInstance>>>,
+
+    /// The behaviours of the imported relations, which disconnect when they are dropped
+    relations: RwLock<HashMap<EdgeKey, Box<dyn Any + Send + Sync>>>,
+}
+
+impl TestImportContext {
+    pub fn new(broker: Arc<MqttBroker>) -> Arc<TestImportContext> {
+        Arc::new(TestImportContext {
+            broker,
+            entities: RwLock::new(HashMap::new()),
+            relations: RwLock::new(HashMap::new()),
+        })
+    }
+
+    /// Returns the imported entity instances of the given type.
+    pub fn entities(&self, type_name: &str) -> Vec<Arc<ReactiveEntityInstance>> {
+        self.entities
+            .read()
+            .unwrap()
+            .values()
+            .filter(|entity| entity.type_name == type_name)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the imported entity instance of the given type with the given label.
+    pub fn entity(&self, type_name: &str, label: &str) -> Option<Arc<ReactiveEntityInstance>> {
+        self.entities(type_name)
+            .into_iter()
+            .find(|entity| entity.as_string("label").as_deref() == Some(label))
+    }
+
+    pub fn count_relations(&self) -> usize {
+        self.relations.read().unwrap().len()
+    }
+
+    fn get_entity(&self, id: Uuid) -> Option<Arc<ReactiveEntityInstance>> {
+        if id == self.broker.entity.id {
+            return Some(self.broker.entity.clone());
+        }
+        self.entities.read().unwrap().get(&id).cloned()
+    }
+}
+
+impl MqttImportContext for TestImportContext {
+    fn create_entity(
+        &self,
+        entity_instance: EntityInstance,
+    ) -> Option<Arc<ReactiveEntityInstance>> {
+        let mut builder = ReactiveEntityInstanceBuilder::new(entity_instance.type_name.as_str());
+        for (name, value) in entity_instance.properties {
+            builder.property(name.as_str(), value);
+        }
+        let entity = builder.get();
+        self.entities
+            .write()
+            .unwrap()
+            .insert(entity.id, entity.clone());
+        Some(entity)
+    }
+
+    fn delete_entity(&self, id: Uuid) {
+        self.entities.write().unwrap().remove(&id);
+    }
+
+    fn create_relation(&self, relation_instance: RelationInstance) -> Option<EdgeKey> {
+        let outbound = self.get_entity(relation_instance.outbound_id)?;
+        let inbound = self.get_entity(relation_instance.inbound_id)?;
+        let mut builder = ReactiveRelationInstanceBuilder::new(
+            outbound,
+            relation_instance.type_name.as_str(),
+            inbound,
+        );
+        for (name, value) in relation_instance.properties {
+            builder.property(name.as_str(), value);
+        }
+        let relation = builder.get();
+        let edge_key = relation.get_key()?;
+        let behaviour: Box<dyn Any + Send + Sync> = match relation.type_name.as_str() {
+            "mqtt_publishes" => Box::new(MqttPublishes::new(relation)),
+            "mqtt_subscribes" => Box::new(MqttSubscribes::new(relation, &self.broker)),
+            _ => return None,
+        };
+        self.relations
+            .write()
+            .unwrap()
+            .insert(edge_key.clone(), behaviour);
+        Some(edge_key)
+    }
+
+    fn delete_relation(&self, edge_key: EdgeKey) {
+        self.relations.write().unwrap().remove(&edge_key);
+    }
+}
@@ -0,0 +1,358 @@
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use inexor_rgf_core_builder::ReactiveEntityInstanceBuilder;
+use inexor_rgf_core_builder::ReactiveRelationInstanceBuilder;
+use inexor_rgf_core_model::PropertyInstanceGetter;
+use inexor_rgf_core_model::ReactiveEntityInstance;
+use inexor_rgf_core_model::ReactiveRelationInstance;
+use inexor_rgf_core_reactive::NamedProperties;
+use inexor_rgf_plugin_mqtt::behaviour::components::MqttEndpointProperties;
+use inexor_rgf_plugin_mqtt::behaviour::components::MqttPayloadMode;
+use inexor_rgf_plugin_mqtt::behaviour::components::MqttTopicProperties;
+use inexor_rgf_plugin_mqtt::behaviour::entity::mqtt_broker::MqttBroker;
+use inexor_rgf_plugin_mqtt::behaviour::entity::mqtt_server::MqttServer;
+use inexor_rgf_plugin_mqtt::behaviour::entity::MqttBrokerProperties;
+use inexor_rgf_plugin_mqtt::behaviour::entity::MqttPublisherProperties;
+use inexor_rgf_plugin_mqtt::behaviour::entity::MqttServerProperties;
+use inexor_rgf_plugin_mqtt::behaviour::entity::MqttSubscriberProperties;
+use inexor_rgf_plugin_mqtt::behaviour::relation::mqtt_publishes::MqttPublishes;
+use inexor_rgf_plugin_mqtt::behaviour::relation::mqtt_subscribes::MqttSubscribes;
+use serde_json::json;
+use serde_json::Value;
+
+pub mod import;
+pub mod tls;
+pub mod v5;
+pub mod ws;
+
+/// Starts an embedded broker on a port which is chosen by the operating system and waits until
+/// it accepts connections. Returns the server and its port.
+pub fn start_server() -> (MqttServer, u16) {
+    let entity = create_server_entity(0);
+    let server = match MqttServer::new(entity.clone()) {
+        Ok(server) => server,
+        Err(_) => panic!("Failed to start MQTT server"),
+    };
+    let port = entity
+        .as_u64(MqttServerProperties::PORT.as_ref())
+        .and_then(|port| u16::try_from(port).ok())
+        .unwrap();
+    assert_ne!(0, port);
+    assert!(wait_until(
+        || TcpStream::connect(("127.0.0.1", port)).is_ok()
+    ));
+    (server, port)
+}
+
+/// Starts an embedded broker on a port which has been released by a previous server.
+pub fn restart_server(port: u16) -> MqttServer {
+    let server = match MqttServer::new(create_server_entity(port)) {
+        Ok(server) => server,
+        Err(_) => panic!("Failed to restart MQTT server on port {}", port),
+    };
+    assert!(wait_until(
+        || TcpStream::connect(("127.0.0.1", port)).is_ok()
+    ));
+    server
+}
+
+/// Returns a port on which nothing listens, but which a server can listen on later.
+pub fn released_port() -> u16 {
+    let (server, port) = start_server();
+    drop(server);
+    port
+}
+
+/// Creates a mqtt_server entity which listens on the given port of the loopback interface.
+pub fn create_server_entity(port: u16) -> Arc<ReactiveEntityInstance> {
+    create_entity(
+        "mqtt_server",
+        MqttServerProperties::properties(),
+        &[
+            (
+                MqttServerProperties::LISTEN_ADDRESS.as_ref(),
+                json!("127.0.0.1"),
+            ),
+            (MqttServerProperties::PORT.as_ref(), json!(port)),
+        ],
+    )
+}
+
+/// Creates a mqtt_broker entity and its behaviour, which connects to the broker on the given port.
+pub fn start_broker(port: u16) -> (Arc<ReactiveEntityInstance>, Arc<MqttBroker>) {
+    start_broker_with(port, &[])
+}
+
+/// Like start_broker, but overrides the given properties of the mqtt_broker entity.
+pub fn start_broker_with(
+    port: u16,
+    values: &[(&str, Value)],
+) -> (Arc<ReactiveEntityInstance>, Arc<MqttBroker>) {
+    let entity = create_broker_entity(port, values);
+    match MqttBroker::new(entity.clone(), Arc::new(Mutex::new(String::new()))) {
+        Ok(broker) => (entity, Arc::new(broker)),
+        Err(_) => panic!("Failed to create MQTT broker for port {}", port),
+    }
+}
+
+/// Like start_broker, but waits until the broker is connected.
+pub fn connect_broker(port: u16) -> (Arc<ReactiveEntityInstance>, Arc<MqttBroker>) {
+    connect_broker_with(port, &[])
+}
+
+/// Like start_broker_with, but waits until the broker is connected.
+pub fn connect_broker_with(
+    port: u16,
+    values: &[(&str, Value)],
+) -> (Arc<ReactiveEntityInstance>, Arc<MqttBroker>) {
+    let (entity, broker) = start_broker_with(port, values);
+    assert!(
+        wait_until(|| is_connected(&entity)),
+        "MQTT broker didn't connect to port {}",
+        port
+    );
+    (entity, broker)
+}
+
+/// Creates a mqtt_broker entity without a behaviour. The entity is configured to connect to the
+/// broker on the given port and overrides the given properties.
+pub fn create_broker_entity(port: u16, values: &[(&str, Value)]) -> Arc<ReactiveEntityInstance> {
+    let mut overrides = vec![
+        (MqttBrokerProperties::HOSTNAME.as_ref(), json!("127.0.0.1")),
+        (MqttBrokerProperties::PORT.as_ref(), json!(port)),
+        (
+            MqttBrokerProperties::RECONNECT_INITIAL_DELAY.as_ref(),
+            json!(100),
+        ),
+        (
+            MqttBrokerProperties::RECONNECT_MAX_DELAY.as_ref(),
+            json!(200),
+        ),
+        (MqttBrokerProperties::RECONNECT_JITTER.as_ref(), json!(0)),
+    ];
+    overrides.extend_from_slice(values);
+    create_entity(
+        "mqtt_broker",
+        MqttBrokerProperties::properties(),
+        overrides.as_slice(),
+    )
+}
+
+pub fn create_publisher() -> Arc<ReactiveEntityInstance> {
+    let mut properties = MqttEndpointProperties::properties();
+    properties.append(&mut MqttPublisherProperties::properties());
+    create_entity("mqtt_publisher", properties, &[])
+}
+
+pub fn create_subscriber() -> Arc<ReactiveEntityInstance> {
+    let mut properties = MqttEndpointProperties::properties();
+    properties.append(&mut MqttSubscriberProperties::properties());
+    create_entity("mqtt_subscriber", properties, &[])
+}
+
+/// Creates a mqtt_publisher which publishes to the topic via the broker.
+pub fn publish_to(
+    broker_entity: &Arc<ReactiveEntityInstance>,
+    topic: &str,
+) -> (Arc<ReactiveEntityInstance>, MqttPublishes) {
+    publish_to_with(broker_entity, topic, &[])
+}
+
+/// Like publish_to, but overrides the given properties of the relation.
+pub fn publish_to_with(
+    broker_entity: &Arc<ReactiveEntityInstance>,
+    topic: &str,
+    values: &[(&str, Value)],
+) -> (Arc<ReactiveEntityInstance>, MqttPublishes) {
+    let publisher = create_publisher();
+    let publishes = MqttPublishes::new(create_topic_relation_with(
+        publisher.clone(),
+        "mqtt_publishes",
+        broker_entity.clone(),
+        topic,
+        values,
+    ));
+    (publisher, publishes)
+}
+
+/// Creates a mqtt_subscriber which subscribes the topic via the broker.
+pub fn subscribe_to(
+    broker_entity: &Arc<ReactiveEntityInstance>,
+    broker: &Arc<MqttBroker>,
+    topic: &str,
+) -> (Arc<ReactiveEntityInstance>, MqttSubscribes) {
+    subscribe_to_with(broker_entity, broker, topic, &[])
+}
+
+/// Like subscribe_to, but overrides the given properties of the relation.
+pub fn subscribe_to_with(
+    broker_entity: &Arc<ReactiveEntityInstance>,
+    broker: &Arc<MqttBroker>,
+    topic: &str,
+    values: &[(&str, Value)],
+) -> (Arc<ReactiveEntityInstance>, MqttSubscribes) {
+    let subscriber = create_subscriber();
+    let subscribes = MqttSubscribes::new(
+        create_topic_relation_with(
+            broker_entity.clone(),
+            "mqtt_subscribes",
+            subscriber.clone(),
+            topic,
+            values,
+        ),
+        broker,
+    );
+    (subscriber, subscribes)
+}
+
+/// Publishes a message to the topic and waits until it has been received via the broker.
+pub fn round_trip(
+    broker_entity: &Arc<ReactiveEntityInstance>,
+    broker: &Arc<MqttBroker>,
+    topic: &str,
+) -> bool {
+    let (publisher, _publishes) = publish_to(broker_entity, topic);
+    let (subscriber, _subscribes) = subscribe_to(broker_entity, broker, topic);
+    publish_until_received(&publisher, &subscriber, json!(topic))
+}
+
+/// Creates a relation of type mqtt_publishes or mqtt_subscribes with the given topic.
+pub fn create_topic_relation(
+    outbound: Arc<ReactiveEntityInstance>,
+    type_name: &str,
+    inbound: Arc<ReactiveEntityInstance>,
+    topic: &str,
+) -> Arc<ReactiveRelationInstance> {
+    create_topic_relation_with(outbound, type_name, inbound, topic, &[])
+}
+
+/// Like create_topic_relation, but overrides the given properties of the relation.
+pub fn create_topic_relation_with(
+    outbound: Arc<ReactiveEntityInstance>,
+    type_name: &str,
+    inbound: Arc<ReactiveEntityInstance>,
+    topic: &str,
+    values: &[(&str, Value)],
+) -> Arc<ReactiveRelationInstance> {
+    let mut builder = ReactiveRelationInstanceBuilder::new(outbound, type_name, inbound);
+    builder
+        .property(MqttTopicProperties::TOPIC.as_ref(), json!(topic))
+        .property(MqttTopicProperties::MODE.as_ref(), json!("json"))
+        .property(MqttTopicProperties::QOS.as_ref(), json!(1))
+        .property(MqttTopicProperties::RETAIN.as_ref(), json!(false));
+    for (name, value) in values {
+        builder.property(*name, value.clone());
+    }
+    builder.get()
+}
+
+/// Publishes the payload with QoS 1 through the send_package property of the broker, like a
+/// device which isn't part of the graph.
+pub fn send_package(
+    broker: &ReactiveEntityInstance,
+    topic: &str,
+    mode: MqttPayloadMode,
+    retain: bool,
+    payload: Value,
+) {
+    set(
+        broker,
+        MqttBrokerProperties::SEND_PACKAGE.as_ref(),
+        json!({
+            MqttTopicProperties::TOPIC.as_ref(): topic,
+            MqttTopicProperties::MODE.as_ref(): mode.as_ref(),
+            MqttTopicProperties::QOS.as_ref(): 1,
+            MqttTopicProperties::RETAIN.as_ref(): retain,
+            MqttEndpointProperties::PAYLOAD.as_ref(): payload
+        }),
+    );
+}
+
+/// Records every value of the property.
+pub fn record(entity: &ReactiveEntityInstance, name: &str) -> Arc<Mutex<Vec<Value>>> {
+    let values = Arc::new(Mutex::new(Vec::new()));
+    let property = entity.properties.get(name).unwrap();
+    let recorded = values.clone();
+    property.stream.read().unwrap().observe_with_handle(
+        move |value| recorded.lock().unwrap().push(value.clone()),
+        property.id.as_u128(),
+    );
+    values
+}
+
+/// Creates an entity with the default values of the properties. The defaults are overridden by
+/// the given values.
+pub fn create_entity(
+    type_name: &str,
+    properties: NamedProperties,
+    values: &[(&str, Value)],
+) -> Arc<ReactiveEntityInstance> {
+    let mut builder = ReactiveEntityInstanceBuilder::new(type_name);
+    for property in properties {
+        builder.property(property.name.as_str(), property.value);
+    }
+    for (name, value) in values {
+        builder.property(*name, value.clone());
+    }
+    builder.get()
+}
+
+pub fn last_error(broker: &ReactiveEntityInstance) -> String {
+    broker
+        .as_string(MqttBrokerProperties::LAST_ERROR.as_ref())
+        .unwrap_or_default()
+}
+
+pub fn reconnect_attempts(broker: &ReactiveEntityInstance) -> u64 {
+    broker
+        .as_u64(MqttBrokerProperties::RECONNECT_ATTEMPTS.as_ref())
+        .unwrap_or(0)
+}
+
+pub fn is_connected(broker: &ReactiveEntityInstance) -> bool {
+    broker
+        .as_bool(MqttBrokerProperties::CONNECTED.as_ref())
+        .unwrap_or(false)
+}
+
+pub fn set(entity: &ReactiveEntityInstance, name: &str, value: Value) {
+    entity.properties.get(name).unwrap().set(value);
+}
+
+pub fn payload(entity: &ReactiveEntityInstance) -> Value {
+    entity
+        .get(MqttEndpointProperties::PAYLOAD.as_ref())
+        .unwrap()
+}
+
+/// Polls the condition for up to 5 seconds.
+pub fn wait_until<F: Fn() -> bool>(condition: F) -> bool {
+    for _ in 0..100 {
+        if condition() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    false
+}
+
+/// Publishes the payload until the subscriber receives it. Publishing once isn't enough,
+/// because the broker may not have processed the SUBSCRIBE yet.
+pub fn publish_until_received(
+    publisher: &ReactiveEntityInstance,
+    subscriber: &ReactiveEntityInstance,
+    value: Value,
+) -> bool {
+    wait_until(|| {
+        set(
+            &publisher,
+            MqttEndpointProperties::PAYLOAD.as_ref(),
+            value.clone(),
+        );
+        payload(subscriber) == value
+    })
+}
@@ -0,0 +1,299 @@
+//! A scripted MQTT 5 broker. rumqttd only accepts MQTT 3.1.1 connections.
+//!
+//! The broker rejects subscriptions of topic filters which start with `forbidden/` with the
+//! reason code NotAuthorized and QoS 1 messages of topics which start with `quota/` with the
+//! reason code QuotaExceeded. Other messages are forwarded with QoS 0 and their properties to the
+//! connections which subscribed exactly their topic. A broker with credentials rejects
+//! connections with other credentials with the reason code BadUserNamePassword.
+
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::TcpStream;
+use tokio::runtime;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::UnboundedSender;
+
+const CONNECT: u8 = 1;
+const PUBLISH: u8 = 3;
+const PUBREL: u8 = 6;
+const SUBSCRIBE: u8 = 8;
+const UNSUBSCRIBE: u8 = 10;
+const PINGREQ: u8 = 12;
+const DISCONNECT: u8 = 14;
+
+const BAD_USER_NAME_OR_PASSWORD: u8 = 0x86;
+const NOT_AUTHORIZED: u8 = 0x87;
+const QUOTA_EXCEEDED: u8 = 0x97;
+
+/// The topic filters by connection.
+type Subscriptions = Arc<Mutex<Vec<(usize, String, UnboundedSender<Vec<u8>>)>>>;
+
+/// The username and the password.
+type Credentials = Option<(String, String)>;
+
+/// Starts the broker on a port which is chosen by the operating system and returns the port.
+pub fn start_v5_broker() -> u16 {
+    start_broker(None)
+}
+
+/// Like start_v5_broker, but only accepts connections with the given credentials.
+pub fn start_v5_broker_with_credentials(username: &str, password: &str) -> u16 {
+    start_broker(Some((username.to_string(), password.to_string())))
+}
+
+fn start_broker(credentials: Credentials) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async move {
+            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+            let subscriptions: Subscriptions = Arc::new(Mutex::new(Vec::new()));
+            let mut connection_id = 0;
+            while let Ok((stream, _)) = listener.accept().await {
+                connection_id += 1;
+                tokio::spawn(serve(
+                    stream,
+                    connection_id,
+                    credentials.clone(),
+                    subscriptions.clone(),
+                ));
+            }
+        });
+    });
+    port
+}
+
+async fn serve(
+    stream: TcpStream,
+    connection_id: usize,
+    credentials: Credentials,
+    subscriptions: Subscriptions,
+) {
+    let (mut reader, mut writer) = stream.into_split();
+    let (sender, mut receiver) = mpsc::unbounded_channel::<Vec<u8>>();
+    let write = async move {
+        while let Some(packet) = receiver.recv().await {
+            if writer.write_all(packet.as_slice()).await.is_err() {
+                break;
+            }
+        }
+    };
+    // The connection is closed after the packets of the broker have been written
+    let read = async move {
+        while let Ok((header, body)) = read_packet(&mut reader).await {
+            match header >> 4 {
+                CONNECT if credentials.is_some() && connect(body.as_slice()) != credentials => {
+                    // The connection is closed after the CONNACK
+                    let _ = sender.send(packet(0x20, &[0, BAD_USER_NAME_OR_PASSWORD, 0]));
+                    break;
+                }
+                CONNECT => {
+                    // No session present, success and no properties
+                    let _ = sender.send(packet(0x20, &[0, 0, 0]));
+                }
+                PUBLISH => publish(header, body.as_slice(), &sender, &subscriptions),
+                PUBREL => {
+                    let _ = sender.send(packet(0x70, &body[0..2]));
+                }
+                SUBSCRIBE => {
+                    let reason_codes = subscribe(body.as_slice(), |filter, options| {
+                        if filter.starts_with("forbidden/") {
+                            return NOT_AUTHORIZED;
+                        }
+                        subscriptions
+                            .lock()
+                            .unwrap()
+                            .push((connection_id, filter, sender.clone()));
+                        // The granted QoS
+                        options & 0x03
+                    });
+                    let _ = sender.send(packet(0x90, &ack(&body[0..2], &reason_codes)));
+                }
+                UNSUBSCRIBE => {
+                    let filters = unsubscribe(body.as_slice());
+                    subscriptions.lock().unwrap().retain(|(id, filter, _)| {
+                        *id != connection_id || !filters.contains(filter)
+                    });
+                    let _ = sender.send(packet(0xb0, &ack(&body[0..2], &vec![0; filters.len()])));
+                }
+                PINGREQ => {
+                    let _ = sender.send(packet(0xd0, &[]));
+                }
+                DISCONNECT => break,
+                _ => {}
+            }
+        }
+        subscriptions
+            .lock()
+            .unwrap()
+            .retain(|(id, _, _)| *id != connection_id);
+    };
+    tokio::join!(write, read);
+}
+
+/// Acknowledges the message and forwards it to the subscribers of its topic.
+fn publish(
+    header: u8,
+    body: &[u8],
+    sender: &UnboundedSender<Vec<u8>>,
+    subscriptions: &Subscriptions,
+) {
+    let qos = (header >> 1) & 0x03;
+    let (topic, mut position) = read_string(body, 0);
+    // Only messages with QoS 1 or 2 have a packet id
+    let packet_id = match qos {
+        0 => &body[position..position],
+        _ => &body[position..position + 2],
+    };
+    position += packet_id.len();
+    let (properties_length, length) = read_variable_integer(&body[position..]);
+    let properties = &body[position..position + length + properties_length];
+    let payload = &body[position + length + properties_length..];
+    let rejected = topic.starts_with("quota/");
+    match qos {
+        1 if rejected => {
+            let _ = sender.send(packet(0x40, &[packet_id, &[QUOTA_EXCEEDED]].concat()));
+        }
+        1 => {
+            let _ = sender.send(packet(0x40, packet_id));
+        }
+        2 => {
+            let _ = sender.send(packet(0x50, packet_id));
+        }
+        _ => {}
+    }
+    if rejected {
+        return;
+    }
+    let mut forwarded = encode_string(topic.as_str());
+    forwarded.extend_from_slice(properties);
+    forwarded.extend_from_slice(payload);
+    for (_, filter, subscriber) in subscriptions.lock().unwrap().iter() {
+        if *filter == topic {
+            let _ = subscriber.send(packet(0x30, forwarded.as_slice()));
+        }
+    }
+}
+
+/// Returns the credentials of the connection.
+fn connect(body: &[u8]) -> Credentials {
+    // The protocol name, the protocol level, the flags and the keep alive
+    let (_, position) = read_string(body, 0);
+    let flags = body[position + 1];
+    let position = position + 4;
+    let (properties_length, length) = read_variable_integer(&body[position..]);
+    let (_, mut position) = read_string(body, position + length + properties_length);
+    if flags & 0x04 != 0 {
+        // The will properties, the will topic and the will payload
+        let (properties_length, length) = read_variable_integer(&body[position..]);
+        let (_, next) = read_string(body, position + length + properties_length);
+        (_, position) = read_string(body, next);
+    }
+    let mut username = String::new();
+    if flags & 0x80 != 0 {
+        (username, position) = read_string(body, position);
+    }
+    let mut password = String::new();
+    if flags & 0x40 != 0 {
+        (password, _) = read_string(body, position);
+    }
+    Some((username, password))
+}
+
+/// Returns the reason code of each topic filter.
+fn subscribe<F: FnMut(String, u8) -> u8>(body: &[u8], mut subscribe: F) -> Vec<u8> {
+    let (properties_length, length) = read_variable_integer(&body[2..]);
+    let mut position = 2 + length + properties_length;
+    let mut reason_codes = Vec::new();
+    while position < body.len() {
+        let (filter, next) = read_string(body, position);
+        reason_codes.push(subscribe(filter, body[next]));
+        position = next + 1;
+    }
+    reason_codes
+}
+
+fn unsubscribe(body: &[u8]) -> Vec<String> {
+    let (properties_length, length) = read_variable_integer(&body[2..]);
+    let mut position = 2 + length + properties_length;
+    let mut filters = Vec::new();
+    while position < body.len() {
+        let (filter, next) = read_string(body, position);
+        filters.push(filter);
+        position = next;
+    }
+    filters
+}
+
+/// The packet id, no properties and the reason codes.
+fn ack(packet_id: &[u8], reason_codes: &[u8]) -> Vec<u8> {
+    [packet_id, &[0], reason_codes].concat()
+}
+
+async fn read_packet(reader: &mut OwnedReadHalf) -> std::io::Result<(u8, Vec<u8>)> {
+    let header = reader.read_u8().await?;
+    let mut remaining_length = 0;
+    for shift in (0..28).step_by(7) {
+        let byte = reader.read_u8().await?;
+        remaining_length |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    let mut body = vec![0; remaining_length];
+    reader.read_exact(body.as_mut_slice()).await?;
+    Ok((header, body))
+}
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut remaining_length = body.len();
+    loop {
+        let byte = (remaining_length & 0x7f) as u8;
+        remaining_length >>= 7;
+        if remaining_length == 0 {
+            packet.push(byte);
+            break;
+        }
+        packet.push(byte | 0x80);
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// Returns the value and the number of bytes.
+fn read_variable_integer(bytes: &[u8]) -> (usize, usize) {
+    let mut value = 0;
+    for (i, byte) in bytes.iter().take(4).enumerate() {
+        value |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return (value, i + 1);
+        }
+    }
+    (value, bytes.len().min(4))
+}
+
+/// Returns the string and the position after the string.
+fn read_string(bytes: &[u8], position: usize) -> (String, usize) {
+    let length = u16::from_be_bytes([bytes[position], bytes[position + 1]]) as usize;
+    let end = position + 2 + length;
+    (
+        String::from_utf8_lossy(&bytes[position + 2..end]).to_string(),
+        end,
+    )
+}
+
+fn encode_string(value: &str) -> Vec<u8> {
+    [&(value.len() as u16).to_be_bytes()[..], value.as_bytes()].concat()
+}
@@ -0,0 +1,82 @@
+use std::net::TcpListener;
+use std::thread;
+
+use futures_util::SinkExt;
+use futures_util::StreamExt;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::runtime;
+use tokio_tungstenite::tungstenite::handshake::server::Request;
+use tokio_tungstenite::tungstenite::handshake::server::Response;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Terminates MQTT over WebSocket in front of the plain listener of an embedded broker and
+/// returns the port of the WebSocket listener. The embedded broker only speaks plain MQTT.
+pub fn start_ws_proxy(backend_port: u16) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async move {
+            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    // Failed handshakes just close the connection
+                    let _ = forward(stream, backend_port).await;
+                });
+            }
+        });
+    });
+    port
+}
+
+async fn forward(stream: TcpStream, backend_port: u16) -> Result<(), Box<dyn std::error::Error>> {
+    // MQTT clients request the subprotocol mqtt
+    let websocket =
+        tokio_tungstenite::accept_hdr_async(stream, |_: &Request, mut response: Response| {
+            response
+                .headers_mut()
+                .insert("Sec-WebSocket-Protocol", HeaderValue::from_static("mqtt"));
+            Ok(response)
+        })
+        .await?;
+    let backend = TcpStream::connect(("127.0.0.1", backend_port)).await?;
+    let (mut backend_reader, mut backend_writer) = backend.into_split();
+    let (mut websocket_writer, mut websocket_reader) = websocket.split();
+    let upstream = async move {
+        while let Some(Ok(message)) = websocket_reader.next().await {
+            match message {
+                Message::Binary(data) => {
+                    if backend_writer.write_all(data.as_slice()).await.is_err() {
+                        break;
+                    }
+                }
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+        let _ = backend_writer.shutdown().await;
+    };
+    let downstream = async move {
+        let mut buffer = vec![0; 4096];
+        while let Ok(read) = backend_reader.read(buffer.as_mut_slice()).await {
+            if read == 0
+                || websocket_writer
+                    .send(Message::Binary(buffer[..read].to_vec()))
+                    .await
+                    .is_err()
+            {
+                break;
+            }
+        }
+        let _ = websocket_writer.close().await;
+    };
+    tokio::join!(upstream, downstream);
+    Ok(())
+}
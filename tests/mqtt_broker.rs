@@ -0,0 +1,950 @@
+//! End to end tests of the behaviours against an embedded broker.
+//!
+//! Each test starts its own mqtt_server on a port which is chosen by the
+//! operating system, so the tests can run in parallel. The mqtt_servers share
+//! the embedded broker of the process, so each test uses its own topics.
+
+use std::thread;
+use std::time::Duration;
+
+use inexor_rgf_core_model::PropertyInstanceGetter;
+use inexor_rgf_core_plugins::EntityBehaviourProvider;
+use inexor_rgf_core_plugins::Plugin;
+use inexor_rgf_core_plugins::RelationBehaviourProvider;
+use inexor_rgf_plugin_mqtt::behaviour::components::mqtt_ha_exposed::MqttHaExposed;
+use inexor_rgf_plugin_mqtt::behaviour::components::MqttEndpointProperties;
+use inexor_rgf_plugin_mqtt::behaviour::components::MqttHaExposedProperties;
+use inexor_rgf_plugin_mqtt::behaviour::components::MqttPayloadMode;
+use inexor_rgf_plugin_mqtt::behaviour::components::MqttTopicProperties;
+use inexor_rgf_plugin_mqtt::behaviour::entity::homie_device::HomieDevice;
+use inexor_rgf_plugin_mqtt::behaviour::entity::mqtt_ha_discovery::MqttHaDiscovery;
+use inexor_rgf_plugin_mqtt::behaviour::entity::mqtt_server::MqttServer;
+use inexor_rgf_plugin_mqtt::behaviour::entity::HomieDeviceProperties;
+use inexor_rgf_plugin_mqtt::behaviour::entity::MqttBrokerProperties;
+use inexor_rgf_plugin_mqtt::behaviour::entity::MqttHaDiscoveryProperties;
+use inexor_rgf_plugin_mqtt::behaviour::entity::MqttPublisherProperties;
+use inexor_rgf_plugin_mqtt::behaviour::entity::MqttServerProperties;
+use inexor_rgf_plugin_mqtt::behaviour::entity::MqttSubscriberProperties;
+use inexor_rgf_plugin_mqtt::behaviour::relation::mqtt_subscribes::MqttSubscribes;
+use inexor_rgf_plugin_mqtt::construct_plugin;
+use serde_json::json;
+use serde_json::Value;
+
+mod common;
+
+use common::import::*;
+use common::tls::*;
+use common::v5::*;
+use common::ws::*;
+use common::*;
+
+#[test]
+fn publish_and_subscribe() {
+    let (_server, port) = start_server();
+
+    let (broker_entity, broker) = connect_broker(port);
+
+    let (publisher, _publishes) = publish_to(&broker_entity, "test/publish/temperature");
+    let (subscriber, _subscribes) = subscribe_to(&broker_entity, &broker, "test/publish/+");
+
+    assert!(publish_until_received(
+        &publisher,
+        &subscriber,
+        json!({ "celsius": 21.5 })
+    ));
+    assert_eq!(
+        Some(String::from("test/publish/temperature")),
+        subscriber.as_string(MqttSubscriberProperties::LAST_TOPIC.as_ref())
+    );
+    assert_eq!(
+        json!(["temperature"]),
+        subscriber
+            .get(MqttSubscriberProperties::CAPTURES.as_ref())
+            .unwrap()
+    );
+}
+
+#[test]
+fn reconnect_and_restore_subscriptions() {
+    // Nothing listens on the port yet
+    let port = released_port();
+    let (broker_entity, broker) = start_broker(port);
+
+    // The subscription is restored as soon as the connection has been established
+    let (subscriber, _subscribes) = subscribe_to(&broker_entity, &broker, "test/reconnect");
+    let (publisher, _publishes) = publish_to(&broker_entity, "test/reconnect");
+
+    assert!(wait_until(|| reconnect_attempts(&broker_entity) > 1));
+    assert!(!is_connected(&broker_entity));
+    assert!(!last_error(&broker_entity).is_empty());
+
+    let _server = restart_server(port);
+    assert!(wait_until(|| is_connected(&broker_entity)));
+    assert_eq!(0, reconnect_attempts(&broker_entity));
+    assert!(publish_until_received(&publisher, &subscriber, json!(1)));
+
+    // Changing a connection property establishes a new connection
+    let connected_since = broker_entity.get(MqttBrokerProperties::CONNECTED_SINCE.as_ref());
+    set(
+        &broker_entity,
+        MqttBrokerProperties::KEEP_ALIVE.as_ref(),
+        json!(30),
+    );
+    assert!(wait_until(|| is_connected(&broker_entity)
+        && broker_entity
+            .get(MqttBrokerProperties::CONNECTED_SINCE.as_ref())
+            != connected_since));
+    assert!(publish_until_received(&publisher, &subscriber, json!(2)));
+}
+
+#[test]
+fn publishing_fails_after_giving_up_to_connect() {
+    // Nothing listens on the port
+    let port = released_port();
+    let (broker_entity, _broker) = start_broker_with(
+        port,
+        &[
+            (
+                MqttBrokerProperties::RECONNECT_INITIAL_DELAY.as_ref(),
+                json!(10),
+            ),
+            (MqttBrokerProperties::RECONNECT_JITTER.as_ref(), json!(0)),
+            (
+                MqttBrokerProperties::RECONNECT_MAX_ATTEMPTS.as_ref(),
+                json!(1),
+            ),
+            (
+                MqttBrokerProperties::RECONNECT_GIVE_UP.as_ref(),
+                json!("stop"),
+            ),
+        ],
+    );
+    assert!(wait_until(
+        || last_error(&broker_entity).starts_with("Gave up")
+    ));
+    assert!(!is_connected(&broker_entity));
+
+    // The message isn't queued for a connection which won't be established anymore
+    send_package(
+        &broker_entity,
+        "test/gave_up",
+        MqttPayloadMode::Json,
+        false,
+        json!(1),
+    );
+    assert!(last_error(&broker_entity).starts_with("Dropped message to topic test/gave_up"));
+}
+
+#[test]
+fn restore_more_subscriptions_than_request_channel_capacity() {
+    // Nothing listens on the port yet
+    let port = released_port();
+    let (broker_entity, broker) = start_broker_with(
+        port,
+        &[(
+            MqttBrokerProperties::REQUEST_CHANNEL_CAPACITY.as_ref(),
+            json!(2),
+        )],
+    );
+
+    let endpoints: Vec<_> = (0..12)
+        .map(|i| {
+            let topic = format!("test/restore/{}", i);
+            let (subscriber, subscribes) = subscribe_to(&broker_entity, &broker, topic.as_str());
+            let (publisher, publishes) = publish_to(&broker_entity, topic.as_str());
+            (publisher, publishes, subscriber, subscribes)
+        })
+        .collect();
+
+    // All subscriptions are restored when the connection has been established
+    let _server = restart_server(port);
+    assert!(wait_until(|| is_connected(&broker_entity)));
+    for (i, (publisher, _, subscriber, _)) in endpoints.iter().enumerate() {
+        assert!(publish_until_received(publisher, subscriber, json!(i)));
+    }
+}
+
+#[test]
+fn tls_with_ca_certificate() {
+    let (_server, port) = start_server();
+    let certificates = TestCertificates::generate();
+    let tls_port = start_tls_proxy(port, &certificates, false);
+
+    // The server certificate is issued for localhost
+    let (broker_entity, broker) = connect_broker_with(
+        tls_port,
+        &[
+            (MqttBrokerProperties::HOSTNAME.as_ref(), json!("localhost")),
+            (MqttBrokerProperties::TRANSPORT.as_ref(), json!("tls")),
+            (
+                MqttBrokerProperties::CA_CERTIFICATE.as_ref(),
+                json!(certificates.ca_certificate),
+            ),
+        ],
+    );
+    assert!(round_trip(&broker_entity, &broker, "test/tls"));
+}
+
+#[test]
+fn mutual_tls() {
+    let (_server, port) = start_server();
+    let certificates = TestCertificates::generate();
+    let tls_port = start_tls_proxy(port, &certificates, true);
+
+    let tls_properties = [
+        (MqttBrokerProperties::HOSTNAME.as_ref(), json!("localhost")),
+        (MqttBrokerProperties::TRANSPORT.as_ref(), json!("tls")),
+        (
+            MqttBrokerProperties::CA_CERTIFICATE.as_ref(),
+            json!(certificates.ca_certificate),
+        ),
+    ];
+    let (anonymous_entity, _anonymous) = start_broker_with(tls_port, &tls_properties);
+
+    // The client key is SEC1 encoded
+    let mut client_properties = tls_properties.to_vec();
+    client_properties.push((
+        MqttBrokerProperties::CLIENT_CERTIFICATE.as_ref(),
+        json!(certificates.client_certificate),
+    ));
+    client_properties.push((
+        MqttBrokerProperties::CLIENT_KEY.as_ref(),
+        json!(certificates.client_key),
+    ));
+    let (broker_entity, broker) = connect_broker_with(tls_port, client_properties.as_slice());
+    assert!(round_trip(&broker_entity, &broker, "test/mutual_tls"));
+
+    // The handshake fails without a client certificate
+    assert!(wait_until(|| reconnect_attempts(&anonymous_entity) > 1));
+    assert!(!is_connected(&anonymous_entity));
+}
+
+#[test]
+fn publish_and_subscribe_over_websocket() {
+    let (_server, port) = start_server();
+    let ws_port = start_ws_proxy(port);
+
+    let (broker_entity, broker) = connect_broker_with(
+        ws_port,
+        &[(MqttBrokerProperties::TRANSPORT.as_ref(), json!("ws"))],
+    );
+    assert!(round_trip(&broker_entity, &broker, "test/ws"));
+}
+
+#[test]
+fn subscribe_before_the_broker_behaviour_exists() {
+    let (_server, port) = start_server();
+
+    let plugin = construct_plugin().unwrap();
+    plugin.init().unwrap();
+    let entity_behaviour_provider = plugin.get_entity_behaviour_provider().unwrap();
+    let relation_behaviour_provider = plugin.get_relation_behaviour_provider().unwrap();
+
+    // The relation waits for the behaviour of the broker
+    let broker_entity = create_broker_entity(port, &[]);
+    let subscriber = create_subscriber();
+    let subscribes = create_topic_relation(
+        broker_entity.clone(),
+        "mqtt_subscribes",
+        subscriber.clone(),
+        "test/late",
+    );
+    relation_behaviour_provider.add_behaviours(subscribes.clone());
+    entity_behaviour_provider.add_behaviours(broker_entity.clone());
+    assert!(wait_until(|| is_connected(&broker_entity)));
+
+    let (publisher_broker_entity, _publisher_broker) = connect_broker(port);
+    let (publisher, _publishes) = publish_to(&publisher_broker_entity, "test/late");
+    assert!(publish_until_received(
+        &publisher,
+        &subscriber,
+        json!("first")
+    ));
+
+    // The relation is recreated with the next behaviour of the broker
+    entity_behaviour_provider.remove_behaviours(broker_entity.clone());
+    entity_behaviour_provider.add_behaviours(broker_entity.clone());
+    assert!(wait_until(|| is_connected(&broker_entity)));
+    assert!(publish_until_received(
+        &publisher,
+        &subscriber,
+        json!("second")
+    ));
+
+    relation_behaviour_provider.remove_behaviours(subscribes);
+    entity_behaviour_provider.remove_behaviours(broker_entity);
+}
+
+#[test]
+fn recreated_broker_keeps_the_password() {
+    let port = start_v5_broker_with_credentials("user", "secret");
+
+    let plugin = construct_plugin().unwrap();
+    plugin.init().unwrap();
+    let entity_behaviour_provider = plugin.get_entity_behaviour_provider().unwrap();
+
+    let broker_entity = create_broker_entity(
+        port,
+        &[
+            (MqttBrokerProperties::PROTOCOL_VERSION.as_ref(), json!(5)),
+            (MqttBrokerProperties::USERNAME.as_ref(), json!("user")),
+            (MqttBrokerProperties::PASSWORD.as_ref(), json!("secret")),
+        ],
+    );
+    entity_behaviour_provider.add_behaviours(broker_entity.clone());
+    assert!(wait_until(|| is_connected(&broker_entity)));
+    // The password has been taken out of the graph
+    assert_eq!(
+        Some(json!("")),
+        broker_entity.get(MqttBrokerProperties::PASSWORD.as_ref())
+    );
+
+    // The next behaviour authenticates with the password of the previous behaviour
+    entity_behaviour_provider.remove_behaviours(broker_entity.clone());
+    assert!(!is_connected(&broker_entity));
+    entity_behaviour_provider.add_behaviours(broker_entity.clone());
+    assert!(wait_until(|| is_connected(&broker_entity)));
+
+    // Setting another password replaces the password
+    set(
+        &broker_entity,
+        MqttBrokerProperties::PASSWORD.as_ref(),
+        json!("wrong"),
+    );
+    assert!(wait_until(
+        || !is_connected(&broker_entity) && reconnect_attempts(&broker_entity) > 0
+    ));
+
+    entity_behaviour_provider.remove_behaviours_by_id(broker_entity.id);
+}
+
+#[test]
+fn server_releases_address_after_removal() {
+    let (server, port) = start_server();
+
+    // The address is in use as long as the server exists
+    let entity = create_server_entity(port);
+    assert!(MqttServer::new(entity.clone()).is_err());
+    assert_eq!(
+        Some(false),
+        entity.as_bool(MqttServerProperties::RUNNING.as_ref())
+    );
+
+    let (broker_entity, _broker) = connect_broker(port);
+    drop(server);
+    assert!(wait_until(|| !is_connected(&broker_entity)));
+
+    let server = MqttServer::new(entity.clone());
+    assert!(server.is_ok());
+    assert_eq!(
+        Some(true),
+        entity.as_bool(MqttServerProperties::RUNNING.as_ref())
+    );
+    assert!(wait_until(|| is_connected(&broker_entity)));
+    drop(server);
+    assert_eq!(
+        Some(false),
+        entity.as_bool(MqttServerProperties::RUNNING.as_ref())
+    );
+}
+
+#[test]
+fn servers_share_the_embedded_broker() {
+    let (_server, port) = start_server();
+    let (_other_server, other_port) = start_server();
+
+    let (broker_entity, _broker) = connect_broker(port);
+    let (other_broker_entity, other_broker) = connect_broker(other_port);
+
+    let (publisher, _publishes) = publish_to(&broker_entity, "test/shared");
+    let (subscriber, _subscribes) =
+        subscribe_to(&other_broker_entity, &other_broker, "test/shared");
+    assert!(publish_until_received(&publisher, &subscriber, json!(1)));
+}
+
+#[test]
+fn disconnect_cleans_up() {
+    let (_server, port) = start_server();
+
+    let (broker_entity, broker) = connect_broker(port);
+
+    let (publisher, publishes) = publish_to(&broker_entity, "test/cleanup");
+    let (subscriber, subscribes) = subscribe_to(&broker_entity, &broker, "test/cleanup");
+    assert!(publish_until_received(
+        &publisher,
+        &subscriber,
+        json!("first")
+    ));
+
+    // The subscriber doesn't receive messages after the relation has been removed
+    drop(subscribes);
+    set(
+        &publisher,
+        MqttEndpointProperties::PAYLOAD.as_ref(),
+        json!("second"),
+    );
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(json!("first"), payload(&subscriber));
+
+    // The publisher doesn't send packages after the relation has been removed
+    drop(publishes);
+    set(
+        &broker_entity,
+        MqttBrokerProperties::SEND_PACKAGE.as_ref(),
+        json!({}),
+    );
+    set(
+        &publisher,
+        MqttEndpointProperties::PAYLOAD.as_ref(),
+        json!("third"),
+    );
+    assert_eq!(
+        json!({}),
+        broker_entity
+            .get(MqttBrokerProperties::SEND_PACKAGE.as_ref())
+            .unwrap()
+    );
+
+    // Removing the broker closes the connection
+    drop(broker);
+    assert!(!is_connected(&broker_entity));
+}
+
+#[test]
+fn retained_messages_with_qos_2() {
+    let (_server, port) = start_server();
+    let (broker_entity, broker) = connect_broker(port);
+
+    let (publisher, _publishes) = publish_to_with(
+        &broker_entity,
+        "test/retained",
+        &[
+            (MqttTopicProperties::QOS.as_ref(), json!(2)),
+            (MqttTopicProperties::RETAIN.as_ref(), json!(true)),
+        ],
+    );
+    set(
+        &publisher,
+        MqttEndpointProperties::PAYLOAD.as_ref(),
+        json!(21),
+    );
+
+    // Subscribers which subscribe later receive the retained message
+    let (subscriber, _subscribes) = subscribe_to_with(
+        &broker_entity,
+        &broker,
+        "test/retained",
+        &[(MqttTopicProperties::QOS.as_ref(), json!(2))],
+    );
+    assert!(wait_until(|| payload(&subscriber) == json!(21)));
+    let (other_broker_entity, other_broker) = connect_broker(port);
+    let (other_subscriber, _other_subscribes) =
+        subscribe_to(&other_broker_entity, &other_broker, "test/retained");
+    assert!(wait_until(|| payload(&other_subscriber) == json!(21)));
+
+    // Updates reach the current subscribers
+    set(
+        &publisher,
+        MqttEndpointProperties::PAYLOAD.as_ref(),
+        json!(42),
+    );
+    assert!(wait_until(
+        || payload(&subscriber) == json!(42) && payload(&other_subscriber) == json!(42)
+    ));
+
+    // Subscribers which subscribe after the retained message has been cleared receive nothing.
+    // A retained message would be delivered before the marker.
+    set(
+        &publisher,
+        MqttPublisherProperties::CLEAR_RETAINED.as_ref(),
+        json!(true),
+    );
+    assert!(wait_until(|| payload(&subscriber) == json!("")));
+    let late_subscriber = create_subscriber();
+    let received = record(&late_subscriber, MqttEndpointProperties::PAYLOAD.as_ref());
+    let _late_subscribes = MqttSubscribes::new(
+        create_topic_relation(
+            other_broker_entity.clone(),
+            "mqtt_subscribes",
+            late_subscriber.clone(),
+            "test/retained/#",
+        ),
+        &other_broker,
+    );
+    let (marker, _marker_publishes) = publish_to(&broker_entity, "test/retained/marker");
+    assert!(publish_until_received(
+        &marker,
+        &late_subscriber,
+        json!("marker")
+    ));
+    assert!(received
+        .lock()
+        .unwrap()
+        .iter()
+        .all(|value| *value == json!("marker")));
+}
+
+#[test]
+fn offline_queue_publishes_after_reconnecting() {
+    let (server, port) = start_server();
+    let (queueing_broker_entity, _queueing_broker) = start_broker_with(
+        port,
+        &[(MqttBrokerProperties::OFFLINE_QUEUE_SIZE.as_ref(), json!(10))],
+    );
+    let (dropping_broker_entity, _dropping_broker) = start_broker_with(
+        port,
+        &[(MqttBrokerProperties::OFFLINE_QUEUE_SIZE.as_ref(), json!(0))],
+    );
+    assert!(wait_until(
+        || is_connected(&queueing_broker_entity) && is_connected(&dropping_broker_entity)
+    ));
+    drop(server);
+    assert!(wait_until(
+        || !is_connected(&queueing_broker_entity) && !is_connected(&dropping_broker_entity)
+    ));
+
+    // The messages are retained, so they can be received after the server has been restarted
+    send_package(
+        &queueing_broker_entity,
+        "test/offline/queued",
+        MqttPayloadMode::Raw,
+        true,
+        json!("queued"),
+    );
+    send_package(
+        &dropping_broker_entity,
+        "test/offline/dropped",
+        MqttPayloadMode::Raw,
+        true,
+        json!("dropped"),
+    );
+    assert!(wait_until(
+        || last_error(&dropping_broker_entity).contains("offline queue is disabled")
+    ));
+
+    let _server = restart_server(port);
+    assert!(wait_until(
+        || is_connected(&queueing_broker_entity) && is_connected(&dropping_broker_entity)
+    ));
+    let (broker_entity, broker) = connect_broker(port);
+    let subscriber = create_subscriber();
+    let received = record(&subscriber, MqttEndpointProperties::PAYLOAD.as_ref());
+    let _subscribes = MqttSubscribes::new(
+        create_topic_relation_with(
+            broker_entity.clone(),
+            "mqtt_subscribes",
+            subscriber.clone(),
+            "test/offline/+",
+            &[(MqttTopicProperties::MODE.as_ref(), json!("raw"))],
+        ),
+        &broker,
+    );
+    assert!(wait_until(|| payload(&subscriber) == json!("queued")));
+
+    // The dropped message would have been delivered with the queued message
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(*received.lock().unwrap(), [json!("queued")]);
+}
+
+#[test]
+fn persistent_session_receives_messages_published_while_offline() {
+    let (_server, port) = start_server();
+    let session = [
+        (
+            MqttBrokerProperties::CLIENT_ID.as_ref(),
+            json!("persistent-session"),
+        ),
+        (MqttBrokerProperties::CLEAN_SESSION.as_ref(), json!(false)),
+    ];
+    let (subscriber_broker_entity, subscriber_broker) = start_broker_with(port, &session);
+    let (publisher_broker_entity, _publisher_broker) = start_broker(port);
+    assert!(wait_until(
+        || is_connected(&subscriber_broker_entity) && is_connected(&publisher_broker_entity)
+    ));
+
+    let (subscriber, subscribes) = subscribe_to(
+        &subscriber_broker_entity,
+        &subscriber_broker,
+        "test/session",
+    );
+    let (publisher, _publishes) = publish_to(&publisher_broker_entity, "test/session");
+    assert!(publish_until_received(&publisher, &subscriber, json!(1)));
+
+    // The session keeps the subscription while the client is offline. Removing the broker
+    // first keeps the relation from unsubscribing.
+    drop(subscriber_broker);
+    drop(subscribes);
+    assert!(!is_connected(&subscriber_broker_entity));
+    set(
+        &publisher,
+        MqttEndpointProperties::PAYLOAD.as_ref(),
+        json!(2),
+    );
+    thread::sleep(Duration::from_millis(500));
+
+    // The broker delivers the message with QoS 1 when the client resumes the session. The
+    // message is held back until the relation subscribes the topic again.
+    let (subscriber_broker_entity, subscriber_broker) = connect_broker_with(port, &session);
+    let (subscriber, _subscribes) = subscribe_to(
+        &subscriber_broker_entity,
+        &subscriber_broker,
+        "test/session",
+    );
+    assert!(wait_until(|| payload(&subscriber) == json!(2)));
+}
+
+#[test]
+fn mqtt5_user_properties_and_reason_codes() {
+    let port = start_v5_broker();
+    let (broker_entity, broker) = connect_broker_with(
+        port,
+        &[(MqttBrokerProperties::PROTOCOL_VERSION.as_ref(), json!(5))],
+    );
+
+    // The user properties and the content type of the publisher are received by the subscriber
+    let (publisher, _publishes) = publish_to(&broker_entity, "test/v5");
+    set(
+        &publisher,
+        MqttPublisherProperties::USER_PROPERTIES.as_ref(),
+        json!({ "unit": "celsius" }),
+    );
+    set(
+        &publisher,
+        MqttPublisherProperties::CONTENT_TYPE.as_ref(),
+        json!("application/json"),
+    );
+    let (subscriber, _subscribes) = subscribe_to(&broker_entity, &broker, "test/v5");
+    assert!(publish_until_received(&publisher, &subscriber, json!(21.5)));
+    assert_eq!(
+        Some(json!({ "unit": "celsius" })),
+        subscriber.get(MqttSubscriberProperties::USER_PROPERTIES.as_ref())
+    );
+    assert_eq!(
+        Some(json!("application/json")),
+        subscriber.get(MqttSubscriberProperties::CONTENT_TYPE.as_ref())
+    );
+
+    // The reason codes of rejected subscriptions and messages are reported
+    let (forbidden_subscriber, _forbidden_subscribes) =
+        subscribe_to(&broker_entity, &broker, "forbidden/topic");
+    assert!(wait_until(|| {
+        let error = last_error(&broker_entity);
+        error.contains("SUBACK") && error.contains("NotAuthorized")
+    }));
+    let (quota_publisher, _quota_publishes) = publish_to(&broker_entity, "quota/topic");
+    set(
+        &quota_publisher,
+        MqttEndpointProperties::PAYLOAD.as_ref(),
+        json!(1),
+    );
+    assert!(wait_until(|| {
+        let error = last_error(&broker_entity);
+        error.contains("PUBACK") && error.contains("QuotaExceeded")
+    }));
+}
+
+#[test]
+fn ha_exposed_announces_the_entity() {
+    let (_server, port) = start_server();
+    let (broker_entity, broker) = start_broker(port);
+    // A second connection acts as Home Assistant
+    let (ha_broker_entity, ha_broker) = start_broker(port);
+    assert!(wait_until(
+        || is_connected(&broker_entity) && is_connected(&ha_broker_entity)
+    ));
+    let ha_subscriber = |topic: &str, mode: &str| {
+        subscribe_to_with(
+            &ha_broker_entity,
+            &ha_broker,
+            topic,
+            &[(MqttTopicProperties::MODE.as_ref(), json!(mode))],
+        )
+    };
+    let (config, _config_subscribes) = ha_subscriber("homeassistant/switch/lamp/config", "json");
+    let (state, _state_subscribes) = ha_subscriber("homeassistant/switch/lamp/state", "json");
+    let (availability, _availability_subscribes) =
+        ha_subscriber("homeassistant/switch/lamp/availability", "raw");
+
+    let lamp = create_entity(
+        "lamp",
+        MqttHaExposedProperties::properties(),
+        &[
+            ("on", json!(false)),
+            (
+                MqttHaExposedProperties::HA_BROKER.as_ref(),
+                json!(broker_entity.id.to_string()),
+            ),
+            (
+                MqttHaExposedProperties::HA_COMPONENT.as_ref(),
+                json!("switch"),
+            ),
+            (
+                MqttHaExposedProperties::HA_OBJECT_ID.as_ref(),
+                json!("lamp"),
+            ),
+            (MqttHaExposedProperties::HA_NAME.as_ref(), json!("Lamp")),
+            (MqttHaExposedProperties::HA_PROPERTY.as_ref(), json!("on")),
+            (MqttHaExposedProperties::HA_SETTABLE.as_ref(), json!(true)),
+        ],
+    );
+    let exposed = match MqttHaExposed::new(lamp.clone(), broker.clone()) {
+        Ok(exposed) => exposed,
+        Err(_) => panic!("Failed to expose the lamp"),
+    };
+
+    // The retained config, state and availability are received by Home Assistant
+    assert!(wait_until(|| {
+        let config = payload(&config);
+        config["state_topic"] == json!("homeassistant/switch/lamp/state")
+            && config["command_topic"] == json!("homeassistant/switch/lamp/set")
+            && config["origin"]["name"] == json!("inexor-rgf-plugin-mqtt")
+    }));
+    assert!(wait_until(|| payload(&availability) == json!("online")));
+    assert!(wait_until(|| payload(&state) == json!(false)));
+
+    // Commands of Home Assistant are written into the property
+    let (command, _command_publishes) =
+        publish_to(&ha_broker_entity, "homeassistant/switch/lamp/set");
+    assert!(wait_until(|| {
+        set(
+            &command,
+            MqttEndpointProperties::PAYLOAD.as_ref(),
+            json!(true),
+        );
+        lamp.get("on") == Some(json!(true))
+    }));
+    assert!(wait_until(|| payload(&state) == json!(true)));
+
+    // Changes of the property are published as state
+    set(&lamp, "on", json!(false));
+    assert!(wait_until(|| payload(&state) == json!(false)));
+
+    // Removing the behaviour makes the entity unavailable, clearing the config removes it
+    exposed.clear_config();
+    assert!(wait_until(|| payload(&config) == json!("")));
+    drop(exposed);
+    assert!(wait_until(|| payload(&availability) == json!("offline")));
+}
+
+#[test]
+fn ha_discovery_imports_and_removes_devices() {
+    let (_server, port) = start_server();
+    let (broker_entity, broker) = start_broker(port);
+    // A second connection acts as the devices
+    let (device_broker_entity, device_broker) = start_broker(port);
+    assert!(wait_until(
+        || is_connected(&broker_entity) && is_connected(&device_broker_entity)
+    ));
+
+    let context = TestImportContext::new(broker.clone());
+    let discovery_entity = create_entity(
+        "mqtt_ha_discovery",
+        MqttHaDiscoveryProperties::properties(),
+        &[
+            (
+                MqttHaDiscoveryProperties::BROKER.as_ref(),
+                json!(broker_entity.id.to_string()),
+            ),
+            // The mqtt_servers of the other tests share the embedded broker
+            (
+                MqttHaDiscoveryProperties::DISCOVERY_PREFIX.as_ref(),
+                json!("discovery"),
+            ),
+        ],
+    );
+    let discovery =
+        match MqttHaDiscovery::new(discovery_entity.clone(), broker.clone(), context.clone()) {
+            Ok(discovery) => discovery,
+            Err(_) => panic!("Failed to start the discovery"),
+        };
+    let discovered = || {
+        discovery_entity
+            .as_u64(MqttHaDiscoveryProperties::DISCOVERED.as_ref())
+            .unwrap_or(0)
+    };
+
+    // A device announces itself with abbreviated keys
+    let kitchen_config = json!({
+        "name": "Kitchen",
+        "~": "kitchen/light",
+        "stat_t": "~/state",
+        "cmd_t": "~/set"
+    });
+    assert!(wait_until(|| {
+        send_package(
+            &device_broker_entity,
+            "discovery/light/kitchen/config",
+            MqttPayloadMode::Json,
+            false,
+            kitchen_config.clone(),
+        );
+        discovered() == 1
+    }));
+    let kitchen_subscriber = context.entity("mqtt_subscriber", "Kitchen").unwrap();
+    let kitchen_publisher = context.entity("mqtt_publisher", "Kitchen").unwrap();
+    assert_eq!(2, context.count_relations());
+
+    // Entities of the graph which are exposed to Home Assistant aren't imported. The configs of
+    // a connection arrive in order, so the exposed config has been processed before the marker.
+    send_package(
+        &device_broker_entity,
+        "discovery/switch/graph/config",
+        MqttPayloadMode::Json,
+        false,
+        json!({
+            "name": "Graph",
+            "stat_t": "graph/state",
+            "origin": { "name": "inexor-rgf-plugin-mqtt" }
+        }),
+    );
+    send_package(
+        &device_broker_entity,
+        "discovery/sensor/marker/config",
+        MqttPayloadMode::Json,
+        false,
+        json!({ "name": "Marker", "stat_t": "marker/state" }),
+    );
+    assert!(wait_until(|| discovered() == 2));
+    assert!(context.entity("mqtt_subscriber", "Graph").is_none());
+    assert!(context.entity("mqtt_subscriber", "Marker").is_some());
+
+    // The imported instances exchange raw payloads with the device
+    assert!(wait_until(|| {
+        send_package(
+            &device_broker_entity,
+            "kitchen/light/state",
+            MqttPayloadMode::Raw,
+            false,
+            json!("ON"),
+        );
+        payload(&kitchen_subscriber) == json!("ON")
+    }));
+    let (command, _command_subscribes) = subscribe_to_with(
+        &device_broker_entity,
+        &device_broker,
+        "kitchen/light/set",
+        &[(MqttTopicProperties::MODE.as_ref(), json!("raw"))],
+    );
+    assert!(publish_until_received(
+        &kitchen_publisher,
+        &command,
+        json!("OFF")
+    ));
+
+    // Clearing the config removes the device
+    send_package(
+        &device_broker_entity,
+        "discovery/light/kitchen/config",
+        MqttPayloadMode::Raw,
+        true,
+        Value::Null,
+    );
+    assert!(wait_until(|| discovered() == 1));
+    assert!(context.entity("mqtt_subscriber", "Kitchen").is_none());
+    assert!(context.entity("mqtt_publisher", "Kitchen").is_none());
+    assert_eq!(1, context.count_relations());
+
+    // Removing the behaviour removes the imported instances
+    drop(discovery);
+    assert!(wait_until(|| context
+        .entities("mqtt_subscriber")
+        .is_empty()));
+    assert_eq!(0, context.count_relations());
+}
+
+#[test]
+fn homie_device_materializes_nodes() {
+    let (_server, port) = start_server();
+    let (broker_entity, broker) = start_broker(port);
+    // A second connection acts as the device
+    let (device_broker_entity, device_broker) = start_broker(port);
+    assert!(wait_until(
+        || is_connected(&broker_entity) && is_connected(&device_broker_entity)
+    ));
+
+    // The device publishes its description retained
+    for (topic, value) in [
+        ("homie/lamp/$homie", "4.0"),
+        ("homie/lamp/$name", "Lamp"),
+        ("homie/lamp/$state", "ready"),
+        ("homie/lamp/$nodes", "light"),
+        ("homie/lamp/light/$name", "Light"),
+        ("homie/lamp/light/$type", "bulb"),
+        ("homie/lamp/light/$properties", "brightness,power"),
+        ("homie/lamp/light/brightness/$datatype", "integer"),
+        ("homie/lamp/light/brightness/$format", "0:100"),
+        ("homie/lamp/light/brightness", "42"),
+        ("homie/lamp/light/power/$datatype", "boolean"),
+        ("homie/lamp/light/power/$settable", "true"),
+        ("homie/lamp/light/power", "false"),
+    ] {
+        send_package(
+            &device_broker_entity,
+            topic,
+            MqttPayloadMode::Raw,
+            true,
+            json!(value),
+        );
+    }
+
+    let context = TestImportContext::new(broker.clone());
+    let device_entity = create_entity(
+        "homie_device",
+        HomieDeviceProperties::properties(),
+        &[
+            (
+                HomieDeviceProperties::BROKER.as_ref(),
+                json!(broker_entity.id.to_string()),
+            ),
+            (HomieDeviceProperties::DEVICE_ID.as_ref(), json!("lamp")),
+        ],
+    );
+    let device = match HomieDevice::new(device_entity.clone(), broker.clone(), context.clone()) {
+        Ok(device) => device,
+        Err(_) => panic!("Failed to import the Homie device"),
+    };
+
+    // The node is materialized with typed values
+    let node = || context.entity("homie_node", "Light");
+    assert!(wait_until(|| node()
+        .map(|node| node.get("brightness") == Some(json!(42))
+            && node.get("power") == Some(json!(false)))
+        .unwrap_or(false)));
+    assert_eq!(1, context.entities("homie_node").len());
+    assert_eq!(
+        Some(json!("Lamp")),
+        device_entity.get(HomieDeviceProperties::NAME.as_ref())
+    );
+    assert_eq!(
+        Some(json!("ready")),
+        device_entity.get(HomieDeviceProperties::STATE.as_ref())
+    );
+
+    // Values which are published by the device update the node
+    send_package(
+        &device_broker_entity,
+        "homie/lamp/light/brightness",
+        MqttPayloadMode::Raw,
+        true,
+        json!("80"),
+    );
+    assert!(wait_until(|| node()
+        .map(|node| node.get("brightness") == Some(json!(80)))
+        .unwrap_or(false)));
+
+    // Writing into a settable property publishes the value to the device
+    let (command, _command_subscribes) = subscribe_to_with(
+        &device_broker_entity,
+        &device_broker,
+        "homie/lamp/light/power/set",
+        &[(MqttTopicProperties::MODE.as_ref(), json!("raw"))],
+    );
+    assert!(wait_until(|| {
+        set(&node().unwrap(), "power", json!(true));
+        payload(&command) == json!("true")
+    }));
+
+    // Removing the behaviour removes the nodes
+    drop(device);
+    assert!(wait_until(|| context.entities("homie_node").is_empty()));
+}